@@ -1,3 +1,10 @@
+use std::{
+    io,
+    net::{IpAddr, ToSocketAddrs},
+};
+
+use bitcoin::{network::TestnetVersion, Network};
+
 /// Hostnames for the Bitcoin network.
 pub const BITCOIN_SEEDS: [&str; 9] = [
     "seed.bitcoin.sipa.be",
@@ -31,3 +38,103 @@ pub const TESTNET4_SEEDS: [&str; 2] = [
     "seed.testnet4.bitcoin.sprovoost.nl",
     "seed.testnet4.wiz.biz",
 ];
+
+/// The port peers listen on by default for a network.
+pub fn default_port(network: Network) -> u16 {
+    match network {
+        Network::Bitcoin => 8333,
+        Network::Signet => 38333,
+        Network::Testnet(TestnetVersion::V4) => 48333,
+        Network::Testnet(_) => 18333,
+        Network::Regtest => 18444,
+    }
+}
+
+/// Resolve a hostname to IP addresses. Seed resolution goes through this trait, so clients that
+/// must not leak DNS queries may substitute their own. For instance, a client connecting over Tor
+/// should resolve through the proxy: open a connection to the SOCKS5 port, negotiate no
+/// authentication, and send Tor's `RESOLVE` command (`0xF0`) with the hostname as a domain
+/// address. The reply carries the resolved address in place of the bound address. Closures of
+/// the form `Fn(&str) -> io::Result<Vec<IpAddr>>` also implement this trait.
+pub trait Resolver {
+    /// Look up the addresses for a hostname.
+    fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>>;
+}
+
+/// Resolve hostnames with the resolver of the operating system.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdResolver;
+
+impl Resolver for StdResolver {
+    fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        // The port is required to build a socket address but plays no part in the query
+        let addrs = (host, 0).to_socket_addrs()?;
+        Ok(addrs.map(|addr| addr.ip()).collect())
+    }
+}
+
+impl<F> Resolver for F
+where
+    F: Fn(&str) -> io::Result<Vec<IpAddr>>,
+{
+    fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        self(host)
+    }
+}
+
+/// The DNS seeds for a network. There are no seeds for regtest.
+pub fn seeds(network: Network) -> &'static [&'static str] {
+    match network {
+        Network::Bitcoin => &BITCOIN_SEEDS,
+        Network::Signet => &SIGNET_SEEDS,
+        Network::Testnet(TestnetVersion::V4) => &TESTNET4_SEEDS,
+        Network::Testnet(_) => &TESTNET3_SEEDS,
+        Network::Regtest => &[],
+    }
+}
+
+/// Query every seed for the network, returning the unique addresses found. Seeds that fail to
+/// resolve are skipped.
+pub fn resolve_seeds(network: Network, resolver: &impl Resolver) -> Vec<IpAddr> {
+    let mut addrs = Vec::new();
+    for seed in seeds(network) {
+        let Ok(found) = resolver.resolve(seed) else {
+            continue;
+        };
+        for addr in found {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+    }
+    addrs
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        net::{IpAddr, Ipv4Addr},
+    };
+
+    use bitcoin::Network;
+
+    use super::{resolve_seeds, BITCOIN_SEEDS};
+
+    #[test]
+    fn test_resolve_seeds() {
+        let resolver = |host: &str| {
+            if host == BITCOIN_SEEDS[0] {
+                return Err(io::Error::from(io::ErrorKind::NotFound));
+            }
+            Ok(vec![
+                IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)),
+                IpAddr::V4(Ipv4Addr::new(host.len() as u8, 0, 0, 1)),
+            ])
+        };
+        let addrs = resolve_seeds(Network::Bitcoin, &resolver);
+        assert_eq!(addrs[0], IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+        assert_eq!(addrs.iter().filter(|addr| **addr == addrs[0]).count(), 1);
+        assert!(resolve_seeds(Network::Regtest, &resolver).is_empty());
+    }
+}
@@ -1,14 +1,26 @@
-use std::{fmt::Display, time::Duration};
+use std::{
+    fmt::{Debug, Display},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use bitcoin::{FeeRate, Network};
+use bitcoin::{bip152::ShortId, BlockHash, FeeRate, Network, Transaction};
 use p2p::{
     message::{CommandString, NetworkMessage},
     message_compact_blocks::SendCmpct,
-    message_network::{Alert, ClientSoftwareVersion, UserAgent, UserAgentVersion, VersionMessage},
+    message_network::{
+        Alert, ClientSoftwareVersion, Reject, RejectReason, UserAgent, UserAgentVersion,
+        VersionMessage,
+    },
     Address, ProtocolVersion, ServiceFlags,
 };
 
-use crate::{FeelerData, Preferences};
+use crate::{
+    announce_compact, known_services,
+    net::{BufferPool, ReadMemoryBudget},
+    BanScore, FeelerData, Preferences, WITNESS_COMPACT_VERSION,
+};
 
 const NETWORK: Network = Network::Bitcoin;
 const UNREACHABLE: Address = Address::useless();
@@ -20,20 +32,229 @@ const AGENT_VERSION: UserAgentVersion = UserAgentVersion::new(ClientSoftwareVers
 const CLIENT_NAME: &str = "SwiftSync";
 const SERVICES: ServiceFlags = ServiceFlags::NONE;
 const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion::WTXID_RELAY_VERSION;
+const MAX_USER_AGENT_LEN: usize = 256;
+const MAX_ADDR_PER_MESSAGE: usize = 1_000;
+
+type OutboundFilterFn = dyn FnMut(&mut NetworkMessage) -> bool + Send;
+
+/// Inspect or modify a message before it is written to the peer.
+#[derive(Clone)]
+pub(crate) struct OutboundFilter(Arc<Mutex<OutboundFilterFn>>);
+
+impl OutboundFilter {
+    /// Returns `false` if the message should be dropped.
+    pub(crate) fn apply(&self, message: &mut NetworkMessage) -> bool {
+        match self.0.lock() {
+            Ok(mut filter) => filter(message),
+            Err(_) => true,
+        }
+    }
+}
+
+impl Debug for OutboundFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "OutboundFilter")
+    }
+}
+
+/// Compose a user agent from a stack of client software components, as described in BIP-14. For
+/// example, `/Satoshi:27.0/MyWallet:1.2/`.
+#[derive(Debug, Clone, Default)]
+pub struct UserAgentBuilder {
+    user_agent: String,
+    // The first name or version containing a character reserved by BIP-14
+    invalid: Option<String>,
+}
+
+impl UserAgentBuilder {
+    /// Begin a user agent with no components.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a software component with the name and version. Neither may contain `/`, `:` or
+    /// `(`, which delimit the components of the user agent.
+    pub fn push_component(mut self, name: impl AsRef<str>, version: impl AsRef<str>) -> Self {
+        if self.invalid.is_none() {
+            self.invalid = [name.as_ref(), version.as_ref()]
+                .into_iter()
+                .find(|part| part.contains(['/', ':', '(']))
+                .map(str::to_string);
+        }
+        if self.user_agent.is_empty() {
+            self.user_agent.push('/');
+        }
+        self.user_agent.push_str(name.as_ref());
+        self.user_agent.push(':');
+        self.user_agent.push_str(version.as_ref());
+        self.user_agent.push('/');
+        self
+    }
+
+    /// Build the user agent, erroring if a component contains a reserved character or the user
+    /// agent exceeds the maximum length allowed by the protocol.
+    pub fn build(self) -> Result<UserAgent, Error> {
+        if let Some(part) = self.invalid {
+            return Err(Error::InvalidUserAgentComponent(part));
+        }
+        if self.user_agent.len() > MAX_USER_AGENT_LEN {
+            return Err(Error::UserAgentTooLong(self.user_agent.len()));
+        }
+        Ok(UserAgent::from_nonstandard(&self.user_agent))
+    }
+}
+
+#[derive(Clone)]
+struct FeeFilterFn(Arc<dyn Fn() -> FeeRate + Send + Sync>);
+
+impl FeeFilterFn {
+    fn constant(fee_rate: FeeRate) -> Self {
+        Self(Arc::new(move || fee_rate))
+    }
+}
+
+impl Debug for FeeFilterFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FeeFilterFn")
+    }
+}
+
+type MempoolLookupFn = dyn Fn(ShortId) -> Option<Transaction> + Send + Sync;
+
+/// Find a transaction in the mempool by the short ID of a compact block.
+#[derive(Clone)]
+pub(crate) struct MempoolLookup(Arc<MempoolLookupFn>);
+
+impl MempoolLookup {
+    pub(crate) fn find(&self, short_id: ShortId) -> Option<Transaction> {
+        (self.0)(short_id)
+    }
+}
+
+impl Debug for MempoolLookup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MempoolLookup")
+    }
+}
 
-/// Build a connection according to a list of preferences
+type LocatorFnInner = dyn Fn() -> Vec<BlockHash> + Send + Sync;
+
+/// Build a block locator from the current chain tip.
+#[derive(Clone)]
+pub(crate) struct LocatorFn(Arc<LocatorFnInner>);
+
+impl LocatorFn {
+    pub(crate) fn locator(&self) -> Vec<BlockHash> {
+        (self.0)()
+    }
+}
+
+impl Debug for LocatorFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LocatorFn")
+    }
+}
+
+/// The transports a connection may use. The v2 transport, described in BIP324, encrypts all
+/// traffic after an opportunistic key exchange.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TransportPreference {
+    /// Only use the unencrypted v1 transport.
+    #[default]
+    V1Only,
+    /// Only use the v2 transport, failing if the peer does not support it.
+    V2Only,
+    /// Attempt the v2 transport, downgrading to v1 if the peer does not support it. Outbound
+    /// connections are reopened to retry over v1, as Bitcoin Core does. Connections driven by
+    /// Tokio only support v1, so they always use v1 with this preference.
+    Auto,
+}
+
+/// How to handle a peer that advertises service bits not known to this crate.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum UnknownServicePolicy {
+    /// Accept the peer.
+    #[default]
+    Ignore,
+    /// Accept the peer, calling the function with the unknown services.
+    Log(fn(ServiceFlags)),
+    /// Reject a peer that advertises unknown services and no known services.
+    Reject,
+}
+
+/// The action to take on a message received from the peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterAction {
+    /// Handle the message as usual.
+    Pass,
+    /// Do not return the message to the caller.
+    Drop,
+    /// Close the connection.
+    Disconnect,
+}
+
+type InboundFilterFn = dyn FnMut(&NetworkMessage) -> FilterAction + Send;
+
+/// Decide what to do with a message as soon as it is received.
+#[derive(Clone)]
+pub(crate) struct InboundFilter(Arc<Mutex<InboundFilterFn>>);
+
+impl InboundFilter {
+    pub(crate) fn apply(&self, message: &NetworkMessage) -> FilterAction {
+        match self.0.lock() {
+            Ok(mut filter) => filter(message),
+            Err(_) => FilterAction::Pass,
+        }
+    }
+}
+
+impl Debug for InboundFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "InboundFilter")
+    }
+}
+
+/// Build a connection according to a list of preferences. With the `serde` feature, the
+/// configuration may be saved and restored, except for filters, buffer pools, read memory budgets,
+/// unknown service policies, mempool lookups and block locators. The fee filter is saved as its
+/// current value.
 #[derive(Debug, Clone)]
 pub struct ConnectionConfig {
     our_version: ProtocolVersion,
-    our_services: ServiceFlags,
+    pub(crate) our_services: ServiceFlags,
     expected_version: ProtocolVersion,
     expected_services: ServiceFlags,
     send_cmpct: SendCmpct,
     user_agent: UserAgent,
     our_height: i32,
-    fee_filter: FeeRate,
+    fee_filter: FeeFilterFn,
     network: Network,
     request_addr: bool,
+    pub(crate) decode_error_tolerance: u32,
+    pub(crate) outbound_filter: Option<OutboundFilter>,
+    pub(crate) inbound_filter: Option<InboundFilter>,
+    pub(crate) buffered_writes: bool,
+    pub(crate) max_lifetime: Option<Duration>,
+    pub(crate) buffer_pool: Option<BufferPool>,
+    pub(crate) read_memory_budget: Option<ReadMemoryBudget>,
+    pub(crate) message_history: usize,
+    pub(crate) trusted: bool,
+    pub(crate) socket_buffers: Option<(usize, usize)>,
+    pub(crate) handshake_jitter: Option<Duration>,
+    pub(crate) block_relay_only: bool,
+    pub(crate) mempool_lookup: Option<MempoolLookup>,
+    pub(crate) max_addr_per_message: usize,
+    pub(crate) disconnect_on_addr_flood: bool,
+    pub(crate) check_filter_headers: bool,
+    pub(crate) bind_local: Option<SocketAddr>,
+    pub(crate) max_unrequested_blocks: Option<u64>,
+    pub(crate) auto_getheaders: Option<LocatorFn>,
+    pub(crate) check_header_pow: bool,
+    unknown_services: UnknownServicePolicy,
+    pub(crate) transport: TransportPreference,
+    pub(crate) inbound: bool,
+    pub(crate) ban_score: BanScore,
 }
 
 impl ConnectionConfig {
@@ -51,9 +272,33 @@ impl ConnectionConfig {
             },
             user_agent,
             our_height: 0,
-            fee_filter: FeeRate::BROADCAST_MIN,
+            fee_filter: FeeFilterFn::constant(FeeRate::BROADCAST_MIN),
             network: NETWORK,
             request_addr: false,
+            decode_error_tolerance: 0,
+            outbound_filter: None,
+            inbound_filter: None,
+            buffered_writes: false,
+            max_lifetime: None,
+            buffer_pool: None,
+            read_memory_budget: None,
+            message_history: 0,
+            trusted: false,
+            socket_buffers: None,
+            handshake_jitter: None,
+            block_relay_only: false,
+            mempool_lookup: None,
+            max_addr_per_message: MAX_ADDR_PER_MESSAGE,
+            disconnect_on_addr_flood: false,
+            check_filter_headers: false,
+            bind_local: None,
+            max_unrequested_blocks: None,
+            auto_getheaders: None,
+            check_header_pow: false,
+            unknown_services: UnknownServicePolicy::Ignore,
+            transport: TransportPreference::V1Only,
+            inbound: false,
+            ban_score: BanScore::default(),
         }
     }
 
@@ -63,6 +308,24 @@ impl ConnectionConfig {
         self
     }
 
+    /// Set the required services and minimum version to what peers on the current network
+    /// realistically offer. Peers on the main network are expected to serve witness blocks, test
+    /// networks only require witness data, and any local peer is accepted on regtest. Call this
+    /// after [`ConnectionConfig::change_network`].
+    pub fn sensible_defaults_for_network(mut self) -> Self {
+        let (services, version) = match self.network {
+            Network::Bitcoin => (
+                ServiceFlags::NETWORK | ServiceFlags::WITNESS,
+                ProtocolVersion::WTXID_RELAY_VERSION,
+            ),
+            Network::Regtest => (ServiceFlags::NONE, ProtocolVersion::MIN_PEER_PROTO_VERSION),
+            _ => (ServiceFlags::WITNESS, ProtocolVersion::WTXID_RELAY_VERSION),
+        };
+        self.expected_services = services;
+        self.expected_version = version;
+        self
+    }
+
     /// Fetch the current network
     pub fn network(&self) -> Network {
         self.network
@@ -74,6 +337,204 @@ impl ConnectionConfig {
         self
     }
 
+    /// Skip up to `max` messages that fail to deserialize over the lifetime of the connection
+    /// before giving up. Only the payload of a message may be skipped, as a malformed header
+    /// leaves the stream in an unknown state.
+    pub fn tolerate_decode_errors(mut self, max: u32) -> Self {
+        self.decode_error_tolerance = max;
+        self
+    }
+
+    /// Inspect, modify, or drop every message before it is written to the peer, including
+    /// automated messages like pings. Returning `false` drops the message.
+    pub fn outbound_filter(
+        mut self,
+        filter: impl FnMut(&mut NetworkMessage) -> bool + Send + 'static,
+    ) -> Self {
+        self.outbound_filter = Some(OutboundFilter(Arc::new(Mutex::new(filter))));
+        self
+    }
+
+    /// Decide to pass, drop, or disconnect on each message received after the handshake. Dropped
+    /// messages are not returned by the reader but are still recorded in the connection metrics.
+    pub fn inbound_filter(
+        mut self,
+        filter: impl FnMut(&NetworkMessage) -> FilterAction + Send + 'static,
+    ) -> Self {
+        self.inbound_filter = Some(InboundFilter(Arc::new(Mutex::new(filter))));
+        self
+    }
+
+    /// Coalesce messages that are sent in quick succession into fewer writes to the socket. All
+    /// pending messages are written once there are no further messages queued.
+    pub fn buffered_writes(mut self, buffered: bool) -> Self {
+        self.buffered_writes = buffered;
+        self
+    }
+
+    /// Close the connection after it has been open for the duration. The writer will report
+    /// [`crate::net::Error::LifetimeExpired`] when the connection is closed.
+    pub fn max_lifetime(mut self, lifetime: Duration) -> Self {
+        self.max_lifetime = Some(lifetime);
+        self
+    }
+
+    /// Read messages into buffers from a pool, which may be shared among many connections.
+    pub fn buffer_pool(mut self, pool: BufferPool) -> Self {
+        self.buffer_pool = Some(pool);
+        self
+    }
+
+    /// Account the messages being read against a memory budget, which may be shared among many
+    /// connections. The connection fails with [`crate::net::Error::ReadMemoryExhausted`] if a
+    /// message does not fit in the budget.
+    pub fn read_memory_budget(mut self, budget: ReadMemoryBudget) -> Self {
+        self.read_memory_budget = Some(budget);
+        self
+    }
+
+    /// Remember the command and time of the last `n` messages sent or received after the
+    /// handshake. See [`crate::ConnectionMetrics::recent_messages`].
+    pub fn keep_message_history(mut self, n: usize) -> Self {
+        self.message_history = n;
+        self
+    }
+
+    /// Treat the peer as trusted, for instance a node operated by the same user. Inactivity
+    /// timeouts are removed once the handshake completes, and violations of the protocol do not
+    /// close the connection. Network magic and message checksums are still validated.
+    pub fn trusted(mut self) -> Self {
+        self.trusted = true;
+        self
+    }
+
+    /// Request the size of the operating system receive and send buffers for the socket, in
+    /// bytes. Larger buffers may improve throughput when serving blocks. The operating system may
+    /// clamp or adjust the requested sizes; for example, Linux doubles the value and caps it by
+    /// `net.core.rmem_max` and `net.core.wmem_max`. Only applied when the connection is opened
+    /// with `open_connection` or `listen`.
+    pub fn socket_buffers(mut self, recv: usize, send: usize) -> Self {
+        self.socket_buffers = Some((recv, send));
+        self
+    }
+
+    /// Bind outbound connections to a local address before connecting, for instance to route
+    /// traffic through a specific network interface on a host with several. The address family of
+    /// the local address must match that of the peer, so an IPv4 address cannot be used to reach
+    /// an IPv6 peer. Only applied when the connection is opened with `open_connection`.
+    pub fn bind_local(mut self, local: SocketAddr) -> Self {
+        self.bind_local = Some(local);
+        self
+    }
+
+    /// Wait a random duration, up to the maximum, once the verack of the peer arrives and before
+    /// sending the messages that complete the handshake, such as `sendcmpct`, so the timing of
+    /// this client is less distinctive. Our version message is sent without delay.
+    pub fn handshake_jitter(mut self, max: Duration) -> Self {
+        self.handshake_jitter = Some(max);
+        self
+    }
+
+    /// Establish a block-relay-only connection, as Bitcoin Core does to obscure the network
+    /// topology. Transaction relay is not requested, no `feefilter` is sent, and reading a
+    /// transaction or transaction announcement from the peer is treated as a protocol violation.
+    pub fn block_relay_only(mut self) -> Self {
+        self.block_relay_only = true;
+        self
+    }
+
+    /// Reconstruct compact blocks from the transactions in the mempool. Transactions that cannot
+    /// be found are requested from the peer with `getblocktxn`, and the progress of each block is
+    /// reported by [`crate::net::ConnectionReader::take_compact_block_status`].
+    pub fn mempool_lookup(
+        mut self,
+        lookup: impl Fn(ShortId) -> Option<Transaction> + Send + Sync + 'static,
+    ) -> Self {
+        self.mempool_lookup = Some(MempoolLookup(Arc::new(lookup)));
+        self
+    }
+
+    /// Respond to a block announced by `inv` with a `getheaders` request, built from the block
+    /// locator returned by `locator`. The `inv` message is still returned to the caller, and
+    /// transaction announcements are unaffected.
+    pub fn auto_getheaders_on_block_inv(
+        mut self,
+        locator: impl Fn() -> Vec<BlockHash> + Send + Sync + 'static,
+    ) -> Self {
+        self.auto_getheaders = Some(LocatorFn(Arc::new(locator)));
+        self
+    }
+
+    /// The most addresses the peer may send in a single `addr` or `addrv2` message, 1,000 by
+    /// default. Larger messages are counted by [`crate::ConnectionMetrics::oversized_addr_count`].
+    pub fn max_addr_per_message(mut self, max: usize) -> Self {
+        self.max_addr_per_message = max;
+        self
+    }
+
+    /// Close the connection if the peer sends more addresses in a single message than allowed by
+    /// [`ConnectionConfig::max_addr_per_message`]. Ignored for trusted peers.
+    pub fn disconnect_on_addr_flood(mut self) -> Self {
+        self.disconnect_on_addr_flood = true;
+        self
+    }
+
+    /// Check that each `cfheaders` message continues from the last filter header received, closing
+    /// the connection with [`crate::net::Error::FilterHeaderDiscontinuity`] otherwise.
+    pub fn check_filter_headers(mut self) -> Self {
+        self.check_filter_headers = true;
+        self
+    }
+
+    /// Close the connection with [`crate::net::Error::UnrequestedBlockFlood`] once the peer sends
+    /// more than `max` blocks that were not requested with `getdata`. Compact blocks pushed by a
+    /// high-bandwidth peer are not counted. Unrequested blocks are counted regardless, see
+    /// [`crate::ConnectionMetrics::unrequested_block_count`]. Ignored for trusted peers.
+    pub fn max_unrequested_blocks(mut self, max: u64) -> Self {
+        self.max_unrequested_blocks = Some(max);
+        self
+    }
+
+    /// Check the proof of work of every header received in a `headers` message, closing the
+    /// connection with [`crate::net::Error::InsufficientPow`] if a header does not meet its own
+    /// target, or claims a target easier than the network allows. See
+    /// [`crate::validation::check_header_pow`].
+    pub fn check_header_pow(mut self) -> Self {
+        self.check_header_pow = true;
+        self
+    }
+
+    /// Decide how to handle a peer that advertises services not known to this crate. Unknown
+    /// services are ignored by default.
+    pub fn on_unknown_services(mut self, policy: UnknownServicePolicy) -> Self {
+        self.unknown_services = policy;
+        self
+    }
+
+    /// Choose the transports the connection may use. Only v1 is used by default. Whether the
+    /// transport the connection settled on is reported by [`crate::ConnectionMetrics::transport`].
+    pub fn transport(mut self, preference: TransportPreference) -> Self {
+        self.transport = preference;
+        self
+    }
+
+    /// Handshake as the accepting side, for streams accepted by the caller and passed to
+    /// [`crate::net::ConnectionExt::handshake`]. A v1 peer is recognized by its version message
+    /// and the v2 handshake is answered rather than started. Connections from
+    /// [`crate::net::ConnectionExt::listen`] are always inbound.
+    pub fn inbound(mut self) -> Self {
+        self.inbound = true;
+        self
+    }
+
+    /// Set the points a peer's ban score increases by for each malformed or discouraged message.
+    /// Messages that cannot be decoded count as malformed. Scoring is informational, see
+    /// [`crate::ConnectionMetrics::should_disconnect`].
+    pub fn ban_score_points(mut self, malformed: u32, discouraged: u32) -> Self {
+        self.ban_score = BanScore::new(malformed, discouraged);
+        self
+    }
+
     /// Decrease the minimum accepted version
     pub fn decrease_version_requirement(mut self, protocol_version: ProtocolVersion) -> Self {
         self.expected_version = protocol_version;
@@ -86,6 +547,21 @@ impl ConnectionConfig {
         self
     }
 
+    /// Require the peer to serve BIP64 `getutxos` requests, in addition to the other required
+    /// services.
+    pub fn require_getutxo(mut self) -> Self {
+        self.expected_services |= ServiceFlags::GETUTXO;
+        self
+    }
+
+    /// Require the peer to advertise `NODE_P2P_V2`, in addition to the other required services.
+    /// Peers that do not support the BIP324 encrypted transport are rejected with
+    /// [`Error::MissingService`].
+    pub fn require_v2_service(mut self) -> Self {
+        self.expected_services |= ServiceFlags::P2P_V2;
+        self
+    }
+
     /// Offer services to the peer
     pub fn offer_services(mut self, service_flags: ServiceFlags) -> Self {
         self.our_services = service_flags;
@@ -98,7 +574,8 @@ impl ConnectionConfig {
         self
     }
 
-    /// Advertise a compact block version
+    /// Advertise a compact block version. Nodes that offer [`ServiceFlags::WITNESS`] must
+    /// advertise version 2, otherwise the handshake fails.
     pub fn send_cmpct(mut self, send_cmpct: SendCmpct) -> Self {
         self.send_cmpct = send_cmpct;
         self
@@ -112,16 +589,37 @@ impl ConnectionConfig {
 
     /// Advertise the minimum fee rate required to gossip transactions
     pub fn fee_filter(mut self, fee_filter: FeeRate) -> Self {
-        self.fee_filter = fee_filter;
+        self.fee_filter = FeeFilterFn::constant(fee_filter);
+        self
+    }
+
+    /// Advertise the minimum fee rate required to gossip transactions, evaluated when each
+    /// connection completes the handshake.
+    pub fn fee_filter_fn(
+        mut self,
+        fee_filter: impl Fn() -> FeeRate + Send + Sync + 'static,
+    ) -> Self {
+        self.fee_filter = FeeFilterFn(Arc::new(fee_filter));
         self
     }
 
-    pub(crate) fn build_our_version(&self, unix_time: Duration, nonce: u64) -> VersionMessage {
+    /// Build the version message sent to the peer at the given time since the Unix epoch, with
+    /// the address of the peer if it is known.
+    pub fn build_our_version(
+        &self,
+        unix_time: Duration,
+        nonce: u64,
+        their_addr: Option<SocketAddr>,
+    ) -> VersionMessage {
+        // The receiver is advertised with the services we expect them to have
+        let receiver = their_addr
+            .map(|addr| Address::new(&addr, self.expected_services))
+            .unwrap_or(UNREACHABLE);
         VersionMessage {
             version: self.our_version,
             services: self.our_services,
             timestamp: unix_time.as_secs() as i64,
-            receiver: UNREACHABLE,
+            receiver,
             sender: UNREACHABLE,
             nonce,
             user_agent: self.user_agent.clone(),
@@ -136,6 +634,11 @@ impl ConnectionConfig {
         network_message: NetworkMessage,
         nonce: u64,
     ) -> Result<(InitializedHandshake, Vec<NetworkMessage>), Error> {
+        if let NetworkMessage::Reject(reject) = &network_message {
+            if let Some(info) = RejectInfo::from_version_reject(reject) {
+                return Err(Error::VersionRejected(info));
+            }
+        }
         let version = match network_message {
             NetworkMessage::Version(version) => version,
             e => return Err(Error::IrrelevantMessage(e.command())),
@@ -152,6 +655,26 @@ impl ConnectionConfig {
         if !version.services.has(self.expected_services) {
             return Err(Error::MissingService(version.services));
         }
+        if version.start_height < 0 {
+            return Err(Error::InvalidHeight(version.start_height));
+        }
+        let unknown = ServiceFlags::from(version.services.to_u64() & !known_services().to_u64());
+        if unknown != ServiceFlags::NONE {
+            match self.unknown_services {
+                UnknownServicePolicy::Ignore => (),
+                UnknownServicePolicy::Log(log) => log(unknown),
+                UnknownServicePolicy::Reject => {
+                    if unknown == version.services {
+                        return Err(Error::UnknownServices(unknown));
+                    }
+                }
+            }
+        }
+        let witness = self.our_services.has(ServiceFlags::WITNESS);
+        let compact_version = self.send_cmpct.version;
+        if witness && compact_version != 0 && compact_version != WITNESS_COMPACT_VERSION {
+            return Err(Error::UnsupportedCompactVersion(compact_version));
+        }
         let effective_version = std::cmp::min(self.our_version, version.version);
         if effective_version >= ProtocolVersion::WTXID_RELAY_VERSION {
             suggested_messages.push(NetworkMessage::WtxidRelay);
@@ -168,6 +691,7 @@ impl ConnectionConfig {
         let net_time_difference = unix_time.as_secs_f64() as i64 - version.timestamp;
         let feeler = FeelerData {
             effective_version,
+            their_version: version.version,
             services: version.services,
             net_time_difference,
             reported_height: version.start_height,
@@ -175,10 +699,13 @@ impl ConnectionConfig {
         };
         let handshake = InitializedHandshake {
             feeler,
+            their_advertised_address: version.sender,
             their_preferences: Preferences::default(),
             send_cmpct: self.send_cmpct,
-            fee_filter: self.fee_filter,
+            witness,
+            fee_filter: (!self.block_relay_only).then(|| (self.fee_filter.0)()),
             request_addr: self.request_addr,
+            pending_pongs: Vec::new(),
         };
         Ok((handshake, suggested_messages))
     }
@@ -190,13 +717,215 @@ impl Default for ConnectionConfig {
     }
 }
 
+#[cfg(feature = "serde")]
+mod serde_impl {
+    use std::{net::SocketAddr, time::Duration};
+
+    use bitcoin::{consensus, FeeRate, Network};
+    use p2p::{message_compact_blocks::SendCmpct, message_network::UserAgent, ProtocolVersion};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::{ConnectionConfig, FeeFilterFn, TransportPreference};
+    use crate::BanScore;
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(default)]
+    struct ConnectionConfigRepr {
+        our_version: u32,
+        our_services: u64,
+        expected_version: u32,
+        expected_services: u64,
+        send_compact: bool,
+        compact_version: u64,
+        user_agent: String,
+        our_height: i32,
+        fee_filter: FeeRate,
+        network: Network,
+        request_addr: bool,
+        decode_error_tolerance: u32,
+        buffered_writes: bool,
+        max_lifetime: Option<Duration>,
+        message_history: usize,
+        trusted: bool,
+        socket_buffers: Option<(usize, usize)>,
+        handshake_jitter: Option<Duration>,
+        block_relay_only: bool,
+        max_addr_per_message: usize,
+        disconnect_on_addr_flood: bool,
+        check_filter_headers: bool,
+        bind_local: Option<SocketAddr>,
+        max_unrequested_blocks: Option<u64>,
+        check_header_pow: bool,
+        transport: TransportPreference,
+        ban_score_points: (u32, u32),
+    }
+
+    fn version_to_u32(version: ProtocolVersion) -> u32 {
+        let bytes = consensus::serialize(&version);
+        u32::from_le_bytes(bytes.try_into().expect("versions are four bytes"))
+    }
+
+    impl From<&ConnectionConfig> for ConnectionConfigRepr {
+        fn from(config: &ConnectionConfig) -> Self {
+            Self {
+                our_version: version_to_u32(config.our_version),
+                our_services: config.our_services.to_u64(),
+                expected_version: version_to_u32(config.expected_version),
+                expected_services: config.expected_services.to_u64(),
+                send_compact: config.send_cmpct.send_compact,
+                compact_version: config.send_cmpct.version,
+                user_agent: config.user_agent.to_string(),
+                our_height: config.our_height,
+                fee_filter: (config.fee_filter.0)(),
+                network: config.network,
+                request_addr: config.request_addr,
+                decode_error_tolerance: config.decode_error_tolerance,
+                buffered_writes: config.buffered_writes,
+                max_lifetime: config.max_lifetime,
+                message_history: config.message_history,
+                trusted: config.trusted,
+                socket_buffers: config.socket_buffers,
+                handshake_jitter: config.handshake_jitter,
+                block_relay_only: config.block_relay_only,
+                max_addr_per_message: config.max_addr_per_message,
+                disconnect_on_addr_flood: config.disconnect_on_addr_flood,
+                check_filter_headers: config.check_filter_headers,
+                bind_local: config.bind_local,
+                max_unrequested_blocks: config.max_unrequested_blocks,
+                check_header_pow: config.check_header_pow,
+                transport: config.transport,
+                ban_score_points: config.ban_score.points(),
+            }
+        }
+    }
+
+    impl Default for ConnectionConfigRepr {
+        fn default() -> Self {
+            Self::from(&ConnectionConfig::new())
+        }
+    }
+
+    impl Serialize for ConnectionConfig {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            ConnectionConfigRepr::from(self).serialize(serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for ConnectionConfig {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let repr = ConnectionConfigRepr::deserialize(deserializer)?;
+            let mut config = ConnectionConfig::new();
+            config.our_version = ProtocolVersion::from_nonstandard(repr.our_version);
+            config.our_services = repr.our_services.into();
+            config.expected_version = ProtocolVersion::from_nonstandard(repr.expected_version);
+            config.expected_services = repr.expected_services.into();
+            config.send_cmpct = SendCmpct {
+                send_compact: repr.send_compact,
+                version: repr.compact_version,
+            };
+            config.user_agent = UserAgent::from_nonstandard(&repr.user_agent);
+            config.our_height = repr.our_height;
+            config.fee_filter = FeeFilterFn::constant(repr.fee_filter);
+            config.network = repr.network;
+            config.request_addr = repr.request_addr;
+            config.decode_error_tolerance = repr.decode_error_tolerance;
+            config.buffered_writes = repr.buffered_writes;
+            config.max_lifetime = repr.max_lifetime;
+            config.message_history = repr.message_history;
+            config.trusted = repr.trusted;
+            config.socket_buffers = repr.socket_buffers;
+            config.handshake_jitter = repr.handshake_jitter;
+            config.block_relay_only = repr.block_relay_only;
+            config.max_addr_per_message = repr.max_addr_per_message;
+            config.disconnect_on_addr_flood = repr.disconnect_on_addr_flood;
+            config.check_filter_headers = repr.check_filter_headers;
+            config.bind_local = repr.bind_local;
+            config.max_unrequested_blocks = repr.max_unrequested_blocks;
+            config.check_header_pow = repr.check_header_pow;
+            config.transport = repr.transport;
+            config.ban_score = BanScore::new(repr.ban_score_points.0, repr.ban_score_points.1);
+            Ok(config)
+        }
+    }
+}
+
+/// Fixtures for testing how handshakes are accepted or rejected, available with the
+/// `test-support` feature.
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use p2p::{
+        message::NetworkMessage,
+        message_network::{UserAgent, VersionMessage},
+        Address, ProtocolVersion, ServiceFlags,
+    };
+
+    use super::{ConnectionConfig, Error};
+    use crate::FeelerData;
+
+    // The nonce of our side of the handshake, which differs from the default mock nonce
+    const OUR_NONCE: u64 = 43;
+
+    /// Build a version message as a remote peer would send it, with a height of zero and a
+    /// nonce that differs from [`our_nonce`]. Fields may be changed on the returned message.
+    pub fn build_mock_version(
+        with_version: ProtocolVersion,
+        with_services: ServiceFlags,
+    ) -> VersionMessage {
+        VersionMessage {
+            version: with_version,
+            services: with_services,
+            timestamp: 222222222,
+            receiver: Address::useless(),
+            sender: Address::useless(),
+            nonce: 42,
+            user_agent: UserAgent::from_nonstandard("hello"),
+            start_height: 0,
+            relay: false,
+        }
+    }
+
+    /// The nonce used for our side of handshakes driven by [`drive_handshake`].
+    pub fn our_nonce() -> u64 {
+        OUR_NONCE
+    }
+
+    /// Run a handshake with the given configuration, where the peer sends `first` followed by
+    /// `messages`. A `verack` is sent last if the messages do not complete the handshake. Returns
+    /// the data learned about the peer, or the error the handshake ends with.
+    pub fn drive_handshake(
+        config: ConnectionConfig,
+        first: NetworkMessage,
+        messages: impl IntoIterator<Item = NetworkMessage>,
+    ) -> Result<FeelerData, Error> {
+        let unix_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let (mut handshake, _) = config.start_handshake(unix_time, first, OUR_NONCE)?;
+        for message in messages {
+            if let Some((completed, _)) = handshake.negotiate(message)? {
+                return Ok(completed.feeler);
+            }
+        }
+        let verack = NetworkMessage::Verack;
+        match handshake.negotiate(verack.clone())? {
+            Some((completed, _)) => Ok(completed.feeler),
+            None => Err(Error::IrrelevantMessage(verack.command())),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct InitializedHandshake {
     feeler: FeelerData,
+    their_advertised_address: Address,
     their_preferences: Preferences,
-    fee_filter: FeeRate,
+    fee_filter: Option<FeeRate>,
     send_cmpct: SendCmpct,
+    witness: bool,
     request_addr: bool,
+    pending_pongs: Vec<NetworkMessage>,
 }
 
 impl InitializedHandshake {
@@ -207,16 +936,21 @@ impl InitializedHandshake {
         match message {
             NetworkMessage::Verack => {
                 let verack = NetworkMessage::Verack;
-                let fee_filter = NetworkMessage::FeeFilter(self.fee_filter);
                 let send_cmpct = NetworkMessage::SendCmpct(self.send_cmpct);
-                let mut messages = vec![verack, send_cmpct, fee_filter];
+                let mut messages = vec![verack, send_cmpct];
+                if let Some(fee_filter) = self.fee_filter {
+                    messages.push(NetworkMessage::FeeFilter(fee_filter));
+                }
                 if self.request_addr {
                     messages.push(NetworkMessage::GetAddr);
                 }
+                messages.append(&mut self.pending_pongs);
                 Ok(Some((
                     CompletedHandshake {
                         feeler: self.feeler,
+                        their_advertised_address: self.their_advertised_address.clone(),
                         their_preferences: self.their_preferences,
+                        our_send_cmpct: self.send_cmpct,
                     },
                     messages,
                 )))
@@ -230,13 +964,30 @@ impl InitializedHandshake {
                 Ok(None)
             }
             NetworkMessage::SendCmpct(cmpct) => {
-                self.their_preferences.sendcmpct = cmpct;
+                announce_compact(&mut self.their_preferences.sendcmpct, cmpct, self.witness);
                 Ok(None)
             }
             NetworkMessage::SendHeaders => {
                 self.their_preferences.sendheaders = true;
                 Ok(None)
             }
+            NetworkMessage::FeeFilter(fee_filter) => {
+                self.their_preferences.fee_filter = Some(fee_filter);
+                Ok(None)
+            }
+            // Answered along with the verack
+            NetworkMessage::Ping(nonce) => {
+                self.pending_pongs.push(NetworkMessage::Pong(nonce));
+                Ok(None)
+            }
+            // Bitcoin Core ignores these before verack, so the connection may continue
+            NetworkMessage::Alert(_) | NetworkMessage::GetAddr => Ok(None),
+            NetworkMessage::Reject(reject) => match RejectInfo::from_version_reject(&reject) {
+                Some(info) => Err(Error::VersionRejected(info)),
+                None => Err(Error::IrrelevantMessage(
+                    NetworkMessage::Reject(reject).command(),
+                )),
+            },
             e => Err(Error::IrrelevantMessage(e.command())),
         }
     }
@@ -245,7 +996,32 @@ impl InitializedHandshake {
 #[derive(Debug, Clone)]
 pub(crate) struct CompletedHandshake {
     pub(crate) feeler: FeelerData,
+    pub(crate) their_advertised_address: Address,
     pub(crate) their_preferences: Preferences,
+    pub(crate) our_send_cmpct: SendCmpct,
+}
+
+/// The reason a peer gave for rejecting our version message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectInfo {
+    /// The category of the rejection.
+    pub code: RejectReason,
+    /// The explanation sent by the peer.
+    pub reason: String,
+}
+
+impl RejectInfo {
+    // Much older peers may reject our version outright instead of sending their own
+    fn from_version_reject(reject: &Reject) -> Option<Self> {
+        let version = CommandString::try_from_static("version").expect("valid command");
+        if reject.message != version {
+            return None;
+        }
+        Some(Self {
+            code: reject.ccode,
+            reason: reject.reason.to_string(),
+        })
+    }
 }
 
 /// Errors that occur during a handshake
@@ -259,6 +1035,18 @@ pub enum Error {
     TooLowVersion(ProtocolVersion),
     /// The peer is missing a required service
     MissingService(ServiceFlags),
+    /// The user agent exceeds the maximum length
+    UserAgentTooLong(usize),
+    /// A name or version of a user agent component contains `/`, `:` or `(`
+    InvalidUserAgentComponent(String),
+    /// The peer reported a negative block height
+    InvalidHeight(i32),
+    /// The peer rejected our version message
+    VersionRejected(RejectInfo),
+    /// The compact block version advertised does not commit to witness data
+    UnsupportedCompactVersion(u64),
+    /// The peer only advertises services that are not known
+    UnknownServices(ServiceFlags),
 }
 
 impl Display for Error {
@@ -268,37 +1056,62 @@ impl Display for Error {
             Error::TooLowVersion(version) => write!(f, "too low version: {version:?}"),
             Error::IrrelevantMessage(irrelevant) => write!(f, "irrelevant message: {irrelevant}"),
             Error::MissingService(services) => write!(f, "missing services: {services}"),
+            Error::UserAgentTooLong(len) => write!(f, "user agent too long: {len} bytes"),
+            Error::InvalidUserAgentComponent(part) => {
+                write!(f, "invalid user agent component: {part}")
+            }
+            Error::InvalidHeight(height) => write!(f, "invalid height: {height}"),
+            Error::VersionRejected(info) => {
+                write!(f, "version rejected: {:?} {}", info.code, info.reason)
+            }
+            Error::UnsupportedCompactVersion(version) => {
+                write!(
+                    f,
+                    "compact block version {version} does not support witness"
+                )
+            }
+            Error::UnknownServices(services) => write!(f, "only unknown services: {services}"),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::time::{SystemTime, UNIX_EPOCH};
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        time::{SystemTime, UNIX_EPOCH},
+    };
 
+    use bitcoin::{FeeRate, Network};
     use p2p::{
+        message::CommandString,
         message::NetworkMessage,
-        message_network::{UserAgent, VersionMessage},
+        message_network::{Reject, RejectReason, UserAgent},
         ProtocolVersion, ServiceFlags,
     };
 
-    use super::ConnectionConfig;
+    use super::{
+        test_support::build_mock_version, ConnectionConfig, Error, RejectInfo,
+        UnknownServicePolicy, UserAgentBuilder,
+    };
 
-    fn build_mock_version(
-        with_version: ProtocolVersion,
-        with_services: ServiceFlags,
-    ) -> VersionMessage {
-        VersionMessage {
-            version: with_version,
-            services: with_services,
-            timestamp: 222222222,
-            receiver: p2p::Address::useless(),
-            sender: p2p::Address::useless(),
-            nonce: 42,
-            user_agent: UserAgent::from_nonstandard("hello"),
-            start_height: 0,
-            relay: false,
-        }
+    #[test]
+    fn test_version_rejected() {
+        let reject = Reject {
+            message: CommandString::try_from_static("version").unwrap(),
+            ccode: RejectReason::Obsolete,
+            reason: "version too high".into(),
+            hash: bitcoin::hashes::sha256d::Hash::from_byte_array([0; 32]),
+        };
+        let system_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let err = ConnectionConfig::new()
+            .start_handshake(system_time, NetworkMessage::Reject(reject), 43)
+            .unwrap_err();
+        let expected = RejectInfo {
+            code: RejectReason::Obsolete,
+            reason: "version too high".to_string(),
+        };
+        assert!(matches!(err, Error::VersionRejected(info) if info == expected));
     }
 
     #[test]
@@ -338,6 +1151,265 @@ mod tests {
         assert!(!completed.their_preferences.sendheaders);
     }
 
+    #[test]
+    fn test_version_receiver() {
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 8333));
+        let connection_config =
+            ConnectionConfig::new().set_service_requirement(ServiceFlags::NETWORK);
+        let system_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let version = connection_config.build_our_version(system_time, 43, Some(addr));
+        assert_eq!(version.receiver.port, 8333);
+        assert_eq!(version.receiver.services, ServiceFlags::NETWORK);
+        let version = connection_config.build_our_version(system_time, 43, None);
+        assert_eq!(version.receiver, p2p::Address::useless());
+    }
+
+    #[test]
+    fn test_lenient_pre_verack() {
+        let mock = build_mock_version(ProtocolVersion::WTXID_RELAY_VERSION, ServiceFlags::NONE);
+        let connection_config = ConnectionConfig::new();
+        let system_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let (mut init_handshake, _) = connection_config
+            .start_handshake(system_time, NetworkMessage::Version(mock), 43)
+            .unwrap();
+        let fee_filter = NetworkMessage::FeeFilter(FeeRate::BROADCAST_MIN);
+        assert!(init_handshake.negotiate(fee_filter).unwrap().is_none());
+        let send_headers = NetworkMessage::SendHeaders;
+        assert!(init_handshake.negotiate(send_headers).unwrap().is_none());
+        assert!(init_handshake.negotiate(NetworkMessage::MemPool).is_err());
+        let (completed, _) = init_handshake
+            .negotiate(NetworkMessage::Verack)
+            .unwrap()
+            .unwrap();
+        assert!(completed.their_preferences.sendheaders);
+        assert_eq!(
+            completed.their_preferences.fee_filter,
+            Some(FeeRate::BROADCAST_MIN)
+        );
+    }
+
+    #[test]
+    fn test_sensible_defaults_for_network() {
+        let mock = build_mock_version(ProtocolVersion::BIP0031_VERSION, ServiceFlags::NONE);
+        let system_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let regtest = ConnectionConfig::new()
+            .change_network(Network::Regtest)
+            .sensible_defaults_for_network();
+        assert!(regtest
+            .start_handshake(system_time, NetworkMessage::Version(mock.clone()), 43)
+            .is_ok());
+        let mainnet = ConnectionConfig::new().sensible_defaults_for_network();
+        assert!(mainnet
+            .start_handshake(system_time, NetworkMessage::Version(mock), 43)
+            .is_err());
+    }
+
+    #[test]
+    fn test_support_rejections() {
+        use super::test_support::{drive_handshake, our_nonce};
+
+        let config = ConnectionConfig::new;
+        let mock = build_mock_version(ProtocolVersion::WTXID_RELAY_VERSION, ServiceFlags::NONE);
+        let low = build_mock_version(ProtocolVersion::BIP0031_VERSION, ServiceFlags::NONE);
+        assert!(matches!(
+            drive_handshake(config(), NetworkMessage::Version(low), []),
+            Err(Error::TooLowVersion(_))
+        ));
+        let mut to_self = mock.clone();
+        to_self.nonce = our_nonce();
+        assert!(matches!(
+            drive_handshake(config(), NetworkMessage::Version(to_self), []),
+            Err(Error::ConnectionToSelf)
+        ));
+        let mut negative = mock.clone();
+        negative.start_height = -1;
+        assert!(matches!(
+            drive_handshake(config(), NetworkMessage::Version(negative), []),
+            Err(Error::InvalidHeight(-1))
+        ));
+        let duplicate = NetworkMessage::Version(mock.clone());
+        assert!(matches!(
+            drive_handshake(config(), duplicate.clone(), [duplicate]),
+            Err(Error::IrrelevantMessage(_))
+        ));
+        assert!(matches!(
+            drive_handshake(config(), NetworkMessage::Verack, []),
+            Err(Error::IrrelevantMessage(_))
+        ));
+        let mut tall = mock;
+        tall.start_height = 5;
+        let feeler = drive_handshake(config(), NetworkMessage::Version(tall), []).unwrap();
+        assert_eq!(feeler.reported_height, 5);
+    }
+
+    #[test]
+    fn test_peer_is_newer() {
+        let system_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let future = build_mock_version(
+            ProtocolVersion::from_nonstandard(80_000),
+            ServiceFlags::NONE,
+        );
+        let (handshake, _) = ConnectionConfig::new()
+            .start_handshake(system_time, NetworkMessage::Version(future), 43)
+            .unwrap();
+        assert!(handshake.feeler.peer_is_newer());
+        assert_eq!(
+            handshake.feeler.effective_version,
+            ProtocolVersion::WTXID_RELAY_VERSION
+        );
+        let current = build_mock_version(ProtocolVersion::WTXID_RELAY_VERSION, ServiceFlags::NONE);
+        let (handshake, _) = ConnectionConfig::new()
+            .start_handshake(system_time, NetworkMessage::Version(current), 43)
+            .unwrap();
+        assert!(!handshake.feeler.peer_is_newer());
+    }
+
+    #[test]
+    fn test_block_relay_only() {
+        let mock = build_mock_version(ProtocolVersion::WTXID_RELAY_VERSION, ServiceFlags::NONE);
+        let connection_config = ConnectionConfig::new().block_relay_only();
+        let system_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let (mut init_handshake, _) = connection_config
+            .start_handshake(system_time, NetworkMessage::Version(mock), 43)
+            .unwrap();
+        let (_, responses) = init_handshake
+            .negotiate(NetworkMessage::Verack)
+            .unwrap()
+            .unwrap();
+        assert!(!responses
+            .iter()
+            .any(|message| matches!(message, NetworkMessage::FeeFilter(_))));
+    }
+
+    #[test]
+    fn test_require_v2_service() {
+        use super::test_support::drive_handshake;
+
+        let config = ConnectionConfig::new().require_v2_service();
+        let v1 = build_mock_version(ProtocolVersion::WTXID_RELAY_VERSION, ServiceFlags::WITNESS);
+        assert!(matches!(
+            drive_handshake(config.clone(), NetworkMessage::Version(v1), []),
+            Err(Error::MissingService(_))
+        ));
+        let v2 = build_mock_version(
+            ProtocolVersion::WTXID_RELAY_VERSION,
+            ServiceFlags::WITNESS | ServiceFlags::P2P_V2,
+        );
+        assert!(drive_handshake(config, NetworkMessage::Version(v2), []).is_ok());
+    }
+
+    #[test]
+    fn test_witness_compact_version() {
+        use super::test_support::{drive_handshake, our_nonce};
+
+        let v1 = SendCmpct {
+            send_compact: true,
+            version: 1,
+        };
+        let v2 = SendCmpct {
+            send_compact: true,
+            version: 2,
+        };
+        let config = ConnectionConfig::new().offer_services(ServiceFlags::WITNESS);
+        let mock = NetworkMessage::Version(build_mock_version(
+            ProtocolVersion::WTXID_RELAY_VERSION,
+            ServiceFlags::WITNESS,
+        ));
+        assert!(matches!(
+            drive_handshake(config.clone().send_cmpct(v1), mock.clone(), []),
+            Err(Error::UnsupportedCompactVersion(1))
+        ));
+        let system_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let (mut init_handshake, _) = config
+            .send_cmpct(v2)
+            .start_handshake(system_time, mock, our_nonce())
+            .unwrap();
+        assert!(init_handshake
+            .negotiate(NetworkMessage::SendCmpct(v2))
+            .unwrap()
+            .is_none());
+        assert!(init_handshake
+            .negotiate(NetworkMessage::SendCmpct(v1))
+            .unwrap()
+            .is_none());
+        let (completed, _) = init_handshake
+            .negotiate(NetworkMessage::Verack)
+            .unwrap()
+            .unwrap();
+        assert_eq!(completed.their_preferences.sendcmpct.version, 2);
+    }
+
+    #[test]
+    fn test_unknown_services() {
+        use super::test_support::drive_handshake;
+
+        let unknown = ServiceFlags::from(1_u64 << 30);
+        let config = ConnectionConfig::new().on_unknown_services(UnknownServicePolicy::Reject);
+        let only_unknown = NetworkMessage::Version(build_mock_version(
+            ProtocolVersion::WTXID_RELAY_VERSION,
+            unknown,
+        ));
+        assert!(matches!(
+            drive_handshake(config.clone(), only_unknown.clone(), []),
+            Err(Error::UnknownServices(_))
+        ));
+        let mixed = build_mock_version(
+            ProtocolVersion::WTXID_RELAY_VERSION,
+            unknown | ServiceFlags::WITNESS,
+        );
+        let feeler = drive_handshake(config, NetworkMessage::Version(mixed), []).unwrap();
+        assert_eq!(feeler.unknown_services(), unknown);
+        let config = ConnectionConfig::new();
+        assert!(drive_handshake(config, only_unknown, []).is_ok());
+    }
+
+    #[test]
+    fn test_ping_during_handshake() {
+        let mock = build_mock_version(ProtocolVersion::WTXID_RELAY_VERSION, ServiceFlags::NONE);
+        let connection_config = ConnectionConfig::new();
+        let system_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let (mut init_handshake, _) = connection_config
+            .start_handshake(system_time, NetworkMessage::Version(mock), 43)
+            .unwrap();
+        let nego = init_handshake.negotiate(NetworkMessage::Ping(42)).unwrap();
+        assert!(nego.is_none());
+        let (_, messages) = init_handshake
+            .negotiate(NetworkMessage::Verack)
+            .unwrap()
+            .unwrap();
+        assert!(matches!(messages.first().unwrap(), NetworkMessage::Verack));
+        assert!(matches!(messages.last().unwrap(), NetworkMessage::Pong(42)));
+    }
+
+    #[test]
+    fn test_user_agent_builder() {
+        let user_agent = UserAgentBuilder::new()
+            .push_component("Satoshi", "27.0")
+            .push_component("MyWallet", "1.2")
+            .build()
+            .unwrap();
+        assert_eq!(
+            user_agent,
+            UserAgent::from_nonstandard("/Satoshi:27.0/MyWallet:1.2/")
+        );
+        let long_name = "a".repeat(300);
+        assert!(UserAgentBuilder::new()
+            .push_component(long_name, "1.0")
+            .build()
+            .is_err());
+        for (name, version) in [
+            ("My/Wallet", "1.2"),
+            ("MyWallet", "1:2"),
+            ("My(Wallet", "1.2"),
+        ] {
+            let result = UserAgentBuilder::new()
+                .push_component("Satoshi", "27.0")
+                .push_component(name, version)
+                .build();
+            assert!(matches!(result, Err(Error::InvalidUserAgentComponent(_))));
+        }
+    }
+
     #[test]
     fn test_reject_low_version() {
         let mock = build_mock_version(
@@ -364,6 +1436,18 @@ mod tests {
             .is_err())
     }
 
+    #[test]
+    fn test_reject_negative_height() {
+        let mut mock = build_mock_version(ProtocolVersion::WTXID_RELAY_VERSION, ServiceFlags::NONE);
+        mock.start_height = i32::MIN;
+        let connection_config = ConnectionConfig::new();
+        let system_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        assert!(matches!(
+            connection_config.start_handshake(system_time, NetworkMessage::Version(mock), 43),
+            Err(Error::InvalidHeight(i32::MIN))
+        ));
+    }
+
     #[test]
     fn test_change_version_ok() {
         let mock = build_mock_version(ProtocolVersion::SENDHEADERS_VERSION, ServiceFlags::NONE);
@@ -391,4 +1475,31 @@ mod tests {
             .unwrap();
         assert!(matches!(messages.last().unwrap(), NetworkMessage::GetAddr));
     }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        use std::time::Duration;
+
+        use super::TransportPreference;
+
+        let config = ConnectionConfig::new()
+            .change_network(Network::Regtest)
+            .max_lifetime(Duration::from_secs(60))
+            .max_addr_per_message(100)
+            .transport(TransportPreference::Auto)
+            .ban_score_points(50, 5);
+        let json = serde_json::to_string(&config).unwrap();
+        let decoded: ConnectionConfig = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.network, Network::Regtest);
+        assert_eq!(decoded.max_lifetime, Some(Duration::from_secs(60)));
+        assert_eq!(decoded.max_addr_per_message, 100);
+        assert_eq!(decoded.transport, TransportPreference::Auto);
+        assert_eq!(decoded.ban_score.points(), (50, 5));
+        assert_eq!(serde_json::to_string(&decoded).unwrap(), json);
+        // Missing fields take their default values
+        let partial: ConnectionConfig = serde_json::from_str(r#"{"trusted":true}"#).unwrap();
+        assert!(partial.trusted);
+        assert_eq!(partial.network, ConnectionConfig::new().network);
+    }
 }
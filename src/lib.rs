@@ -1,15 +1,31 @@
 //! Bitcoin Peer-to-Peer connections.
 #![warn(missing_docs)]
 use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     time::{Duration, Instant},
 };
 
-use p2p::{message_compact_blocks::SendCmpct, ProtocolVersion, ServiceFlags};
+use bitcoin::{constants::genesis_block, FeeRate, Network};
+use p2p::{
+    message::{CommandString, InventoryPayload, NetworkMessage},
+    message_blockdata::Inventory,
+    message_compact_blocks::SendCmpct,
+    message_filter::GetCFCheckpt,
+    Address, ProtocolVersion, ServiceFlags,
+};
 
 pub extern crate p2p as p2p_message_types;
 
+use crate::{
+    net::{MessageProgress, TransportKind},
+    validation::ValidationExt,
+};
+
 /// Make bitcoin-specific DNS queries
 pub mod dns;
 /// Automated version negotiation with remote peers
@@ -19,12 +35,41 @@ pub mod net;
 /// Tools for validating messages and data
 pub mod validation;
 
+static NEXT_PEER_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The number of recent blocks a `NODE_NETWORK_LIMITED` peer must be able to serve, as defined
+/// in BIP-159.
+pub const NODE_NETWORK_LIMITED_BLOCKS: u32 = 288;
+
+/// A unique identifier for a connection, independent of the address of the peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, std::hash::Hash)]
+pub struct PeerId(u64);
+
+impl PeerId {
+    fn next() -> Self {
+        Self(NEXT_PEER_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// The underlying identifier.
+    pub fn to_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl Display for PeerId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "peer-{}", self.0)
+    }
+}
+
 /// The static data related to a connection. Note that this is referred to as "feeler" data because
 /// it may be used to collect data on very short-lived connections.
 #[derive(Debug, Clone, Copy)]
 pub struct FeelerData {
     /// The lowest common version of the connection.
     pub effective_version: ProtocolVersion,
+    /// The version the peer advertises.
+    pub their_version: ProtocolVersion,
     /// The service flags they advertise.
     pub services: ServiceFlags,
     /// The net time difference between our time and what they report.
@@ -35,6 +80,122 @@ pub struct FeelerData {
     pub nonce: u64,
 }
 
+impl FeelerData {
+    /// The peer advertises a newer protocol version than ours, which may indicate our software is
+    /// behind the network.
+    pub fn peer_is_newer(&self) -> bool {
+        self.their_version > self.effective_version
+    }
+
+    /// The peer only serves recent blocks, as it advertises `NODE_NETWORK_LIMITED` without
+    /// `NODE_NETWORK`.
+    pub fn is_pruned(&self) -> bool {
+        self.services.has(ServiceFlags::NETWORK_LIMITED)
+            && !self.services.has(ServiceFlags::NETWORK)
+    }
+
+    /// Can the peer be expected to serve the block at `height` given the current chain tip. A
+    /// pruned peer may only serve the last [`NODE_NETWORK_LIMITED_BLOCKS`] blocks.
+    pub fn can_serve_block(&self, height: u32, tip_height: u32) -> bool {
+        if height > tip_height {
+            return false;
+        }
+        if self.services.has(ServiceFlags::NETWORK) {
+            return true;
+        }
+        self.services.has(ServiceFlags::NETWORK_LIMITED)
+            && tip_height - height < NODE_NETWORK_LIMITED_BLOCKS
+    }
+
+    /// The services this peer advertises that are not known to this crate, which may be signaled
+    /// by newer software.
+    pub fn unknown_services(&self) -> ServiceFlags {
+        ServiceFlags::from(self.services.to_u64() & !known_services().to_u64())
+    }
+
+    /// The services in `required` that this peer does not advertise.
+    pub fn missing_services(&self, required: ServiceFlags) -> ServiceFlags {
+        ServiceFlags::from(required.to_u64() & !self.services.to_u64())
+    }
+
+    /// Build messages that probe the services this peer advertises. Sending these after the
+    /// handshake allows the reader to record which services the peer actually provides, available
+    /// with [`ConnectionMetrics::verified_services`].
+    ///
+    /// `NODE_NETWORK` is probed by requesting the genesis block, and `NODE_COMPACT_FILTERS` is
+    /// probed by requesting a filter checkpoint at the genesis block.
+    pub fn service_probes(&self, network: Network) -> Vec<NetworkMessage> {
+        let genesis = genesis_block(network).block_hash();
+        let mut probes = Vec::new();
+        if self.services.has(ServiceFlags::NETWORK) {
+            probes.push(NetworkMessage::GetData(InventoryPayload(vec![
+                Inventory::Block(genesis),
+            ])));
+        }
+        if self.services.has(ServiceFlags::COMPACT_FILTERS) {
+            probes.push(NetworkMessage::GetCFCheckpt(GetCFCheckpt {
+                filter_type: 0x00,
+                stop_hash: genesis,
+            }));
+        }
+        probes
+    }
+
+    /// Rank the usefulness of the services this peer offers with the default [`ServiceWeights`].
+    pub fn service_score(&self) -> u32 {
+        self.service_score_with(&ServiceWeights::default())
+    }
+
+    /// Rank the usefulness of the services this peer offers, where each advertised service adds
+    /// its weight to the score.
+    pub fn service_score_with(&self, weights: &ServiceWeights) -> u32 {
+        [
+            (ServiceFlags::NETWORK, weights.network),
+            (ServiceFlags::WITNESS, weights.witness),
+            (ServiceFlags::COMPACT_FILTERS, weights.compact_filters),
+            (ServiceFlags::P2P_V2, weights.p2p_v2),
+        ]
+        .into_iter()
+        .filter(|(service, _)| self.services.has(*service))
+        .map(|(_, weight)| weight)
+        .sum()
+    }
+}
+
+pub(crate) fn known_services() -> ServiceFlags {
+    ServiceFlags::NETWORK
+        | ServiceFlags::GETUTXO
+        | ServiceFlags::BLOOM
+        | ServiceFlags::WITNESS
+        | ServiceFlags::COMPACT_FILTERS
+        | ServiceFlags::NETWORK_LIMITED
+        | ServiceFlags::P2P_V2
+}
+
+/// The value of each service when ranking peers with [`FeelerData::service_score_with`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ServiceWeights {
+    /// Weight of `NODE_NETWORK`.
+    pub network: u32,
+    /// Weight of `NODE_WITNESS`.
+    pub witness: u32,
+    /// Weight of `NODE_COMPACT_FILTERS`.
+    pub compact_filters: u32,
+    /// Weight of `NODE_P2P_V2`.
+    pub p2p_v2: u32,
+}
+
+impl Default for ServiceWeights {
+    fn default() -> Self {
+        Self {
+            network: 4,
+            witness: 4,
+            compact_filters: 2,
+            p2p_v2: 1,
+        }
+    }
+}
+
 /// The peer's preferences during this connection. These are updated automatically as the peer
 /// shares information.
 #[derive(Debug, Clone, Copy)]
@@ -47,6 +208,8 @@ pub struct Preferences {
     pub sendcmpct: SendCmpct,
     /// Advertise transactions by WTXID.
     pub sendwtxid: bool,
+    /// The minimum fee rate of transactions to announce, if the peer has sent one.
+    pub fee_filter: Option<FeeRate>,
 }
 
 impl Preferences {
@@ -59,6 +222,7 @@ impl Preferences {
                 version: 0x00,
             },
             sendwtxid: false,
+            fee_filter: None,
         }
     }
 }
@@ -72,31 +236,205 @@ impl Default for Preferences {
 /// Data collected during a connection that is continually updated in the background
 #[derive(Debug, Clone)]
 pub struct ConnectionMetrics {
+    peer_id: PeerId,
     feeler: FeelerData,
+    their_advertised_address: Address,
     their_preferences: Arc<Mutex<Preferences>>,
     timed_messages: Arc<Mutex<TimedMessages>>,
     start_time: Instant,
     outbound_ping_state: Arc<Mutex<OutboundPing>>,
+    verified_services: Arc<Mutex<ServiceFlags>>,
+    message_progress: MessageProgress,
+    our_send_cmpct: SendCmpct,
+    message_history: Option<Arc<Mutex<MessageHistory>>>,
+    addr_gossip: Arc<Mutex<AddrGossip>>,
+    unsolicited_pongs: Arc<Mutex<u64>>,
+    oversized_addrs: Arc<Mutex<u64>>,
+    protocol_violations: Arc<Mutex<u64>>,
+    bytes_by_type: Arc<Mutex<HashMap<CommandString, u64>>>,
+    unrequested_blocks: Arc<Mutex<u64>>,
+    ping_rtt: Arc<Mutex<Option<Duration>>>,
+    first_message: Arc<Mutex<Option<CommandString>>>,
+    bytes_received: Arc<Mutex<u64>>,
+    bytes_sent: Arc<Mutex<u64>>,
+    ban_score: Arc<Mutex<BanScore>>,
+    transport: TransportKind,
+    #[cfg(feature = "debug-v2-session")]
+    v2_session_id: Option<[u8; 32]>,
 }
 
 impl ConnectionMetrics {
+    /// The identifier for this connection.
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
     /// Static data about the peer
     pub fn feeler_data(&self) -> &FeelerData {
         &self.feeler
     }
 
+    /// The address the peer advertises for itself in the version message.
+    pub fn their_advertised_address(&self) -> &Address {
+        &self.their_advertised_address
+    }
+
+    /// The peer advertises a newer protocol version than ours. See [`FeelerData::peer_is_newer`].
+    pub fn peer_is_newer(&self) -> bool {
+        self.feeler.peer_is_newer()
+    }
+
     /// Their current preferences for message exchange, if not currently being mutated.
     pub fn their_preferences(&self) -> Option<Preferences> {
         let pref = self.their_preferences.lock().ok();
         pref.as_deref().copied()
     }
 
+    /// The number of pongs that did not answer an outstanding ping, either because the nonce did
+    /// not match or no ping was sent.
+    pub fn unsolicited_pong_count(&self) -> u64 {
+        self.unsolicited_pongs
+            .lock()
+            .map(|lock| *lock)
+            .unwrap_or_default()
+    }
+
+    /// The number of `addr` or `addrv2` messages that exceeded the configured maximum number of
+    /// addresses.
+    pub fn oversized_addr_count(&self) -> u64 {
+        self.oversized_addrs
+            .lock()
+            .map(|lock| *lock)
+            .unwrap_or_default()
+    }
+
+    /// The number of capability messages the peer sent despite the negotiated version not
+    /// supporting them, for instance `wtxidrelay` below [`ProtocolVersion::WTXID_RELAY_VERSION`].
+    /// These messages are discarded and do not change the peer's preferences.
+    pub fn protocol_violation_count(&self) -> u64 {
+        self.protocol_violations
+            .lock()
+            .map(|lock| *lock)
+            .unwrap_or_default()
+    }
+
+    /// The points the peer accumulated for malformed or discouraged messages, see [`BanScore`].
+    pub fn ban_score(&self) -> u32 {
+        self.ban_score
+            .lock()
+            .map(|lock| lock.score())
+            .unwrap_or_default()
+    }
+
+    /// Has the peer's ban score reached the threshold.
+    pub fn should_disconnect(&self, threshold: u32) -> bool {
+        self.ban_score() >= threshold
+    }
+
+    /// The number of `block` messages the peer sent without them being requested by `getdata`.
+    pub fn unrequested_block_count(&self) -> u64 {
+        self.unrequested_blocks
+            .lock()
+            .map(|lock| *lock)
+            .unwrap_or_default()
+    }
+
+    /// The bytes sent and received over the wire for each message command, including the message
+    /// headers, since the handshake completed.
+    pub fn bytes_by_type(&self) -> HashMap<CommandString, u64> {
+        self.bytes_by_type
+            .lock()
+            .map(|lock| lock.clone())
+            .unwrap_or_default()
+    }
+
+    /// The bytes read off the wire since the handshake completed, including message headers.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received
+            .lock()
+            .map(|lock| *lock)
+            .unwrap_or_default()
+    }
+
+    /// The bytes written to the wire since the handshake completed, including message headers.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.lock().map(|lock| *lock).unwrap_or_default()
+    }
+
+    /// Transactions are announced by WTXID in both directions, as each side sent `wtxidrelay`
+    /// during the handshake.
+    pub fn wtxid_relay_active(&self) -> bool {
+        // We send `wtxidrelay` when the negotiated version supports it
+        let we_sent = self.feeler.effective_version >= ProtocolVersion::WTXID_RELAY_VERSION;
+        we_sent
+            && self
+                .their_preferences()
+                .is_some_and(|preferences| preferences.sendwtxid)
+    }
+
+    /// The most recent fee rate the peer has asked us to respect when announcing transactions, or
+    /// `None` if the peer has not sent a `feefilter`. This approximates the minimum fee rate
+    /// accepted to their mempool, however the filter is advisory and the peer may still reject
+    /// transactions that pay a higher fee rate.
+    pub fn peer_fee_filter(&self) -> Option<FeeRate> {
+        self.their_preferences().and_then(|pref| pref.fee_filter)
+    }
+
+    /// The compact block version in effect for this connection, which is the lower of the versions
+    /// advertised by each side. `None` if either side has not advertised compact block support.
+    pub fn compact_block_version(&self) -> Option<u64> {
+        let theirs = self.their_preferences()?.sendcmpct.version;
+        negotiated_compact_version(self.our_send_cmpct.version, theirs)
+    }
+
+    /// The most recent messages exchanged with the peer, oldest first. Empty unless enabled with
+    /// [`handshake::ConnectionConfig::keep_message_history`].
+    pub fn recent_messages(&self) -> Vec<(Instant, CommandString, Direction)> {
+        self.message_history
+            .as_ref()
+            .and_then(|history| history.lock().ok())
+            .map(|lock| lock.messages.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The ratio of unique addresses to total addresses gossiped by the peer, or `None` if no
+    /// addresses have been received. A low ratio indicates the peer is repeating addresses.
+    pub fn gossip_dedup_ratio(&self) -> Option<f64> {
+        self.addr_gossip.lock().ok()?.dedup_ratio()
+    }
+
     /// The message rate for a time-sensitive message
     pub fn message_rate(&self, timed_message: TimedMessage) -> Option<MessageRate> {
         let lock = self.timed_messages.lock().ok()?;
         Some(*lock.message_rate(timed_message))
     }
 
+    /// The messages per second of a time-sensitive message over the most recent `window`, up to
+    /// [`RECENT_RATE_WINDOW`]. Unlike [`MessageRate::messages_per_secs`], a burst of messages early
+    /// in the connection does not affect the rate once it falls outside the window.
+    pub fn recent_message_rate(
+        &self,
+        timed_message: TimedMessage,
+        window: Duration,
+        now: Instant,
+    ) -> Option<f64> {
+        let lock = self.timed_messages.lock().ok()?;
+        lock.recent_rate(timed_message, window, now)
+    }
+
+    /// The services the peer has demonstrated by responding to a probe. See
+    /// [`FeelerData::service_probes`].
+    pub fn verified_services(&self) -> Option<ServiceFlags> {
+        let lock = self.verified_services.lock().ok()?;
+        Some(*lock)
+    }
+
+    /// The bytes received and total payload length of the message currently being read from the
+    /// peer, if any.
+    pub fn current_message_progress(&self) -> Option<(usize, usize)> {
+        self.message_progress.get()
+    }
+
     /// Time the connection has remained open.
     pub fn connection_time(&self, now: Instant) -> Duration {
         now.duration_since(self.start_time)
@@ -111,6 +449,82 @@ impl ConnectionMetrics {
         false
     }
 
+    /// The round trip time of the most recent ping that was answered, if any.
+    pub fn ping_rtt(&self) -> Option<Duration> {
+        self.ping_rtt.lock().map(|lock| *lock).unwrap_or_default()
+    }
+
+    /// Is the connection using the encrypted v2 transport.
+    pub fn is_encrypted(&self) -> bool {
+        self.transport == TransportKind::V2
+    }
+
+    /// The transport the connection settled on, including whether v1 was used only after the
+    /// peer rejected v2.
+    pub fn transport(&self) -> TransportKind {
+        self.transport
+    }
+
+    /// The session ID of a v2 connection, which is equal on both ends of a correct handshake.
+    /// Only available with the `debug-v2-session` feature, as it is derived from the shared
+    /// secret of the session. The garbage terminators are not exposed, as the BIP324 handshake
+    /// keeps them private and a matching session ID implies they match too.
+    #[cfg(feature = "debug-v2-session")]
+    pub fn v2_session_id(&self) -> Option<[u8; 32]> {
+        self.v2_session_id
+    }
+
+    /// The type of the first message the peer sent after the handshake, if any. The order in
+    /// which a peer sends messages after `verack` differs across implementations.
+    pub fn first_post_handshake_message(&self) -> Option<CommandString> {
+        self.first_message
+            .lock()
+            .map(|lock| lock.clone())
+            .unwrap_or_default()
+    }
+
+    /// Summarize the round trip time, unanswered pings, message rates and uptime of the connection
+    /// with the default thresholds.
+    pub fn quality(&self) -> ConnectionQuality {
+        self.quality_with(&QualityThresholds::default())
+    }
+
+    /// Summarize the health of the connection with custom thresholds.
+    pub fn quality_with(&self, thresholds: &QualityThresholds) -> ConnectionQuality {
+        let now = Instant::now();
+        let pending_ping = match self.outbound_ping_state.lock().as_deref() {
+            Ok(OutboundPing::Waiting { nonce: _, then }) => {
+                Some(now.saturating_duration_since(*then))
+            }
+            _ => None,
+        };
+        let recent = |timed_message| {
+            self.recent_message_rate(timed_message, RECENT_RATE_WINDOW, now)
+                .map_or(0, |rate| {
+                    (rate * RECENT_RATE_WINDOW.as_secs_f64()).round() as u64
+                })
+        };
+        thresholds.quality(
+            self.ping_rtt(),
+            pending_ping,
+            self.connection_time(now),
+            recent(TimedMessage::Addr),
+            recent(TimedMessage::TransactionAnnouncement),
+        )
+    }
+
+    /// Does the connection support the required features after negotiating the version and
+    /// exchanging preferences with the peer.
+    pub fn meets_requirements(&self, required: &RequiredFeatures) -> bool {
+        self.their_preferences().is_some_and(|preferences| {
+            required.met(
+                self.feeler.effective_version,
+                self.feeler.services,
+                &preferences,
+            )
+        })
+    }
+
     /// Has the connection failed to respond to a ping after the given duration.
     pub fn ping_timed_out(&self, timeout: Duration) -> bool {
         if let Ok(lock) = self.outbound_ping_state.lock() {
@@ -123,6 +537,213 @@ impl ConnectionMetrics {
     }
 }
 
+/// Features a connection must have negotiated to be useful, checked with
+/// [`ConnectionMetrics::meets_requirements`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequiredFeatures {
+    /// The minimum protocol version negotiated.
+    pub min_version: ProtocolVersion,
+    /// Services the peer must advertise.
+    pub services: ServiceFlags,
+    /// Transactions must be announced by WTXID.
+    pub wtxid_relay: bool,
+    /// The peer must have sent `sendaddrv2`.
+    pub addrv2: bool,
+    /// The peer must have sent `sendheaders`.
+    pub sendheaders: bool,
+    /// The minimum compact block version the peer must have announced.
+    pub compact_block_version: Option<u64>,
+}
+
+impl RequiredFeatures {
+    fn met(
+        &self,
+        effective_version: ProtocolVersion,
+        services: ServiceFlags,
+        preferences: &Preferences,
+    ) -> bool {
+        let wtxid_relay =
+            effective_version >= ProtocolVersion::WTXID_RELAY_VERSION && preferences.sendwtxid;
+        effective_version >= self.min_version
+            && services.has(self.services)
+            && (wtxid_relay || !self.wtxid_relay)
+            && (preferences.sendaddrv2 || !self.addrv2)
+            && (preferences.sendheaders || !self.sendheaders)
+            && self
+                .compact_block_version
+                .is_none_or(|version| preferences.sendcmpct.version >= version)
+    }
+}
+
+impl Default for RequiredFeatures {
+    fn default() -> Self {
+        Self {
+            min_version: ProtocolVersion::MIN_PEER_PROTO_VERSION,
+            services: ServiceFlags::NONE,
+            wtxid_relay: false,
+            addrv2: false,
+            sendheaders: false,
+            compact_block_version: None,
+        }
+    }
+}
+
+/// Points a peer accumulates for sending messages flagged by [`ValidationExt::is_malformed`] or
+/// [`ValidationExt::is_discouraged`], or messages that could not be decoded, as reported by
+/// [`ConnectionMetrics::ban_score`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BanScore {
+    malformed_points: u32,
+    discouraged_points: u32,
+    score: u32,
+}
+
+impl BanScore {
+    /// Score peers with the points added for each malformed or discouraged message.
+    pub fn new(malformed_points: u32, discouraged_points: u32) -> Self {
+        Self {
+            malformed_points,
+            discouraged_points,
+            score: 0,
+        }
+    }
+
+    /// The points accumulated so far.
+    pub fn score(&self) -> u32 {
+        self.score
+    }
+
+    /// The points added for a malformed and a discouraged message.
+    pub fn points(&self) -> (u32, u32) {
+        (self.malformed_points, self.discouraged_points)
+    }
+
+    /// Add the points for the message, if it is malformed or discouraged. A message that is both
+    /// only counts as malformed.
+    pub fn record(&mut self, message: &NetworkMessage) {
+        let points = if message.is_malformed() {
+            self.malformed_points
+        } else if message.is_discouraged() {
+            self.discouraged_points
+        } else {
+            return;
+        };
+        self.score = self.score.saturating_add(points);
+    }
+
+    /// Add the points for a malformed message when a message could not be decoded at all.
+    pub fn record_undecodable(&mut self) {
+        self.score = self.score.saturating_add(self.malformed_points);
+    }
+}
+
+impl Default for BanScore {
+    // Malformed data is as severe as Bitcoin Core's former ban threshold of 100
+    fn default() -> Self {
+        Self::new(100, 20)
+    }
+}
+
+/// A summary of the health of a connection, from best to worst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ConnectionQuality {
+    /// Fast responses over an established connection.
+    Excellent,
+    /// Responsive, or too new to be measured.
+    Good,
+    /// Slow responses, or the peer floods addresses or transaction announcements.
+    Degraded,
+    /// Very slow responses, or a ping has gone unanswered.
+    Poor,
+}
+
+/// The limits used to derive a [`ConnectionQuality`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QualityThresholds {
+    /// The highest round trip time of an excellent connection.
+    pub excellent_rtt: Duration,
+    /// The highest round trip time of a good connection.
+    pub good_rtt: Duration,
+    /// The highest round trip time of a degraded connection.
+    pub degraded_rtt: Duration,
+    /// How long a ping may go unanswered before the connection is poor.
+    pub unanswered_ping: Duration,
+    /// How long a connection must be open to be considered excellent.
+    pub established: Duration,
+    /// The most addresses the peer may gossip within the [`RECENT_RATE_WINDOW`] before the
+    /// connection is degraded.
+    pub recent_addrs: u64,
+    /// The most transactions the peer may announce within the [`RECENT_RATE_WINDOW`] before the
+    /// connection is degraded.
+    pub recent_tx_announcements: u64,
+}
+
+impl QualityThresholds {
+    fn quality(
+        &self,
+        rtt: Option<Duration>,
+        pending_ping: Option<Duration>,
+        uptime: Duration,
+        recent_addrs: u64,
+        recent_tx_announcements: u64,
+    ) -> ConnectionQuality {
+        if pending_ping.is_some_and(|pending| pending > self.unanswered_ping) {
+            return ConnectionQuality::Poor;
+        }
+        let responsiveness = match rtt {
+            None => ConnectionQuality::Good,
+            Some(rtt) if rtt <= self.excellent_rtt && uptime >= self.established => {
+                ConnectionQuality::Excellent
+            }
+            Some(rtt) if rtt <= self.good_rtt => ConnectionQuality::Good,
+            Some(rtt) if rtt <= self.degraded_rtt => ConnectionQuality::Degraded,
+            Some(_) => ConnectionQuality::Poor,
+        };
+        if recent_addrs > self.recent_addrs
+            || recent_tx_announcements > self.recent_tx_announcements
+        {
+            return responsiveness.max(ConnectionQuality::Degraded);
+        }
+        responsiveness
+    }
+}
+
+impl Default for QualityThresholds {
+    fn default() -> Self {
+        Self {
+            excellent_rtt: Duration::from_millis(200),
+            good_rtt: Duration::from_secs(1),
+            degraded_rtt: Duration::from_secs(5),
+            unanswered_ping: Duration::from_secs(20),
+            established: Duration::from_secs(10 * 60),
+            // A full response to `getaddr` along with regular gossip
+            recent_addrs: 2_000,
+            recent_tx_announcements: 6_000,
+        }
+    }
+}
+
+/// The compact block version that commits to witness data.
+pub(crate) const WITNESS_COMPACT_VERSION: u64 = 2;
+
+/// Record a `sendcmpct` announced by the peer, keeping the highest version announced. Witness
+/// nodes ignore versions other than 2, as short IDs would be computed without witness data.
+pub(crate) fn announce_compact(current: &mut SendCmpct, announced: SendCmpct, witness: bool) {
+    if witness && announced.version != WITNESS_COMPACT_VERSION {
+        return;
+    }
+    if announced.version >= current.version {
+        *current = announced;
+    }
+}
+
+fn negotiated_compact_version(ours: u64, theirs: u64) -> Option<u64> {
+    if ours == 0 || theirs == 0 {
+        return None;
+    }
+    Some(std::cmp::min(ours, theirs))
+}
+
 /// The rate at which a peer sends a particular message
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 pub enum MessageRate {
@@ -182,6 +803,44 @@ impl MessageRate {
     }
 }
 
+/// The longest window supported by [`ConnectionMetrics::recent_message_rate`].
+pub const RECENT_RATE_WINDOW: Duration = Duration::from_secs(RECENT_BUCKETS as u64);
+
+const RECENT_BUCKETS: usize = 60;
+
+// Message counts for each of the most recent seconds of a connection.
+#[derive(Debug, Clone, Copy)]
+struct RecentMessages {
+    // The second since the first message, along with the count of messages in that second
+    buckets: [(u64, u32); RECENT_BUCKETS],
+}
+
+impl Default for RecentMessages {
+    fn default() -> Self {
+        Self {
+            buckets: [(u64::MAX, 0); RECENT_BUCKETS],
+        }
+    }
+}
+
+impl RecentMessages {
+    fn add(&mut self, second: u64, num_messages: u32) {
+        let bucket = &mut self.buckets[(second % RECENT_BUCKETS as u64) as usize];
+        if bucket.0 != second {
+            *bucket = (second, 0);
+        }
+        bucket.1 = bucket.1.saturating_add(num_messages);
+    }
+
+    fn count_since(&self, now: u64, window: u64) -> u64 {
+        self.buckets
+            .iter()
+            .filter(|(second, _)| *second <= now && now - second < window)
+            .map(|(_, count)| u64::from(*count))
+            .sum()
+    }
+}
+
 /// A time-sensitive message
 #[derive(Debug, Clone, Copy, PartialEq, Eq, std::hash::Hash)]
 pub enum TimedMessage {
@@ -197,9 +856,52 @@ pub enum TimedMessage {
     TransactionAnnouncement,
 }
 
+// The overall rate of a message along with its counts over the most recent seconds.
+#[derive(Debug, Clone, Copy)]
+struct TrackedMessage {
+    rate: MessageRate,
+    recent: RecentMessages,
+}
+
+impl TrackedMessage {
+    fn new() -> Self {
+        Self {
+            rate: MessageRate::new(),
+            recent: RecentMessages::default(),
+        }
+    }
+
+    fn add_single_message(&mut self, now: Instant) {
+        self.rate.add_single_message(now);
+        self.add_recent(1, now);
+    }
+
+    fn add_messages(&mut self, num_messages: usize, now: Instant) {
+        self.rate.add_messages(num_messages, now);
+        self.add_recent(num_messages, now);
+    }
+
+    fn add_recent(&mut self, num_messages: usize, now: Instant) {
+        if let MessageRate::Ongoing { count: _, start } = self.rate {
+            let num_messages = num_messages.try_into().unwrap_or(u32::MAX);
+            self.recent
+                .add(now.duration_since(start).as_secs(), num_messages);
+        }
+    }
+
+    fn recent_rate(&self, window: Duration, now: Instant) -> Option<f64> {
+        let MessageRate::Ongoing { count: _, start } = self.rate else {
+            return None;
+        };
+        let window = window.as_secs().clamp(1, RECENT_RATE_WINDOW.as_secs());
+        let elapsed = now.duration_since(start).as_secs();
+        Some(self.recent.count_since(elapsed, window) as f64 / window as f64)
+    }
+}
+
 #[derive(Debug, Clone)]
 struct TimedMessages {
-    tracked: HashMap<TimedMessage, MessageRate>,
+    tracked: HashMap<TimedMessage, TrackedMessage>,
     last_block: Instant,
 }
 
@@ -213,7 +915,7 @@ impl TimedMessages {
             TimedMessage::Addr,
             TimedMessage::TransactionAnnouncement,
         ] {
-            map.insert(key, MessageRate::new());
+            map.insert(key, TrackedMessage::new());
         }
         Self {
             tracked: map,
@@ -238,12 +940,80 @@ impl TimedMessages {
     }
 
     fn message_rate(&self, message: TimedMessage) -> &MessageRate {
+        &self.tracked(message).rate
+    }
+
+    fn recent_rate(&self, message: TimedMessage, window: Duration, now: Instant) -> Option<f64> {
+        self.tracked(message).recent_rate(window, now)
+    }
+
+    fn tracked(&self, message: TimedMessage) -> &TrackedMessage {
         self.tracked
             .get(&message)
             .expect("all timed messages are in the map")
     }
 }
 
+/// The direction a message was sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, std::hash::Hash)]
+pub enum Direction {
+    /// The message was received from the peer.
+    Inbound,
+    /// The message was sent to the peer.
+    Outbound,
+}
+
+#[derive(Debug, Clone)]
+struct MessageHistory {
+    capacity: usize,
+    messages: VecDeque<(Instant, CommandString, Direction)>,
+}
+
+impl MessageHistory {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            messages: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    fn add(&mut self, command: CommandString, direction: Direction, now: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.messages.len() == self.capacity {
+            self.messages.pop_front();
+        }
+        self.messages.push_back((now, command, direction));
+    }
+}
+
+// Bound the memory used to remember gossiped addresses
+const MAX_TRACKED_ADDRS: usize = 50_000;
+
+#[derive(Debug, Clone, Default)]
+struct AddrGossip {
+    seen: HashMap<Vec<u8>, u32>,
+    total: u64,
+}
+
+impl AddrGossip {
+    fn add(&mut self, addr: Vec<u8>) {
+        if self.seen.len() >= MAX_TRACKED_ADDRS && !self.seen.contains_key(&addr) {
+            return;
+        }
+        *self.seen.entry(addr).or_default() += 1;
+        self.total += 1;
+    }
+
+    fn dedup_ratio(&self) -> Option<f64> {
+        if self.total == 0 {
+            return None;
+        }
+        Some(self.seen.len() as f64 / self.total as f64)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 enum OutboundPing {
     Waiting { nonce: u64, then: Instant },
@@ -254,7 +1024,238 @@ enum OutboundPing {
 mod tests {
     use std::time::{Duration, Instant};
 
-    use crate::{MessageRate, TimedMessage, TimedMessages};
+    use p2p::{
+        message::NetworkMessage, message_compact_blocks::SendCmpct, ProtocolVersion, ServiceFlags,
+    };
+
+    use crate::{
+        announce_compact, negotiated_compact_version, AddrGossip, BanScore, ConnectionQuality,
+        Direction, FeelerData, MessageHistory, MessageRate, PeerId, Preferences, QualityThresholds,
+        RequiredFeatures, ServiceWeights, TimedMessage, TimedMessages,
+    };
+
+    #[test]
+    fn test_addr_gossip() {
+        let mut gossip = AddrGossip::default();
+        assert!(gossip.dedup_ratio().is_none());
+        gossip.add(vec![1]);
+        gossip.add(vec![1]);
+        gossip.add(vec![2]);
+        gossip.add(vec![1]);
+        assert_eq!(gossip.dedup_ratio().unwrap(), 0.5);
+    }
+
+    #[test]
+    fn test_message_history() {
+        let now = Instant::now();
+        let mut history = MessageHistory::new(2);
+        history.add(NetworkMessage::Verack.command(), Direction::Inbound, now);
+        history.add(NetworkMessage::Ping(1).command(), Direction::Outbound, now);
+        history.add(NetworkMessage::Pong(1).command(), Direction::Inbound, now);
+        let commands: Vec<_> = history
+            .messages
+            .iter()
+            .map(|(_, command, direction)| (command.to_string(), *direction))
+            .collect();
+        assert_eq!(
+            commands,
+            vec![
+                ("ping".to_string(), Direction::Outbound),
+                ("pong".to_string(), Direction::Inbound)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pruned_peer() {
+        let mut feeler = FeelerData {
+            effective_version: ProtocolVersion::WTXID_RELAY_VERSION,
+            their_version: ProtocolVersion::WTXID_RELAY_VERSION,
+            services: ServiceFlags::NETWORK_LIMITED | ServiceFlags::WITNESS,
+            net_time_difference: 0,
+            reported_height: 0,
+            nonce: 42,
+        };
+        assert!(feeler.is_pruned());
+        assert!(feeler.can_serve_block(1_000, 1_000));
+        assert!(feeler.can_serve_block(713, 1_000));
+        assert!(!feeler.can_serve_block(712, 1_000));
+        assert!(!feeler.can_serve_block(1_001, 1_000));
+        feeler.services = ServiceFlags::NETWORK;
+        assert!(!feeler.is_pruned());
+        assert!(feeler.can_serve_block(0, 1_000));
+        feeler.services = ServiceFlags::NONE;
+        assert!(!feeler.can_serve_block(1_000, 1_000));
+    }
+
+    #[test]
+    fn test_service_score() {
+        let mut feeler = FeelerData {
+            effective_version: ProtocolVersion::WTXID_RELAY_VERSION,
+            their_version: ProtocolVersion::WTXID_RELAY_VERSION,
+            services: ServiceFlags::NETWORK | ServiceFlags::WITNESS,
+            net_time_difference: 0,
+            reported_height: 0,
+            nonce: 42,
+        };
+        assert_eq!(feeler.service_score(), 8);
+        let weights = ServiceWeights {
+            network: 1,
+            witness: 0,
+            compact_filters: 10,
+            p2p_v2: 0,
+        };
+        assert_eq!(feeler.service_score_with(&weights), 1);
+        feeler.services = ServiceFlags::NONE;
+        assert_eq!(feeler.service_score(), 0);
+    }
+
+    #[test]
+    fn test_connection_quality() {
+        let thresholds = QualityThresholds::default();
+        let ms = Duration::from_millis;
+        let hour = Duration::from_secs(60 * 60);
+        assert_eq!(
+            thresholds.quality(Some(ms(50)), None, hour, 0, 0),
+            ConnectionQuality::Excellent
+        );
+        assert_eq!(
+            thresholds.quality(Some(ms(50)), None, ms(10), 0, 0),
+            ConnectionQuality::Good
+        );
+        assert_eq!(
+            thresholds.quality(None, None, ms(10), 0, 0),
+            ConnectionQuality::Good
+        );
+        assert_eq!(
+            thresholds.quality(Some(ms(2_000)), None, hour, 0, 0),
+            ConnectionQuality::Degraded
+        );
+        assert_eq!(
+            thresholds.quality(Some(ms(50)), Some(hour), hour, 0, 0),
+            ConnectionQuality::Poor
+        );
+        assert_eq!(
+            thresholds.quality(Some(ms(50)), None, hour, 2_001, 0),
+            ConnectionQuality::Degraded
+        );
+        assert_eq!(
+            thresholds.quality(None, None, ms(10), 0, 6_001),
+            ConnectionQuality::Degraded
+        );
+        assert_eq!(
+            thresholds.quality(Some(ms(10_000)), None, hour, 2_001, 6_001),
+            ConnectionQuality::Poor
+        );
+    }
+
+    #[test]
+    fn test_compact_version() {
+        assert_eq!(negotiated_compact_version(2, 1), Some(1));
+        assert_eq!(negotiated_compact_version(2, 2), Some(2));
+        assert_eq!(negotiated_compact_version(0, 2), None);
+        assert_eq!(negotiated_compact_version(2, 0), None);
+    }
+
+    #[test]
+    fn test_required_features() {
+        let mut preferences = Preferences::default();
+        let required = RequiredFeatures {
+            wtxid_relay: true,
+            ..Default::default()
+        };
+        let services = ServiceFlags::WITNESS;
+        assert!(RequiredFeatures::default().met(
+            ProtocolVersion::MIN_PEER_PROTO_VERSION,
+            services,
+            &preferences
+        ));
+        assert!(!required.met(ProtocolVersion::WTXID_RELAY_VERSION, services, &preferences));
+        preferences.sendwtxid = true;
+        assert!(required.met(ProtocolVersion::WTXID_RELAY_VERSION, services, &preferences));
+        assert!(!required.met(ProtocolVersion::SENDHEADERS_VERSION, services, &preferences));
+        let required = RequiredFeatures {
+            services: ServiceFlags::NETWORK,
+            compact_block_version: Some(2),
+            ..Default::default()
+        };
+        preferences.sendcmpct.version = 2;
+        assert!(!required.met(ProtocolVersion::WTXID_RELAY_VERSION, services, &preferences));
+        assert!(required.met(
+            ProtocolVersion::WTXID_RELAY_VERSION,
+            services | ServiceFlags::NETWORK,
+            &preferences
+        ));
+    }
+
+    #[test]
+    fn test_announce_compact() {
+        let v1 = SendCmpct {
+            send_compact: true,
+            version: 1,
+        };
+        let v2 = SendCmpct {
+            send_compact: false,
+            version: 2,
+        };
+        let mut current = SendCmpct {
+            send_compact: false,
+            version: 0,
+        };
+        announce_compact(&mut current, v1, true);
+        assert_eq!(current.version, 0);
+        announce_compact(&mut current, v1, false);
+        assert_eq!(current.version, 1);
+        announce_compact(&mut current, v2, false);
+        announce_compact(&mut current, v1, false);
+        assert_eq!(current.version, 2);
+        assert!(!current.send_compact);
+    }
+
+    #[test]
+    fn test_missing_services() {
+        let feeler = FeelerData {
+            effective_version: ProtocolVersion::WTXID_RELAY_VERSION,
+            their_version: ProtocolVersion::WTXID_RELAY_VERSION,
+            services: ServiceFlags::NETWORK | ServiceFlags::WITNESS,
+            net_time_difference: 0,
+            reported_height: 0,
+            nonce: 42,
+        };
+        let required = ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS;
+        assert_eq!(
+            feeler.missing_services(required),
+            ServiceFlags::COMPACT_FILTERS
+        );
+        assert_eq!(
+            feeler.missing_services(ServiceFlags::WITNESS),
+            ServiceFlags::NONE
+        );
+    }
+
+    #[test]
+    fn test_peer_id_unique() {
+        let first = PeerId::next();
+        let second = PeerId::next();
+        assert_ne!(first, second);
+        assert!(second > first);
+    }
+
+    #[test]
+    fn test_ban_score() {
+        let mut score = BanScore::new(100, 10);
+        score.record(&NetworkMessage::Ping(1));
+        assert_eq!(score.score(), 0);
+        score.record(&NetworkMessage::MemPool);
+        score.record(&NetworkMessage::FilterClear);
+        assert_eq!(score.score(), 20);
+        score.record_undecodable();
+        assert_eq!(score.score(), 120);
+        let mut score = BanScore::new(u32::MAX, u32::MAX);
+        score.record(&NetworkMessage::MemPool);
+        score.record(&NetworkMessage::MemPool);
+        assert_eq!(score.score(), u32::MAX);
+    }
 
     #[test]
     fn test_message_rate() {
@@ -270,6 +1271,37 @@ mod tests {
         assert_eq!(rate.messages_per_secs(later).unwrap(), 2.);
     }
 
+    #[test]
+    fn test_recent_rate() {
+        let now = Instant::now();
+        let block = TimedMessage::Block;
+        let mut timed_messages = TimedMessages::new(now);
+        assert!(timed_messages
+            .recent_rate(block, Duration::from_secs(10), now)
+            .is_none());
+        timed_messages.add_many(block, 1_000, now);
+        assert_eq!(
+            timed_messages
+                .recent_rate(block, Duration::from_secs(10), now)
+                .unwrap(),
+            100.
+        );
+        let later = now.checked_add(Duration::from_secs(100)).unwrap();
+        timed_messages.add_many(block, 20, later);
+        assert_eq!(
+            timed_messages
+                .recent_rate(block, Duration::from_secs(10), later)
+                .unwrap(),
+            2.
+        );
+        assert_eq!(
+            timed_messages
+                .recent_rate(block, Duration::from_secs(600), later)
+                .unwrap(),
+            20. / 60.
+        );
+    }
+
     #[test]
     fn test_timed_messages() {
         let now = Instant::now();
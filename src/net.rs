@@ -1,47 +1,122 @@
 use std::{
+    collections::{HashMap, HashSet, VecDeque},
     fmt::Display,
-    io::{self, BufRead, BufReader, Write},
-    net::{SocketAddr, TcpListener, TcpStream},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpListener, TcpStream},
     sync::{
+        atomic::{AtomicBool, Ordering},
         mpsc::{self},
-        Arc, Mutex,
+        Arc, Mutex, Weak,
     },
     thread::JoinHandle,
     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use bip324::{Handshake, PacketReader, PacketType, PacketWriter, Role};
 use bitcoin::{
-    consensus::{self, DeserializeError},
+    bip152::{BlockTransactionsRequest, HeaderAndShortIds},
+    bip158::FilterHeader,
+    block::Header,
+    consensus::{self, encode::VarInt, Decodable, DeserializeError},
+    constants::genesis_block,
+    hashes::sha256d,
     key::rand::random,
+    network::TestnetVersion,
+    params::Params,
+    pow::Target,
+    Block, BlockHash, FeeRate, Network, OutPoint, Transaction, TxOut, Txid, Wtxid,
 };
 use p2p::{
-    message::{NetworkMessage, RawNetworkMessage, V1MessageHeader},
-    message_blockdata::Inventory,
-    Magic, NetworkExt,
+    address::AddrV2Message,
+    message::{
+        CommandString, HeadersMessage, InventoryPayload, NetworkMessage, RawNetworkMessage,
+        V1MessageHeader,
+    },
+    message_blockdata::{GetBlocksMessage, GetHeadersMessage, Inventory},
+    message_compact_blocks::{CmpctBlock, GetBlockTxn},
+    message_filter::{CFHeaders, CFilter},
+    Address, Magic, NetworkExt, ProtocolVersion, ServiceFlags,
 };
 
 use crate::{
-    handshake::{self, CompletedHandshake, ConnectionConfig},
-    ConnectionMetrics, OutboundPing, Preferences, TimedMessage, TimedMessages,
+    announce_compact,
+    dns::{self, Resolver},
+    handshake::{
+        self, CompletedHandshake, ConnectionConfig, FilterAction, InboundFilter, LocatorFn,
+        MempoolLookup, OutboundFilter, TransportPreference,
+    },
+    validation::{check_header_pow, first_disconnected_header, MAX_LOCATOR_HASHES},
+    AddrGossip, BanScore, ConnectionMetrics, Direction, FeelerData, MessageHistory, OutboundPing,
+    PeerId, Preferences, TimedMessage, TimedMessages,
 };
 
+/// Connections driven by a Tokio runtime.
+#[cfg(feature = "tokio")]
+pub mod asynchronous;
+
 /// Maximum amount of time the peer has to seed a message after idling.
 pub const READ_TIMEOUT: Duration = Duration::from_secs(60);
 /// The interval to send a new ping message.
 pub const PING_INTERVAL: Duration = Duration::from_secs(30);
 /// The initial TCP handshake timeout.
 pub const TCP_TIMEOUT: Duration = Duration::from_secs(2);
+/// Maximum amount of time for the peer to complete the version handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Maximum amount of time for the peer to send their version after the connection is opened.
+pub const INITIAL_READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// Minimum time between `getaddr` requests to the same peer, as Bitcoin Core only answers one
+/// `getaddr` per connection and caches its response for about a day.
+pub const GETADDR_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Maximum number of outpoints a peer will accept in a single `getutxos` request.
+pub const MAX_GETUTXOS_OUTPOINTS: usize = 15;
+/// Maximum number of addresses in an `addr` or `addrv2` message for its addresses to be relayed.
+/// Larger messages are usually responses to `getaddr`.
+pub const MAX_ADDR_RELAY: usize = 10;
+
+// Addresses are only relayed if they were seen within this window, and timestamps further in the
+// future are implausible.
+const ADDR_RELAY_WINDOW: u64 = 10 * 60;
+// Timestamps at or before this time, in 1973, are implausible
+const MIN_ADDR_TIME: u32 = 100_000_000;
+// Implausible timestamps are replaced by a time this long ago
+const ADDR_TIME_PENALTY: u64 = 5 * 24 * 60 * 60;
+
+/// The writer, reader and metrics of an open connection.
+pub type Connection<R = TcpStream> = (ConnectionWriter, ConnectionReader<R>, ConnectionMetrics);
 
 /// Open or begin a connection to an inbound or outbound peer.
 pub trait ConnectionExt: Send + Sync {
     /// Facilitate a version handshake on a potentially open connection. One use for this method is
-    /// to begin a handshake over an existing Socks5 proxy.
+    /// to begin a handshake over an existing Socks5 proxy. With [`TransportPreference::Auto`], an
+    /// outbound peer that rejects the v2 transport is reconnected to at the peer address of the
+    /// stream to retry over v1, so streams through a proxy should use
+    /// [`ConnectionExt::open_connection_via_proxy`] instead.
     fn handshake(
         self,
         tcp_stream: TcpStream,
         timeout_params: TimeoutParams,
     ) -> Result<(ConnectionWriter, ConnectionReader, ConnectionMetrics), Error>;
 
+    /// Facilitate a version handshake over any stream, for example a Unix socket or an in-memory
+    /// pipe. The `reader` and `writer` are the two halves of the same stream.
+    fn handshake_over<R, W>(
+        self,
+        reader: R,
+        writer: W,
+        timeout_params: TimeoutParams,
+    ) -> Result<(ConnectionWriter, ConnectionReader<R>, ConnectionMetrics), Error>
+    where
+        R: Read + StreamControl,
+        W: Write + StreamControl + Send + 'static;
+
+    /// Complete a version handshake only to learn about the peer, then close the connection. No
+    /// threads are spawned, and the peer is sent a `verack` as with any other connection.
+    fn probe(
+        self,
+        tcp_stream: TcpStream,
+        timeout_params: TimeoutParams,
+    ) -> Result<FeelerData, Error>;
+
     /// Listen for inbound connections on the specified socket address.
     fn listen(
         self,
@@ -55,6 +130,17 @@ pub trait ConnectionExt: Send + Sync {
         to: impl Into<SocketAddr>,
         timeout_params: TimeoutParams,
     ) -> Result<(ConnectionWriter, ConnectionReader, ConnectionMetrics), Error>;
+
+    /// Open an outbound connection through a Socks5 proxy, such as the one offered by Tor. The
+    /// target may be a socket address or a domain name resolved by the proxy, for example an
+    /// onion address. Credentials are a username and password, if the proxy requires them.
+    fn open_connection_via_proxy(
+        self,
+        to: impl Into<ProxyTarget>,
+        proxy: SocketAddr,
+        credentials: Option<(String, String)>,
+        timeout_params: TimeoutParams,
+    ) -> Result<(ConnectionWriter, ConnectionReader, ConnectionMetrics), Error>;
 }
 
 impl ConnectionExt for ConnectionConfig {
@@ -63,12 +149,27 @@ impl ConnectionExt for ConnectionConfig {
         to: impl Into<SocketAddr>,
         timeout_params: TimeoutParams,
     ) -> Result<(ConnectionWriter, ConnectionReader, ConnectionMetrics), Error> {
-        let tcp_stream = TcpStream::connect_timeout(&to.into(), timeout_params.tcp)?;
-        tcp_stream.set_read_timeout(timeout_params.read)?;
-        tcp_stream.set_write_timeout(timeout_params.write)?;
+        let tcp_stream = connect_stream(&self, to.into(), &timeout_params)?;
         Self::handshake(self, tcp_stream, timeout_params)
     }
 
+    fn open_connection_via_proxy(
+        self,
+        to: impl Into<ProxyTarget>,
+        proxy: SocketAddr,
+        credentials: Option<(String, String)>,
+        timeout_params: TimeoutParams,
+    ) -> Result<(ConnectionWriter, ConnectionReader, ConnectionMetrics), Error> {
+        let to = to.into();
+        let connect = |config: &ConnectionConfig| {
+            let mut tcp_stream = connect_stream(config, proxy, &timeout_params)?;
+            socks5_connect(&mut tcp_stream, &to, credentials.as_ref())?;
+            Ok(tcp_stream)
+        };
+        let tcp_stream = connect(&self)?;
+        handshake_with_fallback(self, tcp_stream, timeout_params, connect)
+    }
+
     fn listen(
         self,
         bind: impl Into<SocketAddr>,
@@ -76,415 +177,6146 @@ impl ConnectionExt for ConnectionConfig {
     ) -> Result<(ConnectionWriter, ConnectionReader, ConnectionMetrics), Error> {
         let listener = TcpListener::bind(bind.into())?;
         let (tcp_stream, _) = listener.accept()?;
-        tcp_stream.set_read_timeout(timeout_params.read)?;
-        tcp_stream.set_write_timeout(timeout_params.write)?;
-        Self::handshake(self, tcp_stream, timeout_params)
+        configure_stream(&self, &tcp_stream, &timeout_params)?;
+        Self::handshake(self.inbound(), tcp_stream, timeout_params)
     }
 
     fn handshake(
         self,
-        mut tcp_stream: TcpStream,
+        tcp_stream: TcpStream,
         timeout_params: TimeoutParams,
     ) -> Result<(ConnectionWriter, ConnectionReader, ConnectionMetrics), Error> {
-        let system_time = SystemTime::now();
-        let unix_time = system_time
-            .duration_since(UNIX_EPOCH)
-            .expect("time cannot go backwards.");
-        let nonce = random();
-        let version = self.build_our_version(unix_time, nonce);
-        let mut write_half = WriteTransport::V1(self.network().default_network_magic());
-        let mut read_half = ReadTransport::V1(self.network().default_network_magic());
-        write_half.write_message(NetworkMessage::Version(version), &mut tcp_stream)?;
-        let tcp_stream_clone = tcp_stream.try_clone()?;
-        let mut buf_reader = BufReader::new(tcp_stream_clone);
-        let (mut handshake, messages) = match read_half.read_message(&mut buf_reader)? {
-            Some(message) => self.start_handshake(unix_time, message, nonce)?,
-            None => return Err(Error::MissingVersion),
-        };
-        for message in messages {
-            write_half.write_message(message, &mut tcp_stream)?;
-        }
-        loop {
-            if let Some(message) = read_half.read_message(&mut buf_reader)? {
-                match handshake.negotiate(message)? {
-                    Some((completed_handshake, responses)) => {
-                        for response in responses {
-                            write_half.write_message(response, &mut tcp_stream)?;
-                        }
-                        let timed_messages =
-                            Arc::new(Mutex::new(TimedMessages::new(Instant::now())));
-                        let outbound_ping = Arc::new(Mutex::new(OutboundPing::LastReceived {
-                            then: Instant::now(),
-                        }));
-                        let CompletedHandshake {
-                            feeler,
-                            their_preferences,
-                        } = completed_handshake;
-                        let arc_pref = Arc::new(Mutex::new(their_preferences));
-                        let live_connection = ConnectionMetrics {
-                            feeler,
-                            their_preferences: Arc::clone(&arc_pref),
-                            timed_messages: Arc::clone(&timed_messages),
-                            start_time: Instant::now(),
-                            outbound_ping_state: Arc::clone(&outbound_ping),
-                        };
-                        let (tx, rx) = mpsc::channel();
-                        let open_writer = OpenWriter {
-                            tcp_stream,
-                            transport: write_half,
-                            receiver: rx,
-                            outbound_ping_state: Arc::clone(&outbound_ping),
-                            ping_interval: timeout_params.ping_interval,
-                        };
-                        let write_handle =
-                            std::thread::spawn(move || open_writer.maintain_connection());
-                        let writer = ConnectionWriter {
-                            sender: tx,
-                            task_handle: write_handle,
-                        };
-                        let reader = ConnectionReader {
-                            tcp_stream: buf_reader,
-                            transport: read_half,
-                            their_preferences: Arc::clone(&arc_pref),
-                            timed_messages,
-                            outbound_ping_state: Arc::clone(&outbound_ping),
-                        };
-                        return Ok((writer, reader, live_connection));
-                    }
-                    None => continue,
-                }
+        let peer_addr = tcp_stream.peer_addr();
+        handshake_with_fallback(self, tcp_stream, timeout_params, |config| {
+            connect_stream(config, peer_addr?, &timeout_params)
+        })
+    }
+
+    fn probe(
+        self,
+        mut tcp_stream: TcpStream,
+        timeout_params: TimeoutParams,
+    ) -> Result<FeelerData, Error> {
+        let reader = match tcp_stream.try_clone() {
+            Ok(reader) => reader,
+            Err(e) => {
+                let _ = tcp_stream.shutdown(std::net::Shutdown::Both);
+                return Err(Error::StreamClone(e));
             }
+        };
+        let mut buf_reader = BufReader::new(reader);
+        let completed_handshake =
+            establish_transport(&self, &mut buf_reader, &mut tcp_stream, &timeout_params).and_then(
+                |(mut write_half, mut read_half)| {
+                    negotiate_version(
+                        self,
+                        &mut buf_reader,
+                        &mut tcp_stream,
+                        &mut write_half,
+                        &mut read_half,
+                        &timeout_params,
+                    )
+                },
+            );
+        let _ = tcp_stream.shutdown(std::net::Shutdown::Both);
+        Ok(completed_handshake?.feeler)
+    }
+
+    fn handshake_over<R, W>(
+        self,
+        reader: R,
+        mut writer: W,
+        timeout_params: TimeoutParams,
+    ) -> Result<(ConnectionWriter, ConnectionReader<R>, ConnectionMetrics), Error>
+    where
+        R: Read + StreamControl,
+        W: Write + StreamControl + Send + 'static,
+    {
+        let mut buf_reader = BufReader::new(reader);
+        let (mut write_half, mut read_half) =
+            establish_transport(&self, &mut buf_reader, &mut writer, &timeout_params)?;
+        let completed_handshake = negotiate_version(
+            self.clone(),
+            &mut buf_reader,
+            &mut writer,
+            &mut write_half,
+            &mut read_half,
+            &timeout_params,
+        )?;
+        if self.trusted {
+            buf_reader.get_ref().apply_read_timeout(None)?;
+            writer.apply_write_timeout(None)?;
         }
+        let opened = OpenedConnection::new(
+            &self,
+            buf_reader,
+            writer,
+            (write_half, read_half),
+            completed_handshake,
+            &timeout_params,
+        );
+        Ok(opened.start())
     }
 }
 
-/// Configurations for ending a connection due to inactivity.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct TimeoutParams {
-    read: Option<Duration>,
-    write: Option<Duration>,
-    tcp: Duration,
-    ping_interval: Duration,
+// A connection that completed the handshake, before its writer is started.
+struct OpenedConnection<R, W: Write + StreamControl> {
+    open_writer: OpenWriter<W>,
+    sender: mpsc::Sender<WriteRequest>,
+    writer_alive: Arc<()>,
+    peer_id: PeerId,
+    effective_version: ProtocolVersion,
+    their_preferences: Arc<Mutex<Preferences>>,
+    outbound_ping_state: Arc<Mutex<OutboundPing>>,
+    ping_nonce: bool,
+    reader: ConnectionReader<R>,
+    metrics: ConnectionMetrics,
 }
 
-impl TimeoutParams {
-    /// Construct new timeout parameters
-    pub fn new() -> Self {
-        Self::default()
+impl<R, W> OpenedConnection<R, W>
+where
+    R: Read + StreamControl,
+    W: Write + StreamControl + Send + 'static,
+{
+    fn new(
+        config: &ConnectionConfig,
+        buf_reader: BufReader<R>,
+        writer: W,
+        (write_half, read_half): (WriteTransport, ReadTransport),
+        completed_handshake: CompletedHandshake,
+        timeout_params: &TimeoutParams,
+    ) -> Self {
+        let genesis_hash = genesis_block(config.network()).block_hash();
+        let decode_error_tolerance = config.decode_error_tolerance;
+        let outbound_filter = config.outbound_filter.clone();
+        let inbound_filter = config.inbound_filter.clone();
+        let buffered_writes = config.buffered_writes;
+        let max_lifetime = config.max_lifetime;
+        let trusted = config.trusted;
+        let block_relay_only = config.block_relay_only;
+        let max_addr_per_message = config.max_addr_per_message;
+        let disconnect_on_addr_flood = config.disconnect_on_addr_flood && !trusted;
+        let check_filter_headers = config.check_filter_headers;
+        let witness = config.our_services.has(ServiceFlags::WITNESS);
+        let max_unrequested_blocks = config.max_unrequested_blocks.filter(|_| !trusted);
+        let auto_getheaders = config.auto_getheaders.clone();
+        let max_header_target = config
+            .check_header_pow
+            .then(|| Params::new(config.network()).max_attainable_target);
+        let mempool_lookup = config.mempool_lookup.clone();
+        let buffer_pool = config.buffer_pool.clone();
+        let read_memory_budget = config.read_memory_budget.clone();
+        let message_history = (config.message_history > 0)
+            .then(|| Arc::new(Mutex::new(MessageHistory::new(config.message_history))));
+        let transport = match write_half {
+            WriteTransport::V1(_) => TransportKind::V1,
+            WriteTransport::V2 { .. } => TransportKind::V2,
+        };
+        #[cfg(feature = "debug-v2-session")]
+        let v2_session_id = match &read_half {
+            ReadTransport::V1(_) => None,
+            ReadTransport::V2 { session_id, .. } => Some(*session_id),
+        };
+        let timed_messages = Arc::new(Mutex::new(TimedMessages::new(Instant::now())));
+        let outbound_ping = Arc::new(Mutex::new(OutboundPing::LastReceived {
+            then: Instant::now(),
+        }));
+        let CompletedHandshake {
+            feeler,
+            their_advertised_address,
+            their_preferences,
+            our_send_cmpct,
+        } = completed_handshake;
+        let effective_version = feeler.effective_version;
+        let ping_nonce = effective_version > ProtocolVersion::BIP0031_VERSION;
+        let arc_pref = Arc::new(Mutex::new(their_preferences));
+        let verified_services = Arc::new(Mutex::new(ServiceFlags::NONE));
+        let peer_id = PeerId::next();
+        let progress = MessageProgress::default();
+        let start_time = Instant::now();
+        let addr_gossip = Arc::new(Mutex::new(AddrGossip::default()));
+        let unsolicited_pongs = Arc::new(Mutex::new(0));
+        let oversized_addrs = Arc::new(Mutex::new(0));
+        let protocol_violations = Arc::new(Mutex::new(0));
+        let bytes_by_type = Arc::new(Mutex::new(HashMap::new()));
+        let unrequested_blocks = Arc::new(Mutex::new(0));
+        let ping_rtt = Arc::new(Mutex::new(None));
+        let first_message = Arc::new(Mutex::new(None));
+        let bytes_received = Arc::new(Mutex::new(0));
+        let bytes_sent = Arc::new(Mutex::new(0));
+        let ban_score = Arc::new(Mutex::new(config.ban_score));
+        let requested_blocks = Arc::new(Mutex::new(HashSet::new()));
+        let peer_closed = Arc::new(AtomicBool::new(false));
+        let live_connection = ConnectionMetrics {
+            peer_id,
+            feeler,
+            their_advertised_address,
+            their_preferences: Arc::clone(&arc_pref),
+            timed_messages: Arc::clone(&timed_messages),
+            start_time,
+            outbound_ping_state: Arc::clone(&outbound_ping),
+            verified_services: Arc::clone(&verified_services),
+            message_progress: progress.clone(),
+            our_send_cmpct,
+            message_history: message_history.clone(),
+            addr_gossip: Arc::clone(&addr_gossip),
+            unsolicited_pongs: Arc::clone(&unsolicited_pongs),
+            oversized_addrs: Arc::clone(&oversized_addrs),
+            protocol_violations: Arc::clone(&protocol_violations),
+            bytes_by_type: Arc::clone(&bytes_by_type),
+            unrequested_blocks: Arc::clone(&unrequested_blocks),
+            ping_rtt: Arc::clone(&ping_rtt),
+            first_message: Arc::clone(&first_message),
+            bytes_received: Arc::clone(&bytes_received),
+            bytes_sent: Arc::clone(&bytes_sent),
+            ban_score: Arc::clone(&ban_score),
+            transport,
+            #[cfg(feature = "debug-v2-session")]
+            v2_session_id,
+        };
+        let (tx, rx) = mpsc::channel();
+        // The reader holds a sender to answer pings, so the writer stops with this token instead
+        let writer_alive = Arc::new(());
+        let open_writer = OpenWriter {
+            stream: BufWriter::new(writer),
+            buffered_writes,
+            transport: write_half,
+            receiver: rx,
+            outbound_ping_state: Arc::clone(&outbound_ping),
+            ping_interval: timeout_params.ping_interval,
+            ping_nonce,
+            outbound_filter,
+            start_time,
+            max_lifetime,
+            peer_closed: Arc::clone(&peer_closed),
+            message_history: message_history.clone(),
+            bytes_by_type: Arc::clone(&bytes_by_type),
+            bytes_sent,
+            requested_blocks: Arc::clone(&requested_blocks),
+            writer_alive: Arc::downgrade(&writer_alive),
+        };
+        let reader = ConnectionReader {
+            peer_id,
+            writer: tx.clone(),
+            stream: buf_reader,
+            transport: read_half,
+            their_preferences: Arc::clone(&arc_pref),
+            timed_messages,
+            outbound_ping_state: Arc::clone(&outbound_ping),
+            verified_services,
+            unsolicited_pongs,
+            oversized_addrs,
+            protocol_violations,
+            bytes_by_type,
+            unrequested_blocks,
+            ping_rtt,
+            first_message,
+            bytes_received,
+            ban_score,
+            requested_blocks,
+            max_unrequested_blocks,
+            auto_getheaders,
+            max_header_target,
+            effective_version,
+            max_addr_per_message,
+            disconnect_on_addr_flood,
+            check_filter_headers,
+            last_filter_header: None,
+            witness,
+            ping_nonce,
+            block_relay_only,
+            peer_closed: Arc::clone(&peer_closed),
+            mempool_lookup,
+            pending_compact_block: None,
+            partial_frame: PartialFrame::default(),
+            last_message_size: 0,
+            compact_block_status: None,
+            genesis_hash,
+            decode_errors_remaining: decode_error_tolerance,
+            inbound_filter,
+            progress: progress.clone(),
+            buffer_pool,
+            read_memory_budget,
+            message_history,
+            addr_gossip,
+        };
+        OpenedConnection {
+            open_writer,
+            sender: tx,
+            writer_alive,
+            peer_id,
+            effective_version,
+            their_preferences: arc_pref,
+            outbound_ping_state: outbound_ping,
+            ping_nonce,
+            reader,
+            metrics: live_connection,
+        }
     }
 
-    /// Set the time a peer has until they have must sent a message.
-    pub fn read_timeout(&mut self, timeout: Duration) {
-        self.read = Some(timeout)
+    // Spawn the writer on its own thread.
+    fn start(self) -> Connection<R> {
+        let open_writer = self.open_writer;
+        let task_handle = std::thread::spawn(move || open_writer.maintain_connection());
+        let writer = ConnectionWriter {
+            sender: self.sender,
+            _alive: self.writer_alive,
+            task_handle,
+            effective_version: self.effective_version,
+            peer_id: self.peer_id,
+            their_preferences: self.their_preferences,
+            outbound_ping_state: self.outbound_ping_state,
+            ping_nonce: self.ping_nonce,
+        };
+        (writer, self.reader, self.metrics)
     }
+}
 
-    /// Maximum amount of time it should take to write a message.
-    pub fn write_timeout(&mut self, timeout: Duration) {
-        self.write = Some(timeout)
+fn relays_transactions(message: &NetworkMessage) -> bool {
+    match message {
+        NetworkMessage::Tx(_) => true,
+        NetworkMessage::Inv(inv) => inv.0.iter().any(|inv| {
+            matches!(
+                inv,
+                Inventory::Transaction(_) | Inventory::WitnessTransaction(_) | Inventory::WTx(_)
+            )
+        }),
+        _ => false,
     }
+}
 
-    /// The initial TCP handshake timeout.
-    pub fn tcp_handshake_timeout(&mut self, timeout: Duration) {
-        self.tcp = timeout;
+// Capability messages that the negotiated version does not allow the peer to send.
+fn violates_version(message: &NetworkMessage, version: ProtocolVersion) -> bool {
+    match message {
+        NetworkMessage::WtxidRelay => version < ProtocolVersion::WTXID_RELAY_VERSION,
+        NetworkMessage::SendAddrV2 => version < ProtocolVersion::from_nonstandard(70016),
+        NetworkMessage::SendHeaders => version < ProtocolVersion::SENDHEADERS_VERSION,
+        NetworkMessage::SendCmpct(_) => version < ProtocolVersion::SHORT_IDS_BLOCKS_VERSION,
+        NetworkMessage::FeeFilter(_) => version < ProtocolVersion::FEEFILTER_VERSION,
+        _ => false,
     }
+}
 
-    /// How often is this peer pinged for activity
-    pub fn ping_interval(&mut self, every: Duration) {
-        self.ping_interval = every
-    }
+fn requests_witness(request: &Inventory) -> bool {
+    matches!(
+        request,
+        Inventory::WitnessTransaction(_) | Inventory::WTx(_) | Inventory::WitnessBlock(_)
+    )
 }
 
-impl Default for TimeoutParams {
-    fn default() -> Self {
-        Self {
-            read: Some(READ_TIMEOUT),
-            write: None,
-            tcp: TCP_TIMEOUT,
-            ping_interval: PING_INTERVAL,
-        }
+fn strip_witness(transaction: &mut Transaction) {
+    for input in transaction.input.iter_mut() {
+        input.witness.clear();
     }
 }
 
-#[derive(Debug)]
-enum WriteRequest {
-    Shutdown,
-    SendMessage(NetworkMessage),
-}
+// Bounds on the random garbage and decoy packets sent during the v2 handshake, which vary its
+// length so it cannot be fingerprinted.
+const MAX_V2_GARBAGE_LEN: usize = 4_095;
+const MAX_V2_DECOYS: usize = 2;
+const MAX_V2_DECOY_LEN: usize = 128;
+const ELLSWIFT_KEY_LEN: usize = 64;
+const GARBAGE_TERMINATOR_LEN: usize = 16;
+// The length prefix of a v2 packet
+const V2_LENGTH_LEN: usize = 3;
+// The length prefix, header byte and authentication tag of a v2 packet
+const V2_PACKET_OVERHEAD: usize = 20;
+// Bitcoin Core rejects messages larger than this
+const MAX_MESSAGE_LEN: usize = 4_000_000;
+const MAX_V2_PACKET_LEN: usize = MAX_MESSAGE_LEN + V2_PACKET_OVERHEAD;
+// The garbage and terminator a peer sends in the v2 handshake, followed by its packets up to the
+// version packet, may not take more than a packet of the largest size.
+const MAX_V2_HANDSHAKE_LEN: usize = MAX_V2_GARBAGE_LEN + GARBAGE_TERMINATOR_LEN + MAX_V2_PACKET_LEN;
 
-/// Send messages to an open connection.
-#[derive(Debug)]
-pub struct ConnectionWriter {
-    sender: mpsc::Sender<WriteRequest>,
-    task_handle: JoinHandle<Result<(), io::Error>>,
+// Messages with a one byte encoding of their command in the v2 transport, in order of their ID.
+const V2_SHORT_IDS: [&str; 28] = [
+    "addr",
+    "block",
+    "blocktxn",
+    "cmpctblock",
+    "feefilter",
+    "filteradd",
+    "filterclear",
+    "filterload",
+    "getblocks",
+    "getblocktxn",
+    "getdata",
+    "getheaders",
+    "headers",
+    "inv",
+    "mempool",
+    "merkleblock",
+    "notfound",
+    "ping",
+    "pong",
+    "sendcmpct",
+    "tx",
+    "getcfilters",
+    "cfilter",
+    "getcfheaders",
+    "cfheaders",
+    "getcfcheckpt",
+    "cfcheckpt",
+    "addrv2",
+];
+
+// The first bytes of a v1 `version` message, which are never sent by a v2 peer.
+fn v1_version_prefix(magic: Magic) -> Vec<u8> {
+    let mut prefix = consensus::serialize(&magic);
+    prefix.extend_from_slice(b"version\0\0\0\0\0");
+    prefix
 }
 
-#[allow(clippy::result_large_err)]
-impl ConnectionWriter {
-    /// Send a network message to this peer. Errors indicate that the connection is terminated and
-    /// no further messages will succeed.
-    pub fn send_message(&self, network_message: NetworkMessage) -> Result<(), Error> {
-        self.sender
-            .send(WriteRequest::SendMessage(network_message))
-            .map_err(|_| Error::ChannelClosed)
-    }
+// How often the first bytes of an inbound connection are checked while they arrive
+const PEEK_INTERVAL: Duration = Duration::from_millis(10);
 
-    /// Kill both sides of the connection, erroring if the stream is already closed.
-    pub fn shutdown(&self) -> Result<(), Error> {
-        self.sender
-            .send(WriteRequest::Shutdown)
-            .map_err(|_| Error::ChannelClosed)
+// Like Bitcoin Core, wait for the magic and `version` command before deciding the peer uses v1,
+// unless the bytes received so far differ. Streams that cannot be peeked compare the bytes
+// already received, as a v2 key matching the network magic by chance is unlikely.
+fn sends_v1_version<R: Read + StreamControl>(
+    buf_reader: &mut BufReader<R>,
+    magic: Magic,
+) -> Result<bool, Error> {
+    let prefix = v1_version_prefix(magic);
+    let mut peeked = vec![0; prefix.len()];
+    let timeout = buf_reader.get_ref().current_read_timeout()?;
+    let start = Instant::now();
+    while buf_reader.buffer().is_empty() {
+        let Some(len) = buf_reader.get_ref().peek(&mut peeked)? else {
+            break;
+        };
+        if len == 0 {
+            return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+        if peeked[..len] != prefix[..len] {
+            return Ok(false);
+        }
+        if len == prefix.len() {
+            return Ok(true);
+        }
+        if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+            return Err(Error::Io(io::ErrorKind::TimedOut.into()));
+        }
+        std::thread::sleep(PEEK_INTERVAL);
     }
+    let received = buf_reader.fill_buf()?;
+    let len = received.len().min(prefix.len());
+    Ok(len > 0 && received[..len] == prefix[..len])
+}
 
-    /// In the event of a failed message, investigate IO related failures if the connection was not
-    /// closed gracefully.
-    pub fn take_errors(self) -> Option<io::Error> {
-        self.task_handle.join().ok()?.err()
+// Fill the buffer from the stream, failing if nothing arrives before the deadline.
+fn read_exact_before<R: Read + StreamControl>(
+    buf_reader: &mut BufReader<R>,
+    buf: &mut [u8],
+    deadline: Instant,
+) -> Result<(), Error> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() || !wait_readable(buf_reader, remaining).map_err(Error::V2Handshake)? {
+        return Err(Error::V2Handshake(io::ErrorKind::TimedOut.into()));
     }
+    buf_reader.read_exact(buf).map_err(Error::V2Handshake)
 }
 
-#[derive(Debug)]
-struct OpenWriter {
-    tcp_stream: TcpStream,
-    transport: WriteTransport,
-    receiver: mpsc::Receiver<WriteRequest>,
-    outbound_ping_state: Arc<Mutex<OutboundPing>>,
-    ping_interval: Duration,
+// Choose the transport according to the configuration, completing the v2 handshake if it is used.
+fn establish_transport<R, W>(
+    config: &ConnectionConfig,
+    buf_reader: &mut BufReader<R>,
+    writer: &mut W,
+    timeout_params: &TimeoutParams,
+) -> Result<(WriteTransport, ReadTransport), Error>
+where
+    R: Read + StreamControl,
+    W: Write,
+{
+    let magic = config.network().default_network_magic();
+    if config.transport == TransportPreference::V1Only {
+        return Ok((WriteTransport::V1(magic), ReadTransport::V1(magic)));
+    }
+    // The peer speaks first, so a v1 peer is recognized by its version message
+    if config.inbound && sends_v1_version(buf_reader, magic)? {
+        if config.transport == TransportPreference::V2Only {
+            return Err(Error::UnsupportedTransport);
+        }
+        return Ok((WriteTransport::V1(magic), ReadTransport::V1(magic)));
+    }
+    let role = if config.inbound {
+        Role::Responder
+    } else {
+        Role::Initiator
+    };
+    let deadline = Instant::now() + timeout_params.handshake;
+    v2_handshake(config.network(), role, buf_reader, writer, deadline)
 }
 
-impl OpenWriter {
-    fn maintain_connection(mut self) -> Result<(), std::io::Error> {
-        loop {
-            let message = self.receiver.recv_timeout(Duration::from_secs(1));
-            match message {
-                Ok(request) => match request {
-                    WriteRequest::SendMessage(message) => self
-                        .transport
-                        .write_message(message, &mut self.tcp_stream)?,
-                    WriteRequest::Shutdown => {
-                        self.tcp_stream.shutdown(std::net::Shutdown::Both)?;
-                    }
-                },
-                Err(e) => match e {
-                    mpsc::RecvTimeoutError::Timeout => (),
-                    _ => return Ok(()),
-                },
+// Exchange keys, garbage and version packets with the peer as described in BIP324, failing if the
+// peer does not complete its side before the deadline.
+fn v2_handshake<R, W>(
+    network: Network,
+    role: Role,
+    buf_reader: &mut BufReader<R>,
+    writer: &mut W,
+    deadline: Instant,
+) -> Result<(WriteTransport, ReadTransport), Error>
+where
+    R: Read + StreamControl,
+    W: Write,
+{
+    let magic = network.default_network_magic();
+    let garbage: Vec<u8> = (0..random::<usize>() % (MAX_V2_GARBAGE_LEN + 1))
+        .map(|_| random())
+        .collect();
+    let mut key_buffer = vec![0; ELLSWIFT_KEY_LEN + garbage.len()];
+    let mut handshake = Handshake::new(network, role, Some(garbage.as_slice()), &mut key_buffer)
+        .map_err(Error::V2Protocol)?;
+    writer
+        .write_all(&key_buffer)
+        .and_then(|_| writer.flush())
+        .map_err(Error::V2Handshake)?;
+    let mut their_key = [0; ELLSWIFT_KEY_LEN];
+    read_exact_before(buf_reader, &mut their_key, deadline)?;
+    if their_key.starts_with(&v1_version_prefix(magic)) {
+        return Err(Error::V2Handshake(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "peer sent a v1 version message",
+        )));
+    }
+    let decoys: Vec<Vec<u8>> = (0..random::<usize>() % (MAX_V2_DECOYS + 1))
+        .map(|_| vec![0; random::<usize>() % MAX_V2_DECOY_LEN])
+        .collect();
+    let decoys: Vec<&[u8]> = decoys.iter().map(Vec::as_slice).collect();
+    let response_len = GARBAGE_TERMINATOR_LEN
+        + V2_PACKET_OVERHEAD
+        + decoys
+            .iter()
+            .map(|decoy| decoy.len() + V2_PACKET_OVERHEAD)
+            .sum::<usize>();
+    let mut response = vec![0; response_len];
+    handshake
+        .complete_materials(their_key, &mut response, Some(decoys.as_slice()))
+        .map_err(Error::V2Protocol)?;
+    writer
+        .write_all(&response)
+        .and_then(|_| writer.flush())
+        .map_err(Error::V2Handshake)?;
+    // The garbage is read a byte at a time until the terminator and the length of the packet that
+    // follows it are found, after which each packet is read whole. Nothing following the version
+    // packet is consumed.
+    let mut received = Vec::new();
+    let mut wanted = 1;
+    // Where the last packet read whole ended, if any
+    let mut packet_end = None;
+    loop {
+        if wanted > MAX_V2_HANDSHAKE_LEN {
+            return Err(Error::OversizedMessage(wanted));
+        }
+        let start = received.len();
+        received.resize(wanted, 0);
+        read_exact_before(buf_reader, &mut received[start..], deadline)?;
+        match handshake.authenticate_garbage_and_version(&received) {
+            Ok(()) => break,
+            // The buffer must grow to the end of the packet whose length was decrypted
+            Err(bip324::Error::BufferTooSmall { required_bytes }) => {
+                wanted = required_bytes.max(received.len() + 1);
+                packet_end = Some(wanted);
             }
-            if let Ok(mut ping) = self.outbound_ping_state.lock() {
-                match *ping {
-                    OutboundPing::LastReceived { then } => {
-                        if then.elapsed() > self.ping_interval {
-                            let nonce: u64 = random();
-                            self.transport
-                                .write_message(NetworkMessage::Ping(nonce), &mut self.tcp_stream)?;
-
-                            *ping = OutboundPing::Waiting {
-                                nonce,
-                                then: Instant::now(),
-                            }
-                        }
-                    }
-                    OutboundPing::Waiting { nonce: _, then: _ } => continue,
+            Err(bip324::Error::CiphertextTooSmall) => {
+                let limit = packet_end.unwrap_or(MAX_V2_GARBAGE_LEN + GARBAGE_TERMINATOR_LEN);
+                if received.len() >= limit + V2_LENGTH_LEN {
+                    return Err(Error::V2Handshake(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "malformed v2 handshake",
+                    )));
                 }
+                wanted = received.len() + 1;
             }
-            // Do traffic shaping or gossip addrs
+            Err(e) => return Err(Error::V2Protocol(e)),
         }
     }
+    let session = handshake.finalize().map_err(Error::V2Protocol)?;
+    #[cfg(feature = "debug-v2-session")]
+    let session_id = session.id().to_owned();
+    let (reader, writer) = session.into_split();
+    Ok((
+        WriteTransport::V2 {
+            magic,
+            cipher: writer,
+        },
+        ReadTransport::V2 {
+            magic,
+            cipher: reader,
+            pending: Vec::new(),
+            #[cfg(feature = "debug-v2-session")]
+            session_id,
+        },
+    ))
 }
 
-/// Read messages from an open connection.
-#[derive(Debug)]
-pub struct ConnectionReader {
-    tcp_stream: BufReader<TcpStream>,
-    transport: ReadTransport,
-    their_preferences: Arc<Mutex<Preferences>>,
-    timed_messages: Arc<Mutex<TimedMessages>>,
-    outbound_ping_state: Arc<Mutex<OutboundPing>>,
+// The contents of a v2 packet are the command, as a short ID if it has one, followed by the
+// payload of the message.
+fn encode_v2_contents(magic: Magic, network_message: NetworkMessage) -> Vec<u8> {
+    let command = network_message.command().to_string();
+    let raw = consensus::serialize(&RawNetworkMessage::new(magic, network_message));
+    let mut contents = match V2_SHORT_IDS.iter().position(|id| *id == command) {
+        Some(index) => vec![index as u8 + 1],
+        None => {
+            let mut contents = vec![0];
+            contents.extend_from_slice(&raw[4..16]);
+            contents
+        }
+    };
+    contents.extend_from_slice(&raw[24..]);
+    contents
 }
 
-impl ConnectionReader {
-    /// Wait for a message while blocking the current thread of execution.
-    pub fn read_message(&mut self) -> Result<Option<NetworkMessage>, Error> {
-        let message = self.transport.read_message(&mut self.tcp_stream)?;
-        if let Some(message) = &message {
-            match message {
-                NetworkMessage::SendHeaders => {
-                    if let Ok(mut lock) = self.their_preferences.lock() {
-                        lock.sendheaders = true;
-                    }
-                }
-                NetworkMessage::SendCmpct(cmpct) => {
-                    if let Ok(mut lock) = self.their_preferences.lock() {
-                        lock.sendcmpct = *cmpct;
-                    }
-                }
-                NetworkMessage::Block(_) => {
-                    if let Ok(mut lock) = self.timed_messages.lock() {
-                        lock.add_single(TimedMessage::Block, Instant::now());
-                    }
-                }
-                NetworkMessage::Headers(_) => {
-                    if let Ok(mut lock) = self.timed_messages.lock() {
-                        lock.add_single(TimedMessage::BlockHeaders, Instant::now());
-                        lock.last_block = Instant::now();
-                    }
-                }
-                NetworkMessage::CFilter(_) => {
-                    if let Ok(mut lock) = self.timed_messages.lock() {
-                        lock.add_single(TimedMessage::CFilters, Instant::now());
-                    }
-                }
-                NetworkMessage::Addr(list) => {
-                    if let Ok(mut lock) = self.timed_messages.lock() {
-                        lock.add_many(TimedMessage::Addr, list.0.len(), Instant::now());
-                    }
-                }
-                NetworkMessage::AddrV2(list) => {
-                    if let Ok(mut lock) = self.timed_messages.lock() {
-                        lock.add_many(TimedMessage::Addr, list.0.len(), Instant::now());
-                    }
-                }
-                NetworkMessage::Pong(pong) => {
-                    // There are bigger problems with this connection if the lock fails, so it is
-                    // okay to ignore the nonce.
-                    if let Ok(mut lock) = self.outbound_ping_state.lock() {
-                        if let OutboundPing::Waiting { nonce, then: _ } = *lock {
-                            if *pong == nonce {
-                                *lock = OutboundPing::LastReceived {
-                                    then: Instant::now(),
-                                };
-                            }
-                        }
-                    }
-                }
-                NetworkMessage::Inv(payload) => {
-                    let payload = &payload.0;
-                    let now = Instant::now();
-                    if let Ok(mut lock) = self.timed_messages.lock() {
-                        for inv in payload {
-                            if matches!(
-                                inv,
-                                Inventory::WTx(_)
-                                    | Inventory::WitnessTransaction(_)
-                                    | Inventory::Transaction(_)
-                            ) {
-                                lock.add_single(TimedMessage::TransactionAnnouncement, now);
-                            }
-                            if matches!(
-                                inv,
-                                Inventory::Block(_)
-                                    | Inventory::WitnessBlock(_)
-                                    | Inventory::CompactBlock(_)
-                            ) {
-                                lock.last_block = now;
-                            }
+// Build the v1 frame of the message in a v2 packet, so both transports are decoded alike.
+fn v2_contents_to_frame(magic: Magic, contents: &[u8]) -> Result<Vec<u8>, Error> {
+    let invalid = || {
+        Error::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid v2 message",
+        ))
+    };
+    let (command, payload) = match contents.split_first() {
+        Some((0, rest)) if rest.len() >= 12 => (rest[..12].to_vec(), &rest[12..]),
+        Some((0, _)) | None => return Err(invalid()),
+        // Unknown short IDs are treated as an unknown message, as in Bitcoin Core
+        Some((id, rest)) => {
+            let mut command = V2_SHORT_IDS
+                .get(*id as usize - 1)
+                .map(|name| name.as_bytes().to_vec())
+                .unwrap_or_default();
+            command.resize(12, 0);
+            (command, rest)
+        }
+    };
+    let mut frame = consensus::serialize(&magic);
+    frame.extend_from_slice(&command);
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&message_checksum(payload));
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+// Exchange version messages and complete the handshake, writing the final responses to the peer.
+fn negotiate_version<R, W>(
+    config: ConnectionConfig,
+    buf_reader: &mut BufReader<R>,
+    writer: &mut W,
+    write_half: &mut WriteTransport,
+    read_half: &mut ReadTransport,
+    timeout_params: &TimeoutParams,
+) -> Result<CompletedHandshake, Error>
+where
+    R: Read + StreamControl,
+    W: Write + StreamControl,
+{
+    let unix_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| Error::SystemTime)?;
+    let nonce = random();
+    let handshake_jitter = config.handshake_jitter;
+    let version = config.build_our_version(unix_time, nonce, writer.remote_addr());
+    write_half.write_message(NetworkMessage::Version(version), writer)?;
+    if !wait_readable(buf_reader, timeout_params.initial_read)? {
+        let _ = writer.close();
+        return Err(Error::NoInitialVersion);
+    }
+    let first_message = read_half.read_message(buf_reader)?;
+    let inbound = config.inbound;
+    let (mut handshake, messages) = match first_message {
+        Some(message) => config.start_handshake(unix_time, message, nonce)?,
+        None => return Err(Error::MissingVersion),
+    };
+    for message in version_responses(inbound, messages) {
+        write_half.write_message(message, writer)?;
+    }
+    let verack_deadline = Instant::now() + timeout_params.handshake;
+    loop {
+        let remaining = verack_deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || !wait_readable(buf_reader, remaining)? {
+            return Err(Error::MissingVerack);
+        }
+        if let Some(message) = read_half.read_message(buf_reader)? {
+            match handshake.negotiate(message)? {
+                Some((completed_handshake, responses)) => {
+                    std::thread::sleep(jitter_delay(handshake_jitter));
+                    for response in verack_responses(inbound, responses) {
+                        if let Err(e) = write_half.write_message(response, writer) {
+                            let _ = writer.close();
+                            return Err(Error::HandshakeIncompleteResponse(e));
                         }
                     }
+                    return Ok(completed_handshake);
                 }
-                _ => (),
+                None => continue,
             }
         }
-        Ok(message)
     }
 }
 
-#[derive(Debug)]
-enum WriteTransport {
-    V1(Magic),
+// The random delay before the messages that follow the verack of the peer.
+fn jitter_delay(max: Option<Duration>) -> Duration {
+    let Some(max) = max else {
+        return Duration::ZERO;
+    };
+    let max_nanos = max.as_nanos().min(u64::MAX as u128) as u64;
+    Duration::from_nanos(random::<u64>().checked_rem(max_nanos).unwrap_or(0))
 }
 
-impl WriteTransport {
-    fn write_message<W: Write>(
-        &mut self,
-        network_message: NetworkMessage,
-        writer: &mut W,
-    ) -> Result<(), io::Error> {
-        match self {
-            WriteTransport::V1(magic) => {
-                let raw = RawNetworkMessage::new(*magic, network_message);
-                let bytes = consensus::serialize(&raw);
-                writer.write_all(&bytes)?;
-                writer.flush()?;
-                Ok(())
-            }
-        }
+// An inbound connection acknowledges the version as soon as it is accepted, as Bitcoin Core does,
+// so two connections from this crate do not each wait for the verack of the other. The verack
+// follows the messages that must be sent before it.
+fn version_responses(inbound: bool, messages: Vec<NetworkMessage>) -> Vec<NetworkMessage> {
+    if !inbound {
+        return messages;
     }
+    let (before, after): (Vec<_>, Vec<_>) = messages.into_iter().partition(|message| {
+        matches!(
+            message,
+            NetworkMessage::WtxidRelay | NetworkMessage::SendAddrV2
+        )
+    });
+    before
+        .into_iter()
+        .chain([NetworkMessage::Verack])
+        .chain(after)
+        .collect()
 }
 
-#[derive(Debug)]
-enum ReadTransport {
-    V1(Magic),
+// The responses once the handshake completes, without the verack if it was already sent.
+fn verack_responses(
+    inbound: bool,
+    responses: Vec<NetworkMessage>,
+) -> impl Iterator<Item = NetworkMessage> {
+    responses
+        .into_iter()
+        .filter(move |message| !inbound || !matches!(message, NetworkMessage::Verack))
 }
 
-impl ReadTransport {
-    fn read_message<R: BufRead>(
-        &mut self,
-        reader: &mut R,
-    ) -> Result<Option<NetworkMessage>, Error> {
-        match self {
-            ReadTransport::V1(magic) => {
-                let mut message_buf = vec![0; 24];
-                reader.read_exact(&mut message_buf)?;
-                let message_header = consensus::deserialize::<V1MessageHeader>(&message_buf)?;
-                if message_header.magic != *magic {
-                    return Err(Error::UnexpectedMagic(message_header.magic));
-                }
-                // Will panic on machines with under 32 bit precision
-                let mut contents_buf = vec![0; message_header.length as usize];
-                reader.read_exact(&mut contents_buf)?;
-                message_buf.extend_from_slice(&contents_buf);
-                let message = consensus::deserialize::<RawNetworkMessage>(&message_buf)?;
-                Ok(Some(message.into_payload()))
-            }
+// Wait at most the timeout for bytes to arrive without consuming them, returning `false` if none
+// did. The message is then read with the usual read timeout, so a deadline never interrupts a
+// message that is partially read and the stream is not left out of sync.
+fn wait_readable<R: Read + StreamControl>(
+    buf_reader: &mut BufReader<R>,
+    timeout: Duration,
+) -> Result<bool, io::Error> {
+    if !buf_reader.buffer().is_empty() {
+        return Ok(true);
+    }
+    let read_timeout = buf_reader.get_ref().current_read_timeout()?;
+    // A zero timeout is rejected by the socket
+    let timeout = timeout.max(Duration::from_millis(1));
+    buf_reader.get_ref().apply_read_timeout(Some(timeout))?;
+    let available = buf_reader.fill_buf().map(|buf| buf.len());
+    buf_reader.get_ref().apply_read_timeout(read_timeout)?;
+    match available {
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            Ok(false)
         }
+        Err(e) => Err(e),
+        // The end of the stream is reported by the read that follows
+        Ok(_) => Ok(true),
     }
 }
 
-/// Errors that occur during a connection.
-#[derive(Debug)]
-pub enum Error {
-    /// A message was not deserialized according to protocol specifications.
-    Deserialize(DeserializeError),
-    /// An IO related error occurred.
-    Io(io::Error),
-    /// An error occurred during the version handshake.
-    Handshake(handshake::Error),
-    /// The peer sent magic that does not belong to the current network.
-    UnexpectedMagic(Magic),
-    /// The peer did not send a version message.
-    MissingVersion,
-    /// The channel to the message writing thread was closed.
-    ChannelClosed,
-}
+/// Control a stream beyond reading and writing. TCP streams support timeouts and closing the
+/// connection early, while other streams may rely on the default methods, which do nothing.
+pub trait StreamControl {
+    /// Close the stream in both directions, unblocking any pending reads.
+    fn close(&self) -> Result<(), io::Error> {
+        Ok(())
+    }
 
-impl Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Error::Deserialize(d) => d.fmt(f),
-            Error::Io(e) => e.fmt(f),
-            Error::Handshake(e) => e.fmt(f),
-            Error::UnexpectedMagic(magic) => write!(f, "unexpected network magic: {magic}"),
-            Error::MissingVersion => write!(f, "missing version message."),
-            Error::ChannelClosed => write!(f, "channel closed"),
-        }
+    /// Set the maximum time a read may block.
+    fn apply_read_timeout(&self, _timeout: Option<Duration>) -> Result<(), io::Error> {
+        Ok(())
     }
-}
 
-impl std::error::Error for Error {}
+    /// The maximum time a read may block.
+    fn current_read_timeout(&self) -> Result<Option<Duration>, io::Error> {
+        Ok(None)
+    }
 
-impl From<DeserializeError> for Error {
-    fn from(value: DeserializeError) -> Self {
-        Self::Deserialize(value)
+    /// Set the maximum time a write may block.
+    fn apply_write_timeout(&self, _timeout: Option<Duration>) -> Result<(), io::Error> {
+        Ok(())
     }
-}
 
-impl From<io::Error> for Error {
-    fn from(value: io::Error) -> Self {
-        Self::Io(value)
+    /// The address of the remote end of the stream, if any.
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Copy the bytes ready to be read into `buf` without consuming them, waiting for at least
+    /// one byte. Returns `None` if the stream cannot be peeked.
+    fn peek(&self, _buf: &mut [u8]) -> Result<Option<usize>, io::Error> {
+        Ok(None)
+    }
+
+    /// Set whether reads return `WouldBlock` instead of waiting for bytes to arrive. Required by
+    /// [`ConnectionReader::try_read_message`].
+    fn set_nonblocking(&self, _nonblocking: bool) -> Result<(), io::Error> {
+        Err(io::ErrorKind::Unsupported.into())
     }
 }
 
-impl From<handshake::Error> for Error {
-    fn from(value: handshake::Error) -> Self {
-        Self::Handshake(value)
+impl StreamControl for TcpStream {
+    fn close(&self) -> Result<(), io::Error> {
+        self.shutdown(std::net::Shutdown::Both)
+    }
+
+    fn apply_read_timeout(&self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        self.set_read_timeout(timeout)
+    }
+
+    fn current_read_timeout(&self) -> Result<Option<Duration>, io::Error> {
+        self.read_timeout()
+    }
+
+    fn apply_write_timeout(&self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        self.set_write_timeout(timeout)
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr().ok()
+    }
+
+    fn peek(&self, buf: &mut [u8]) -> Result<Option<usize>, io::Error> {
+        TcpStream::peek(self, buf).map(Some)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> Result<(), io::Error> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+// How often a blocked in-memory read checks if the stream was closed
+const MEMORY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The reading half of an in-memory stream created by [`loopback`].
+#[derive(Debug)]
+pub struct MemoryReader {
+    incoming: mpsc::Receiver<Vec<u8>>,
+    pending: Vec<u8>,
+    position: usize,
+    // Bytes received by a peek, which are read after the pending bytes
+    peeked: Mutex<Vec<u8>>,
+    read_timeout: Mutex<Option<Duration>>,
+    nonblocking: AtomicBool,
+    closed: Arc<AtomicBool>,
+    // Closed by this end, so nothing further is read
+    shut: AtomicBool,
+}
+
+impl MemoryReader {
+    // Wait for the next bytes written by the other end, or `None` at the end of the stream.
+    fn next_chunk(&self) -> io::Result<Option<Vec<u8>>> {
+        let start = Instant::now();
+        loop {
+            // As with a TCP stream, bytes written before the stream was closed are still read
+            if self.closed.load(Ordering::Relaxed) {
+                return Ok(self.incoming.try_recv().ok());
+            }
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return match self.incoming.try_recv() {
+                    Ok(bytes) => Ok(Some(bytes)),
+                    Err(mpsc::TryRecvError::Empty) => Err(io::ErrorKind::WouldBlock.into()),
+                    Err(mpsc::TryRecvError::Disconnected) => Ok(None),
+                };
+            }
+            let timeout = self.current_read_timeout()?;
+            if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            let wait = timeout.map_or(MEMORY_POLL_INTERVAL, |timeout| {
+                MEMORY_POLL_INTERVAL.min(timeout.saturating_sub(start.elapsed()))
+            });
+            match self.incoming.recv_timeout(wait) {
+                Ok(bytes) => return Ok(Some(bytes)),
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(None),
+            }
+        }
+    }
+}
+
+impl Read for MemoryReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.shut.load(Ordering::Relaxed) {
+            return Ok(0);
+        }
+        while self.position == self.pending.len() {
+            let peeked = self
+                .peeked
+                .lock()
+                .map(|mut lock| std::mem::take(&mut *lock))
+                .unwrap_or_default();
+            self.pending = match peeked.is_empty() {
+                true => match self.next_chunk()? {
+                    Some(bytes) => bytes,
+                    None => return Ok(0),
+                },
+                false => peeked,
+            };
+            self.position = 0;
+        }
+        let len = buf.len().min(self.pending.len() - self.position);
+        buf[..len].copy_from_slice(&self.pending[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+impl StreamControl for MemoryReader {
+    fn close(&self) -> Result<(), io::Error> {
+        self.shut.store(true, Ordering::Relaxed);
+        self.closed.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn apply_read_timeout(&self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        if let Ok(mut lock) = self.read_timeout.lock() {
+            *lock = timeout;
+        }
+        Ok(())
+    }
+
+    fn current_read_timeout(&self) -> Result<Option<Duration>, io::Error> {
+        Ok(self
+            .read_timeout
+            .lock()
+            .map(|lock| *lock)
+            .unwrap_or_default())
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> Result<(), io::Error> {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn peek(&self, buf: &mut [u8]) -> Result<Option<usize>, io::Error> {
+        if self.shut.load(Ordering::Relaxed) {
+            return Ok(Some(0));
+        }
+        let mut peeked = self
+            .peeked
+            .lock()
+            .map_err(|_| io::Error::from(io::ErrorKind::Other))?;
+        let pending = &self.pending[self.position..];
+        while pending.is_empty() && peeked.is_empty() {
+            match self.next_chunk()? {
+                Some(bytes) => peeked.extend_from_slice(&bytes),
+                None => return Ok(Some(0)),
+            }
+        }
+        while let Ok(bytes) = self.incoming.try_recv() {
+            peeked.extend_from_slice(&bytes);
+        }
+        let available = pending.iter().chain(peeked.iter());
+        let len = buf.len().min(pending.len() + peeked.len());
+        buf.iter_mut()
+            .zip(available)
+            .for_each(|(slot, byte)| *slot = *byte);
+        Ok(Some(len))
+    }
+}
+
+/// The writing half of an in-memory stream created by [`loopback`].
+#[derive(Debug)]
+pub struct MemoryWriter {
+    outgoing: mpsc::Sender<Vec<u8>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        self.outgoing
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl StreamControl for MemoryWriter {
+    fn close(&self) -> Result<(), io::Error> {
+        self.closed.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+fn memory_stream(
+    closed: &Arc<AtomicBool>,
+) -> ((MemoryReader, MemoryWriter), (MemoryReader, MemoryWriter)) {
+    let (ours_tx, theirs_rx) = mpsc::channel();
+    let (theirs_tx, ours_rx) = mpsc::channel();
+    let end = |incoming, outgoing| {
+        let reader = MemoryReader {
+            incoming,
+            pending: Vec::new(),
+            position: 0,
+            peeked: Mutex::new(Vec::new()),
+            read_timeout: Mutex::new(None),
+            nonblocking: AtomicBool::new(false),
+            closed: Arc::clone(closed),
+            shut: AtomicBool::new(false),
+        };
+        let writer = MemoryWriter {
+            outgoing,
+            closed: Arc::clone(closed),
+        };
+        (reader, writer)
+    };
+    (end(ours_rx, ours_tx), end(theirs_rx, theirs_tx))
+}
+
+/// Connect two configurations to each other over an in-memory stream, completing a handshake
+/// between them. Useful to test that messages sent by one side are received by the other.
+#[allow(clippy::result_large_err)]
+pub fn loopback(
+    ours: ConnectionConfig,
+    theirs: ConnectionConfig,
+    timeout_params: TimeoutParams,
+) -> Result<(Connection<MemoryReader>, Connection<MemoryReader>), Error> {
+    let closed = Arc::new(AtomicBool::new(false));
+    let ((our_reader, our_writer), (their_reader, their_writer)) = memory_stream(&closed);
+    let theirs = theirs.inbound();
+    let their_handshake = std::thread::spawn(move || {
+        theirs.handshake_over(their_reader, their_writer, timeout_params)
+    });
+    let our_connection = ours.handshake_over(our_reader, our_writer, timeout_params);
+    if our_connection.is_err() {
+        // Unblock the other side
+        closed.store(true, Ordering::Relaxed);
+    }
+    let their_connection = their_handshake
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+    Ok((our_connection?, their_connection?))
+}
+
+fn handshake_stream(
+    config: ConnectionConfig,
+    tcp_stream: TcpStream,
+    timeout_params: TimeoutParams,
+) -> Result<(ConnectionWriter, ConnectionReader, ConnectionMetrics), Error> {
+    let reader = match tcp_stream.try_clone() {
+        Ok(reader) => reader,
+        Err(e) => {
+            let _ = tcp_stream.shutdown(std::net::Shutdown::Both);
+            return Err(Error::StreamClone(e));
+        }
+    };
+    config.handshake_over(reader, tcp_stream, timeout_params)
+}
+
+// Complete the handshake over the stream. With an automatic transport preference, an outbound
+// peer that only speaks v1 closes the connection after receiving our key, and the stream cannot
+// be reused, so v1 is attempted over a new connection opened with `reconnect`.
+fn handshake_with_fallback(
+    config: ConnectionConfig,
+    tcp_stream: TcpStream,
+    timeout_params: TimeoutParams,
+    reconnect: impl FnOnce(&ConnectionConfig) -> Result<TcpStream, Error>,
+) -> Result<(ConnectionWriter, ConnectionReader, ConnectionMetrics), Error> {
+    if config.transport != TransportPreference::Auto || config.inbound {
+        return handshake_stream(config, tcp_stream, timeout_params);
+    }
+    match handshake_stream(config.clone(), tcp_stream, timeout_params) {
+        Err(Error::V2Handshake(_)) => {
+            let config = config.transport(TransportPreference::V1Only);
+            let tcp_stream = reconnect(&config)?;
+            let (writer, reader, mut metrics) =
+                handshake_stream(config, tcp_stream, timeout_params)?;
+            metrics.transport = TransportKind::V1Fallback;
+            Ok((writer, reader, metrics))
+        }
+        result => result,
+    }
+}
+
+/// The transport a connection settled on during the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransportKind {
+    /// The unencrypted v1 transport.
+    V1,
+    /// The encrypted v2 transport described in BIP324.
+    V2,
+    /// The v1 transport, after the peer rejected the v2 transport and the connection was
+    /// reopened. See [`TransportPreference::Auto`].
+    V1Fallback,
+}
+
+/// The destination of a connection opened through a Socks5 proxy.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ProxyTarget {
+    /// A socket address.
+    Addr(SocketAddr),
+    /// A domain name and port, resolved by the proxy.
+    Domain(String, u16),
+}
+
+impl From<SocketAddr> for ProxyTarget {
+    fn from(value: SocketAddr) -> Self {
+        Self::Addr(value)
+    }
+}
+
+impl From<SocketAddrV4> for ProxyTarget {
+    fn from(value: SocketAddrV4) -> Self {
+        Self::Addr(value.into())
+    }
+}
+
+impl From<SocketAddrV6> for ProxyTarget {
+    fn from(value: SocketAddrV6) -> Self {
+        Self::Addr(value.into())
+    }
+}
+
+impl From<(String, u16)> for ProxyTarget {
+    fn from((domain, port): (String, u16)) -> Self {
+        Self::Domain(domain, port)
+    }
+}
+
+impl From<(&str, u16)> for ProxyTarget {
+    fn from((domain, port): (&str, u16)) -> Self {
+        Self::Domain(domain.to_string(), port)
+    }
+}
+
+const SOCKS5_VERSION: u8 = 0x05;
+const SOCKS5_NO_AUTH: u8 = 0x00;
+const SOCKS5_USER_PASS: u8 = 0x02;
+const SOCKS5_NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const SOCKS5_USER_PASS_VERSION: u8 = 0x01;
+const SOCKS5_CONNECT: u8 = 0x01;
+const SOCKS5_ATYP_IPV4: u8 = 0x01;
+const SOCKS5_ATYP_DOMAIN: u8 = 0x03;
+const SOCKS5_ATYP_IPV6: u8 = 0x04;
+
+// Ask the proxy on the other end of the stream to connect to the target, as in RFC 1928, with
+// username and password authentication from RFC 1929.
+fn socks5_connect<S: Read + Write>(
+    stream: &mut S,
+    to: &ProxyTarget,
+    credentials: Option<&(String, String)>,
+) -> Result<(), Error> {
+    let invalid = |reason: &str| Error::Io(io::Error::new(io::ErrorKind::InvalidData, reason));
+    let greeting = match credentials {
+        Some(_) => vec![SOCKS5_VERSION, 2, SOCKS5_NO_AUTH, SOCKS5_USER_PASS],
+        None => vec![SOCKS5_VERSION, 1, SOCKS5_NO_AUTH],
+    };
+    stream.write_all(&greeting)?;
+    let mut choice = [0; 2];
+    stream.read_exact(&mut choice)?;
+    if choice[0] != SOCKS5_VERSION {
+        return Err(invalid("unexpected socks version"));
+    }
+    match (choice[1], credentials) {
+        (SOCKS5_NO_AUTH, _) => (),
+        (SOCKS5_USER_PASS, Some((username, password))) => {
+            let (username, password) = (username.as_bytes(), password.as_bytes());
+            let too_long = |field: &[u8]| field.is_empty() || field.len() > u8::MAX as usize;
+            if too_long(username) || too_long(password) {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "socks credentials must be 1 to 255 bytes",
+                )));
+            }
+            let mut request = vec![SOCKS5_USER_PASS_VERSION, username.len() as u8];
+            request.extend_from_slice(username);
+            request.push(password.len() as u8);
+            request.extend_from_slice(password);
+            stream.write_all(&request)?;
+            let mut status = [0; 2];
+            stream.read_exact(&mut status)?;
+            if status[1] != 0 {
+                return Err(Error::ProxyAuthentication);
+            }
+        }
+        (SOCKS5_NO_ACCEPTABLE_METHOD, _) => return Err(Error::ProxyAuthentication),
+        _ => return Err(invalid("unexpected socks authentication method")),
+    }
+    let mut request = vec![SOCKS5_VERSION, SOCKS5_CONNECT, 0];
+    let port = match to {
+        ProxyTarget::Addr(SocketAddr::V4(addr)) => {
+            request.push(SOCKS5_ATYP_IPV4);
+            request.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        ProxyTarget::Addr(SocketAddr::V6(addr)) => {
+            request.push(SOCKS5_ATYP_IPV6);
+            request.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        ProxyTarget::Domain(domain, port) => {
+            if domain.is_empty() || domain.len() > u8::MAX as usize {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "socks domain must be 1 to 255 bytes",
+                )));
+            }
+            request.push(SOCKS5_ATYP_DOMAIN);
+            request.push(domain.len() as u8);
+            request.extend_from_slice(domain.as_bytes());
+            *port
+        }
+    };
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request)?;
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != SOCKS5_VERSION {
+        return Err(invalid("unexpected socks version"));
+    }
+    if reply[1] != 0 {
+        return Err(Error::ProxyReply(reply[1]));
+    }
+    // The address the proxy bound to is of no use, but must be read past
+    let bound_len = match reply[3] {
+        SOCKS5_ATYP_IPV4 => 4,
+        SOCKS5_ATYP_IPV6 => 16,
+        SOCKS5_ATYP_DOMAIN => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        _ => return Err(invalid("unexpected socks address type")),
+    };
+    let mut bound = vec![0; bound_len + 2];
+    stream.read_exact(&mut bound)?;
+    Ok(())
+}
+
+fn connect_stream(
+    config: &ConnectionConfig,
+    to: SocketAddr,
+    timeout_params: &TimeoutParams,
+) -> Result<TcpStream, Error> {
+    let tcp_stream = match config.bind_local {
+        Some(local) => connect_from(local, to, timeout_params.tcp)?,
+        None => TcpStream::connect_timeout(&to, timeout_params.tcp)?,
+    };
+    configure_stream(config, &tcp_stream, timeout_params)?;
+    Ok(tcp_stream)
+}
+
+fn connect_from(local: SocketAddr, to: SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(to),
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    socket.bind(&local.into())?;
+    socket.connect_timeout(&to.into(), timeout)?;
+    Ok(socket.into())
+}
+
+fn configure_stream(
+    config: &ConnectionConfig,
+    tcp_stream: &TcpStream,
+    timeout_params: &TimeoutParams,
+) -> Result<(), io::Error> {
+    tcp_stream.set_read_timeout(timeout_params.read)?;
+    tcp_stream.set_write_timeout(timeout_params.write)?;
+    if let Some((recv, send)) = config.socket_buffers {
+        let socket = socket2::SockRef::from(tcp_stream);
+        socket.set_recv_buffer_size(recv)?;
+        socket.set_send_buffer_size(send)?;
+    }
+    Ok(())
+}
+
+/// Configurations for ending a connection due to inactivity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct TimeoutParams {
+    read: Option<Duration>,
+    write: Option<Duration>,
+    tcp: Duration,
+    ping_interval: Duration,
+    handshake: Duration,
+    initial_read: Duration,
+}
+
+impl TimeoutParams {
+    /// Construct new timeout parameters
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the time a peer has until they have must sent a message.
+    pub fn read_timeout(&mut self, timeout: Duration) {
+        self.read = Some(timeout)
+    }
+
+    /// Maximum amount of time it should take to write a message.
+    pub fn write_timeout(&mut self, timeout: Duration) {
+        self.write = Some(timeout)
+    }
+
+    /// The initial TCP handshake timeout.
+    pub fn tcp_handshake_timeout(&mut self, timeout: Duration) {
+        self.tcp = timeout;
+    }
+
+    /// How often is this peer pinged for activity
+    pub fn ping_interval(&mut self, every: Duration) {
+        self.ping_interval = every
+    }
+
+    /// The time a peer has to complete the version handshake with a `verack`.
+    pub fn handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake = timeout
+    }
+
+    /// The time a peer has to send their version after the connection is opened.
+    pub fn initial_read_timeout(&mut self, timeout: Duration) {
+        self.initial_read = timeout
+    }
+}
+
+impl Default for TimeoutParams {
+    fn default() -> Self {
+        Self {
+            read: Some(READ_TIMEOUT),
+            write: None,
+            tcp: TCP_TIMEOUT,
+            ping_interval: PING_INTERVAL,
+            handshake: HANDSHAKE_TIMEOUT,
+            initial_read: INITIAL_READ_TIMEOUT,
+        }
+    }
+}
+
+/// New data to announce to a peer.
+#[derive(Debug, Clone)]
+pub enum Announcement {
+    /// A new block.
+    Block {
+        /// The header of the new block.
+        header: Header,
+        /// The compact block, if available, for peers that prefer high-bandwidth compact blocks.
+        compact_block: Option<CmpctBlock>,
+    },
+    /// New transactions.
+    Transactions(Vec<Inventory>),
+    /// New transactions with their fee rates. Transactions below the fee filter of the peer are
+    /// not announced, and the rest are announced by WTXID if both sides negotiated `wtxidrelay`.
+    TransactionsWithFees(Vec<TxAnnouncement>),
+}
+
+/// A transaction to announce, with the data required to follow the preferences of the peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxAnnouncement {
+    /// The transaction identifier.
+    pub txid: Txid,
+    /// The witness transaction identifier.
+    pub wtxid: Wtxid,
+    /// The fee rate of the transaction.
+    pub fee_rate: FeeRate,
+}
+
+// Announce only transactions that pass the fee filter, identified as the peer prefers
+fn transaction_inventory(
+    transactions: Vec<TxAnnouncement>,
+    fee_filter: Option<FeeRate>,
+    wtxid_relay: bool,
+) -> Vec<Inventory> {
+    transactions
+        .into_iter()
+        .filter(|tx| fee_filter.is_none_or(|min| tx.fee_rate >= min))
+        .map(|tx| match wtxid_relay {
+            true => Inventory::WTx(tx.wtxid),
+            false => Inventory::Transaction(tx.txid),
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+enum WriteRequest {
+    Shutdown,
+    Disconnect,
+    SendMessage(NetworkMessage),
+}
+
+/// Send messages to an open connection.
+#[derive(Debug)]
+pub struct ConnectionWriter {
+    sender: mpsc::Sender<WriteRequest>,
+    // Dropping the writer stops the writer thread, see `OpenWriter::writer_alive`
+    _alive: Arc<()>,
+    task_handle: JoinHandle<Result<(), Error>>,
+    effective_version: ProtocolVersion,
+    peer_id: PeerId,
+    their_preferences: Arc<Mutex<Preferences>>,
+    outbound_ping_state: Arc<Mutex<OutboundPing>>,
+    ping_nonce: bool,
+}
+
+#[allow(clippy::result_large_err)]
+impl ConnectionWriter {
+    /// The identifier for this connection.
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// Send a network message to this peer. Errors indicate that the connection is terminated and
+    /// no further messages will succeed.
+    pub fn send_message(&self, network_message: NetworkMessage) -> Result<(), Error> {
+        self.sender
+            .send(WriteRequest::SendMessage(network_message))
+            .map_err(|_| Error::ChannelClosed)
+    }
+
+    /// Ping the peer and block until the matching pong is read, returning the round trip time. If
+    /// a keepalive ping is already outstanding, its pong is awaited instead of sending another.
+    /// The pong is only observed while the [`ConnectionReader`] is reading messages, so this must
+    /// be called from a different thread than the reader. Returns [`Error::WaitTimeout`] if no
+    /// pong arrives within the timeout.
+    pub fn ping_now(&self, timeout: Duration) -> Result<Duration, Error> {
+        let sent = {
+            let mut lock = self
+                .outbound_ping_state
+                .lock()
+                .map_err(|_| Error::ChannelClosed)?;
+            match *lock {
+                OutboundPing::Waiting { nonce: _, then } => then,
+                OutboundPing::LastReceived { then: _ } => {
+                    let (nonce, ping) = ping_message(self.ping_nonce);
+                    let then = Instant::now();
+                    *lock = OutboundPing::Waiting { nonce, then };
+                    self.send_message(ping)?;
+                    then
+                }
+            }
+        };
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if let Ok(lock) = self.outbound_ping_state.lock() {
+                if let OutboundPing::LastReceived { then } = *lock {
+                    if then >= sent {
+                        return Ok(then.saturating_duration_since(sent));
+                    }
+                }
+            }
+            std::thread::sleep(PING_POLL_INTERVAL);
+        }
+        Err(Error::WaitTimeout)
+    }
+
+    /// Request an inventory of blocks following the last known hash in the locator, up to the
+    /// `stop_hash`. The peer responds with an `Inv` of block hashes, which may be collected with
+    /// [`inventory_block_hashes`].
+    pub fn get_blocks(
+        &self,
+        locator_hashes: Vec<BlockHash>,
+        stop_hash: BlockHash,
+    ) -> Result<(), Error> {
+        if locator_hashes.len() > MAX_LOCATOR_HASHES {
+            return Err(Error::TooManyLocatorHashes(locator_hashes.len()));
+        }
+        let get_blocks = GetBlocksMessage {
+            version: self.effective_version,
+            locator_hashes,
+            stop_hash,
+        };
+        self.send_message(NetworkMessage::GetBlocks(get_blocks))
+    }
+
+    /// Announce new data to the peer according to their preferences. New blocks are sent as a
+    /// compact block to peers that request high-bandwidth compact block relay, if one is provided,
+    /// then by header to peers that prefer `sendheaders`, and otherwise by inventory. See
+    /// [`Announcement::TransactionsWithFees`] for how transactions are announced. Nothing is sent
+    /// if every transaction is filtered out.
+    pub fn announce(&self, announcement: Announcement) -> Result<(), Error> {
+        let preferences = self
+            .their_preferences
+            .lock()
+            .map(|lock| *lock)
+            .unwrap_or_default();
+        let message = match announcement {
+            Announcement::Block {
+                header,
+                compact_block,
+            } => match compact_block {
+                Some(compact_block) if preferences.sendcmpct.send_compact => {
+                    NetworkMessage::CmpctBlock(compact_block)
+                }
+                _ if preferences.sendheaders => {
+                    NetworkMessage::Headers(HeadersMessage(vec![header]))
+                }
+                _ => NetworkMessage::Inv(InventoryPayload(vec![Inventory::Block(
+                    header.block_hash(),
+                )])),
+            },
+            Announcement::Transactions(inventory) => {
+                NetworkMessage::Inv(InventoryPayload(inventory))
+            }
+            Announcement::TransactionsWithFees(transactions) => {
+                // We send `wtxidrelay` when the negotiated version supports it
+                let wtxid_relay = preferences.sendwtxid
+                    && self.effective_version >= ProtocolVersion::WTXID_RELAY_VERSION;
+                let inventory =
+                    transaction_inventory(transactions, preferences.fee_filter, wtxid_relay);
+                if inventory.is_empty() {
+                    return Ok(());
+                }
+                NetworkMessage::Inv(InventoryPayload(inventory))
+            }
+        };
+        self.send_message(message)
+    }
+
+    /// Query the peer for the unspent status of the outpoints as described in BIP64, optionally
+    /// including the mempool. The peer should offer [`ServiceFlags::GETUTXO`], and responds with a
+    /// `utxos` message, surfaced by [`MessageHandler::on_utxos`] or [`Utxos::from_message`].
+    pub fn get_utxos(&self, outpoints: Vec<OutPoint>, check_mempool: bool) -> Result<(), Error> {
+        if outpoints.len() > MAX_GETUTXOS_OUTPOINTS {
+            return Err(Error::TooManyOutpoints(outpoints.len()));
+        }
+        let mut payload = consensus::serialize(&check_mempool);
+        payload.extend(consensus::serialize(&outpoints));
+        self.send_message(NetworkMessage::Unknown {
+            command: CommandString::try_from_static("getutxos").expect("valid command"),
+            payload,
+        })
+    }
+
+    /// Serve a transaction to the peer in response to the `request` in a `GetData`. As described
+    /// in BIP144, witness data is only sent if a `MSG_WITNESS_TX` or `MSG_WTX` was requested, and
+    /// is removed for a plain `MSG_TX`.
+    pub fn send_tx(&self, request: &Inventory, mut transaction: Transaction) -> Result<(), Error> {
+        if !requests_witness(request) {
+            strip_witness(&mut transaction);
+        }
+        self.send_message(NetworkMessage::Tx(transaction))
+    }
+
+    /// Serve a block to the peer in response to the `request` in a `GetData`. As described in
+    /// BIP144, witness data is only sent if a `MSG_WITNESS_BLOCK` was requested, and is removed for
+    /// a plain `MSG_BLOCK`.
+    pub fn send_block(&self, request: &Inventory, mut block: Block) -> Result<(), Error> {
+        if !requests_witness(request) {
+            block.txdata.iter_mut().for_each(strip_witness);
+        }
+        self.send_message(NetworkMessage::Block(block))
+    }
+
+    /// Kill both sides of the connection, erroring if the stream is already closed.
+    pub fn shutdown(&self) -> Result<(), Error> {
+        self.sender
+            .send(WriteRequest::Shutdown)
+            .map_err(|_| Error::ChannelClosed)
+    }
+
+    /// Close the connection after every message already queued has been written to the stream and
+    /// flushed. Blocks until the writer finishes, returning any error it encountered.
+    pub fn disconnect_flushing(self) -> Result<(), Error> {
+        // If the writer has already stopped, its error is reported below
+        let _ = self.sender.send(WriteRequest::Disconnect);
+        drop(self.sender);
+        self.task_handle.join().unwrap_or(Err(Error::ChannelClosed))
+    }
+
+    /// In the event of a failed message, investigate failures if the connection was not closed
+    /// gracefully.
+    pub fn take_errors(self) -> Option<Error> {
+        self.task_handle.join().ok()?.err()
+    }
+}
+
+#[derive(Debug)]
+struct OpenWriter<W: Write + StreamControl> {
+    stream: BufWriter<W>,
+    buffered_writes: bool,
+    transport: WriteTransport,
+    receiver: mpsc::Receiver<WriteRequest>,
+    outbound_ping_state: Arc<Mutex<OutboundPing>>,
+    ping_interval: Duration,
+    ping_nonce: bool,
+    outbound_filter: Option<OutboundFilter>,
+    start_time: Instant,
+    max_lifetime: Option<Duration>,
+    peer_closed: Arc<AtomicBool>,
+    message_history: Option<Arc<Mutex<MessageHistory>>>,
+    bytes_by_type: Arc<Mutex<HashMap<CommandString, u64>>>,
+    bytes_sent: Arc<Mutex<u64>>,
+    requested_blocks: Arc<Mutex<HashSet<BlockHash>>>,
+    // Dropped with the `ConnectionWriter`, as the reader keeps the channel open
+    writer_alive: Weak<()>,
+}
+
+impl<W: Write + StreamControl> OpenWriter<W> {
+    fn send(&mut self, mut message: NetworkMessage) -> Result<(), io::Error> {
+        if let Some(filter) = &self.outbound_filter {
+            if !filter.apply(&mut message) {
+                return Ok(());
+            }
+        }
+        if let Some(history) = &self.message_history {
+            if let Ok(mut lock) = history.lock() {
+                lock.add(message.command(), Direction::Outbound, Instant::now());
+            }
+        }
+        if let NetworkMessage::GetData(inventory) = &message {
+            if let Ok(mut lock) = self.requested_blocks.lock() {
+                lock.extend(inventory_block_hashes(&inventory.0));
+            }
+        }
+        let command = message.command();
+        let bytes = self.transport.encode_message(message);
+        if let Ok(mut lock) = self.bytes_by_type.lock() {
+            *lock.entry(command).or_default() += bytes.len() as u64;
+        }
+        self.write_all(&bytes)?;
+        if let Ok(mut lock) = self.bytes_sent.lock() {
+            *lock += bytes.len() as u64;
+        }
+        if !self.buffered_writes {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // The reader may briefly make a shared socket nonblocking, see
+    // `ConnectionReader::try_read_message`, so a full send buffer is waited out here.
+    fn write_all(&mut self, mut bytes: &[u8]) -> Result<(), io::Error> {
+        while !bytes.is_empty() {
+            match self.stream.write(bytes) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(len) => bytes = &bytes[len..],
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(WOULD_BLOCK_RETRY_INTERVAL)
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        loop {
+            match self.stream.flush() {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(WOULD_BLOCK_RETRY_INTERVAL)
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn maintain_connection(mut self) -> Result<(), Error> {
+        loop {
+            if let Some(result) = self.stopped() {
+                return result;
+            }
+            let message = match self.receiver.try_recv() {
+                Ok(request) => Ok(request),
+                Err(mpsc::TryRecvError::Empty) => {
+                    // Nothing else is queued, so the buffer must be written out before idling
+                    self.flush()?;
+                    self.receiver.recv_timeout(WRITER_IDLE_INTERVAL)
+                }
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    self.flush()?;
+                    return Ok(());
+                }
+            };
+            match message {
+                Ok(request) => {
+                    if self.handle(request)? {
+                        return Ok(());
+                    }
+                }
+                Err(e) => match e {
+                    mpsc::RecvTimeoutError::Timeout => (),
+                    _ => return Ok(()),
+                },
+            }
+            self.ping_if_due()?;
+            // Do traffic shaping or gossip addrs
+        }
+    }
+
+    // The result of the writer if the connection has ended.
+    fn stopped(&mut self) -> Option<Result<(), Error>> {
+        if self.writer_alive.strong_count() == 0 {
+            return Some(self.flush().map_err(Error::Io));
+        }
+        if self.peer_closed.load(Ordering::Relaxed) {
+            let _ = self.stream.get_ref().close();
+            return Some(Err(Error::PeerHalfClosed));
+        }
+        if let Some(max_lifetime) = self.max_lifetime {
+            if self.start_time.elapsed() > max_lifetime {
+                let closed = self.flush().and_then(|_| self.stream.get_ref().close());
+                return Some(closed.map_err(Error::Io).and(Err(Error::LifetimeExpired)));
+            }
+        }
+        None
+    }
+
+    // Handle a queued request, returning `true` if the writer should stop.
+    fn handle(&mut self, request: WriteRequest) -> Result<bool, Error> {
+        match request {
+            WriteRequest::SendMessage(message) => self.send(message)?,
+            WriteRequest::Shutdown => {
+                self.flush()?;
+                self.stream.get_ref().close()?;
+            }
+            // Requests are handled in order, so every earlier message was already sent
+            WriteRequest::Disconnect => {
+                self.flush()?;
+                self.stream.get_ref().close()?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // Ping the peer if the ping interval passed since the last pong.
+    fn ping_if_due(&mut self) -> Result<(), Error> {
+        let ping_state = Arc::clone(&self.outbound_ping_state);
+        if let Ok(mut ping) = ping_state.lock() {
+            if let OutboundPing::LastReceived { then } = *ping {
+                if then.elapsed() > self.ping_interval {
+                    let (nonce, message) = ping_message(self.ping_nonce);
+                    self.send(message)?;
+                    *ping = OutboundPing::Waiting {
+                        nonce,
+                        then: Instant::now(),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// The longest an idle writer waits for a request before checking the connection and pings
+const WRITER_IDLE_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long the writer waits to retry a write that would have blocked
+const WOULD_BLOCK_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
+// How often an on-demand ping checks if the pong was read
+const PING_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Peers before BIP31 expect an empty ping and will not respond with a pong, so any message that
+// follows is taken as a sign of life.
+fn ping_message(ping_nonce: bool) -> (u64, NetworkMessage) {
+    if ping_nonce {
+        let nonce = random();
+        (nonce, NetworkMessage::Ping(nonce))
+    } else {
+        let message = NetworkMessage::Unknown {
+            command: CommandString::try_from_static("ping").expect("valid command"),
+            payload: Vec::new(),
+        };
+        (0, message)
+    }
+}
+
+/// Read messages from an open connection.
+#[derive(Debug)]
+pub struct ConnectionReader<R = TcpStream> {
+    peer_id: PeerId,
+    writer: mpsc::Sender<WriteRequest>,
+    stream: BufReader<R>,
+    transport: ReadTransport,
+    their_preferences: Arc<Mutex<Preferences>>,
+    timed_messages: Arc<Mutex<TimedMessages>>,
+    outbound_ping_state: Arc<Mutex<OutboundPing>>,
+    verified_services: Arc<Mutex<ServiceFlags>>,
+    unsolicited_pongs: Arc<Mutex<u64>>,
+    oversized_addrs: Arc<Mutex<u64>>,
+    protocol_violations: Arc<Mutex<u64>>,
+    bytes_by_type: Arc<Mutex<HashMap<CommandString, u64>>>,
+    unrequested_blocks: Arc<Mutex<u64>>,
+    ping_rtt: Arc<Mutex<Option<Duration>>>,
+    first_message: Arc<Mutex<Option<CommandString>>>,
+    bytes_received: Arc<Mutex<u64>>,
+    ban_score: Arc<Mutex<BanScore>>,
+    requested_blocks: Arc<Mutex<HashSet<BlockHash>>>,
+    max_unrequested_blocks: Option<u64>,
+    auto_getheaders: Option<LocatorFn>,
+    max_header_target: Option<Target>,
+    effective_version: ProtocolVersion,
+    max_addr_per_message: usize,
+    disconnect_on_addr_flood: bool,
+    check_filter_headers: bool,
+    last_filter_header: Option<FilterHeader>,
+    witness: bool,
+    ping_nonce: bool,
+    block_relay_only: bool,
+    peer_closed: Arc<AtomicBool>,
+    mempool_lookup: Option<MempoolLookup>,
+    pending_compact_block: Option<PartialBlock>,
+    partial_frame: PartialFrame,
+    last_message_size: usize,
+    compact_block_status: Option<CompactBlockStatus>,
+    genesis_hash: BlockHash,
+    decode_errors_remaining: u32,
+    inbound_filter: Option<InboundFilter>,
+    progress: MessageProgress,
+    buffer_pool: Option<BufferPool>,
+    read_memory_budget: Option<ReadMemoryBudget>,
+    message_history: Option<Arc<Mutex<MessageHistory>>>,
+    addr_gossip: Arc<Mutex<AddrGossip>>,
+}
+
+impl<R: Read + StreamControl> ConnectionReader<R> {
+    /// The identifier for this connection.
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// Wait for a message as with [`ConnectionReader::read_message`], along with the number of
+    /// bytes it took on the wire. This includes the header, and for the v2 transport the
+    /// encryption overhead and any decoy packets sent before the message.
+    pub fn read_message_with_size(&mut self) -> Result<Option<(NetworkMessage, usize)>, Error> {
+        loop {
+            let message = self.next_message()?;
+            let size = self.last_message_size;
+            if let Some(message) = self.accept(message)? {
+                return Ok(Some((message, size)));
+            }
+        }
+    }
+
+    /// Wait for a message while blocking the current thread of execution.
+    pub fn read_message(&mut self) -> Result<Option<NetworkMessage>, Error> {
+        loop {
+            let message = self.next_message()?;
+            if let Some(message) = self.accept(message)? {
+                return Ok(Some(message));
+            }
+        }
+    }
+
+    /// Read a message if one has been fully received, returning `Ok(None)` without blocking
+    /// otherwise, or if the message read was dropped. Bytes of a message that has only partially
+    /// arrived are kept until the rest of the message is received by a later call. The stream
+    /// must support [`StreamControl::set_nonblocking`].
+    pub fn try_read_message(&mut self) -> Result<Option<NetworkMessage>, Error> {
+        self.stream.get_ref().set_nonblocking(true)?;
+        let frame = self.poll_frame();
+        self.stream.get_ref().set_nonblocking(false)?;
+        let message = match frame {
+            Ok(Some((header, frame))) => self.decode(header.command, frame)?,
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => None,
+            Ok(None) => None,
+            Err(e) => return Err(e),
+        };
+        match message {
+            Some(message) => self.accept(message),
+            None => Ok(None),
+        }
+    }
+
+    /// Read messages until one with the given command arrives. The payload of every other message
+    /// is discarded without being decoded, so these messages are not reflected in the connection
+    /// metrics. Pings are still decoded and answered to keep the connection alive.
+    pub fn skip_until(&mut self, command: CommandString) -> Result<NetworkMessage, Error> {
+        let ping = CommandString::try_from_static("ping").expect("valid command");
+        loop {
+            let header = self.read_header()?;
+            if header.command != command && header.command != ping {
+                self.transport.skip_body(&mut self.stream, &header)?;
+                continue;
+            }
+            let Some(message) = self.decode_body(header)? else {
+                continue;
+            };
+            let Some(message) = self.accept(message)? else {
+                continue;
+            };
+            if let NetworkMessage::Ping(nonce) = message {
+                self.writer
+                    .send(WriteRequest::SendMessage(NetworkMessage::Pong(nonce)))
+                    .map_err(|_| Error::ChannelClosed)?;
+                if command != ping {
+                    continue;
+                }
+            }
+            return Ok(message);
+        }
+    }
+
+    // Record the message and apply the inbound filter, returning the message if it passes.
+    fn accept(&mut self, message: NetworkMessage) -> Result<Option<NetworkMessage>, Error> {
+        if let Some(history) = &self.message_history {
+            if let Ok(mut lock) = history.lock() {
+                lock.add(message.command(), Direction::Inbound, Instant::now());
+            }
+        }
+        if let Ok(mut lock) = self.ban_score.lock() {
+            lock.record(&message);
+        }
+        if violates_version(&message, self.effective_version) {
+            if let Ok(mut lock) = self.protocol_violations.lock() {
+                *lock += 1;
+            }
+            return Ok(None);
+        }
+        if self.block_relay_only && relays_transactions(&message) {
+            let _ = self.stream.get_ref().close();
+            return Err(Error::UnexpectedTransactionRelay(message.command()));
+        }
+        let num_addrs = match &message {
+            NetworkMessage::Addr(addr) => addr.0.len(),
+            NetworkMessage::AddrV2(addr) => addr.0.len(),
+            _ => 0,
+        };
+        if num_addrs > self.max_addr_per_message {
+            if let Ok(mut lock) = self.oversized_addrs.lock() {
+                *lock += 1;
+            }
+            if self.disconnect_on_addr_flood {
+                let _ = self.stream.get_ref().close();
+                return Err(Error::OversizedAddr(num_addrs));
+            }
+        }
+        if let NetworkMessage::Block(block) = &message {
+            let requested = self
+                .requested_blocks
+                .lock()
+                .map(|mut lock| lock.remove(&block.block_hash()))
+                .unwrap_or(true);
+            if !requested {
+                let count = match self.unrequested_blocks.lock() {
+                    Ok(mut lock) => {
+                        *lock += 1;
+                        *lock
+                    }
+                    Err(_) => 0,
+                };
+                if self.max_unrequested_blocks.is_some_and(|max| count > max) {
+                    let _ = self.stream.get_ref().close();
+                    return Err(Error::UnrequestedBlockFlood(count));
+                }
+            }
+        }
+        if let (Some(max_target), NetworkMessage::Headers(headers)) =
+            (self.max_header_target, &message)
+        {
+            if let Some(header) = headers
+                .0
+                .iter()
+                .find(|header| !check_header_pow(header, max_target))
+            {
+                let _ = self.stream.get_ref().close();
+                return Err(Error::InsufficientPow(header.block_hash()));
+            }
+        }
+        if let (true, NetworkMessage::CFHeaders(cfheaders)) = (self.check_filter_headers, &message)
+        {
+            if self
+                .last_filter_header
+                .is_some_and(|last| last != cfheaders.previous_filter_header)
+            {
+                let _ = self.stream.get_ref().close();
+                return Err(Error::FilterHeaderDiscontinuity(
+                    cfheaders.previous_filter_header,
+                ));
+            }
+            let last = cfheaders
+                .filter_hashes
+                .iter()
+                .fold(cfheaders.previous_filter_header, |previous, filter_hash| {
+                    filter_hash.filter_header(&previous)
+                });
+            self.last_filter_header = Some(last);
+        }
+        let action = match &self.inbound_filter {
+            Some(filter) => filter.apply(&message),
+            None => FilterAction::Pass,
+        };
+        if let FilterAction::Disconnect = action {
+            let _ = self.stream.get_ref().close();
+            return Err(Error::FilterDisconnect(message.command()));
+        }
+        // Dropped messages are still reflected in the connection metrics
+        self.record_message(&message);
+        if let FilterAction::Pass = action {
+            self.reconstruct_compact_block(&message)?;
+        }
+        if let (Some(locator), NetworkMessage::Inv(inventory)) = (&self.auto_getheaders, &message) {
+            if !inventory_block_hashes(&inventory.0).is_empty() {
+                let get_headers = GetHeadersMessage {
+                    version: self.effective_version,
+                    locator_hashes: locator.locator(),
+                    stop_hash: BlockHash::from_byte_array([0; 32]),
+                };
+                self.writer
+                    .send(WriteRequest::SendMessage(NetworkMessage::GetHeaders(
+                        get_headers,
+                    )))
+                    .map_err(|_| Error::ChannelClosed)?;
+            }
+        }
+        match action {
+            FilterAction::Pass => Ok(Some(message)),
+            _ => Ok(None),
+        }
+    }
+
+    /// The bytes received and total payload length of the message currently being read, if any.
+    /// A peer that delivers a large message too slowly may be considered stalling. See
+    /// [`ConnectionMetrics::current_message_progress`] to observe this from another thread.
+    pub fn current_message_progress(&self) -> Option<(usize, usize)> {
+        self.progress.get()
+    }
+
+    /// Read messages and pass each one to the corresponding method of the handler, until the
+    /// connection fails or is closed.
+    pub fn dispatch(&mut self, handler: &mut impl MessageHandler) -> Result<(), Error> {
+        while let Some(message) = self.read_message()? {
+            match message {
+                NetworkMessage::Block(block) => handler.on_block(block),
+                NetworkMessage::Headers(headers) => handler.on_headers(headers.0),
+                NetworkMessage::Inv(inv) => handler.on_inv(inv.0),
+                NetworkMessage::GetData(inv) => handler.on_get_data(inv.0),
+                NetworkMessage::NotFound(inv) => handler.on_not_found(inv.0),
+                NetworkMessage::GetHeaders(get_headers) => handler.on_get_headers(get_headers),
+                NetworkMessage::Tx(tx) => handler.on_tx(tx),
+                NetworkMessage::Addr(addr) => handler.on_addr(addr.0),
+                NetworkMessage::AddrV2(addr) => handler.on_addrv2(addr.0),
+                NetworkMessage::CFilter(cfilter) => handler.on_cfilter(cfilter),
+                NetworkMessage::CFHeaders(cfheaders) => handler.on_cfheaders(cfheaders),
+                NetworkMessage::CmpctBlock(cmpct) => handler.on_cmpct_block(cmpct),
+                NetworkMessage::Ping(nonce) => handler.on_ping(nonce),
+                NetworkMessage::Pong(nonce) => handler.on_pong(nonce),
+                other => match Utxos::from_message(&other) {
+                    Some(utxos) => handler.on_utxos(utxos?),
+                    None => handler.on_other(other),
+                },
+            }
+            if let Some(status) = self.compact_block_status.take() {
+                handler.on_compact_block_status(status);
+            }
+        }
+        Ok(())
+    }
+
+    /// Read messages until a `headers` message arrives, checking that each header builds on the
+    /// one before it. Returns [`Error::DisconnectedHeaders`] with the hash of the first header
+    /// that does not connect. Other messages are handled as in [`ConnectionReader::wait_for`].
+    pub fn wait_for_headers(&mut self, timeout: Duration) -> Result<Vec<Header>, Error> {
+        let message = self.wait_for(
+            |message| matches!(message, NetworkMessage::Headers(_)),
+            timeout,
+        )?;
+        let NetworkMessage::Headers(headers) = message else {
+            unreachable!("only headers satisfy the predicate");
+        };
+        if let Some(index) = first_disconnected_header(&headers.0) {
+            return Err(Error::DisconnectedHeaders(headers.0[index].block_hash()));
+        }
+        Ok(headers.0)
+    }
+
+    /// Wait at most the timeout for a message to start arriving, returning `Ok(None)` if none
+    /// does, so the caller may do periodic work between reads. Once the first bytes of a message
+    /// arrive, the rest of the message is read with the usual read timeout so the stream is never
+    /// left in the middle of a message. A closed connection is still reported as an error.
+    pub fn read_message_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<NetworkMessage>, Error> {
+        if !wait_readable(&mut self.stream, timeout)? {
+            return Ok(None);
+        }
+        self.read_message()
+    }
+
+    /// Read messages until one satisfies the predicate, discarding the others. Discarded messages
+    /// are still recorded in the connection metrics, and discarded pings are answered. Returns
+    /// [`Error::WaitTimeout`] if no matching message starts to arrive within the timeout. As with
+    /// [`ConnectionReader::read_message_timeout`], a message that started to arrive is read in
+    /// full with the usual read timeout.
+    pub fn wait_for(
+        &mut self,
+        predicate: impl Fn(&NetworkMessage) -> bool,
+        timeout: Duration,
+    ) -> Result<NetworkMessage, Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || !wait_readable(&mut self.stream, remaining)? {
+                return Err(Error::WaitTimeout);
+            }
+            let message = self.next_message()?;
+            let Some(message) = self.accept(message)? else {
+                continue;
+            };
+            if predicate(&message) {
+                return Ok(message);
+            }
+            if let NetworkMessage::Ping(nonce) = message {
+                self.writer
+                    .send(WriteRequest::SendMessage(NetworkMessage::Pong(nonce)))
+                    .map_err(|_| Error::ChannelClosed)?;
+            }
+        }
+    }
+
+    /// Set the filter header the next `cfheaders` message must continue from, for instance a
+    /// checkpoint. Only used with [`ConnectionConfig::check_filter_headers`].
+    pub fn expect_filter_header(&mut self, filter_header: FilterHeader) {
+        self.last_filter_header = Some(filter_header);
+    }
+
+    /// The progress of the most recent compact block, if it changed since the last call. Compact
+    /// blocks are only reconstructed when [`ConnectionConfig::mempool_lookup`] is configured.
+    pub fn take_compact_block_status(&mut self) -> Option<CompactBlockStatus> {
+        self.compact_block_status.take()
+    }
+
+    fn reconstruct_compact_block(&mut self, message: &NetworkMessage) -> Result<(), Error> {
+        let Some(lookup) = &self.mempool_lookup else {
+            return Ok(());
+        };
+        match message {
+            NetworkMessage::CmpctBlock(cmpct) => {
+                let partial = PartialBlock::new(&cmpct.compact_block, lookup);
+                let missing = partial.missing();
+                if missing.is_empty() {
+                    self.compact_block_status = Some(partial.complete());
+                    return Ok(());
+                }
+                let block_hash = partial.header.block_hash();
+                let get_block_txn = GetBlockTxn {
+                    txs_request: BlockTransactionsRequest {
+                        block_hash,
+                        indexes: missing.clone(),
+                    },
+                };
+                self.writer
+                    .send(WriteRequest::SendMessage(NetworkMessage::GetBlockTxn(
+                        get_block_txn,
+                    )))
+                    .map_err(|_| Error::ChannelClosed)?;
+                self.pending_compact_block = Some(partial);
+                self.compact_block_status = Some(CompactBlockStatus::Requested {
+                    block_hash,
+                    missing,
+                });
+            }
+            NetworkMessage::BlockTxn(block_txn) => {
+                let block_transactions = &block_txn.transactions;
+                let is_pending = self.pending_compact_block.as_ref().is_some_and(|partial| {
+                    partial.header.block_hash() == block_transactions.block_hash
+                });
+                if let (true, Some(mut partial)) = (is_pending, self.pending_compact_block.take()) {
+                    let status = if partial.fill(&block_transactions.transactions) {
+                        partial.complete()
+                    } else {
+                        CompactBlockStatus::Failed(block_transactions.block_hash)
+                    };
+                    self.compact_block_status = Some(status);
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn next_message(&mut self) -> Result<NetworkMessage, Error> {
+        loop {
+            // Finish a message started by a non-blocking read before reading the next header
+            if !self.partial_frame.is_empty() {
+                if let Some((header, frame)) = self.poll_frame()? {
+                    if let Some(message) = self.decode(header.command, frame)? {
+                        return Ok(message);
+                    }
+                }
+                continue;
+            }
+            let header = self.read_header()?;
+            if let Some(message) = self.decode_body(header)? {
+                return Ok(message);
+            }
+        }
+    }
+
+    fn read_header(&mut self) -> Result<FrameHeader, Error> {
+        match self
+            .transport
+            .read_header(&mut self.stream, self.read_memory_budget.as_ref())
+        {
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                Err(self.closed_by_peer())
+            }
+            Err(e @ Error::ReadMemoryExhausted(_)) => {
+                self.abandon_stream();
+                Err(e)
+            }
+            Ok(header) => {
+                self.record_header(&header);
+                Ok(header)
+            }
+            result => result,
+        }
+    }
+
+    // Read the bytes available towards the next message, returning the message once complete.
+    fn poll_frame(&mut self) -> Result<Option<(FrameHeader, Vec<u8>)>, Error> {
+        match self.transport.poll_frame(
+            &mut self.stream,
+            &mut self.partial_frame,
+            self.read_memory_budget.as_ref(),
+        ) {
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                Err(self.closed_by_peer())
+            }
+            Err(e @ Error::ReadMemoryExhausted(_)) => {
+                self.abandon_stream();
+                Err(e)
+            }
+            Ok(Some((header, frame))) => {
+                self.record_header(&header);
+                Ok(Some((header, frame)))
+            }
+            result => result,
+        }
+    }
+
+    fn closed_by_peer(&mut self) -> Error {
+        // The peer will not send anything further, so stop the writer as well
+        self.peer_closed.store(true, Ordering::Relaxed);
+        let _ = self.stream.get_ref().close();
+        Error::PeerHalfClosed
+    }
+
+    // Close a stream that was left part way through a message, so no further reads decode the
+    // rest of it as the start of another message.
+    fn abandon_stream(&mut self) {
+        self.peer_closed.store(true, Ordering::Relaxed);
+        let _ = self.stream.get_ref().close();
+        let buffered = self.stream.buffer().len();
+        self.stream.consume(buffered);
+        self.partial_frame = PartialFrame::default();
+    }
+
+    fn record_header(&mut self, header: &FrameHeader) {
+        self.last_message_size = header.wire_len;
+        if let Ok(mut lock) = self.bytes_received.lock() {
+            *lock += header.wire_len as u64;
+        }
+        if let Ok(mut lock) = self.bytes_by_type.lock() {
+            let len = (header.bytes.len() + header.length) as u64;
+            *lock.entry(header.command.clone()).or_default() += len;
+        }
+    }
+
+    // Read and decode the payload following the header, returning `None` if a decoding error
+    // was tolerated.
+    fn decode_body(&mut self, mut header: FrameHeader) -> Result<Option<NetworkMessage>, Error> {
+        let command = header.command.clone();
+        // Released when the frame is dropped or returned to the pool. A v2 packet is reserved
+        // before it is read along with the header.
+        let _reservation = match (header.reservation.take(), &self.read_memory_budget) {
+            (Some(reservation), _) => Some(reservation),
+            (None, Some(budget)) => match budget.reserve(header.bytes.len() + header.length) {
+                Ok(reservation) => Some(reservation),
+                Err(e) => {
+                    self.abandon_stream();
+                    return Err(e);
+                }
+            },
+            (None, None) => None,
+        };
+        let frame = self.transport.read_body(
+            &mut self.stream,
+            header,
+            Some(&self.progress),
+            self.buffer_pool.as_ref(),
+        )?;
+        self.decode(command, frame)
+    }
+
+    fn decode(
+        &mut self,
+        command: CommandString,
+        frame: Vec<u8>,
+    ) -> Result<Option<NetworkMessage>, Error> {
+        let decoded = self.transport.decode_frame(&frame);
+        if let Some(pool) = &self.buffer_pool {
+            pool.give(frame);
+        }
+        if decoded.is_err() {
+            if let Ok(mut lock) = self.ban_score.lock() {
+                lock.record_undecodable();
+            }
+        }
+        match decoded {
+            Ok(message) => Ok(Some(message)),
+            Err(_) if self.decode_errors_remaining > 0 => {
+                self.decode_errors_remaining -= 1;
+                Ok(None)
+            }
+            Err(source) => Err(Error::Deserialize {
+                command: Some(command),
+                source,
+            }),
+        }
+    }
+
+    fn record_message(&mut self, message: &NetworkMessage) {
+        if let Ok(mut lock) = self.first_message.lock() {
+            lock.get_or_insert_with(|| message.command());
+        }
+        if !self.ping_nonce {
+            if let Ok(mut lock) = self.outbound_ping_state.lock() {
+                *lock = OutboundPing::LastReceived {
+                    then: Instant::now(),
+                };
+            }
+        }
+        match message {
+            NetworkMessage::SendHeaders => {
+                if let Ok(mut lock) = self.their_preferences.lock() {
+                    lock.sendheaders = true;
+                }
+            }
+            NetworkMessage::SendCmpct(cmpct) => {
+                if let Ok(mut lock) = self.their_preferences.lock() {
+                    announce_compact(&mut lock.sendcmpct, *cmpct, self.witness);
+                }
+            }
+            NetworkMessage::FeeFilter(fee_filter) => {
+                if let Ok(mut lock) = self.their_preferences.lock() {
+                    lock.fee_filter = Some(*fee_filter);
+                }
+            }
+            NetworkMessage::Block(block) => {
+                if let Ok(mut lock) = self.timed_messages.lock() {
+                    lock.add_single(TimedMessage::Block, Instant::now());
+                }
+                if block.block_hash() == self.genesis_hash {
+                    if let Ok(mut lock) = self.verified_services.lock() {
+                        *lock |= ServiceFlags::NETWORK;
+                    }
+                }
+            }
+            // Only the checkpoint requested by `FeelerData::service_probes` verifies the service
+            NetworkMessage::CFCheckpt(checkpt)
+                if checkpt.stop_hash == self.genesis_hash && checkpt.filter_type == 0x00 =>
+            {
+                if let Ok(mut lock) = self.verified_services.lock() {
+                    *lock |= ServiceFlags::COMPACT_FILTERS;
+                }
+            }
+            NetworkMessage::Headers(_) => {
+                if let Ok(mut lock) = self.timed_messages.lock() {
+                    lock.add_single(TimedMessage::BlockHeaders, Instant::now());
+                    lock.last_block = Instant::now();
+                }
+            }
+            NetworkMessage::CFilter(_) => {
+                if let Ok(mut lock) = self.timed_messages.lock() {
+                    lock.add_single(TimedMessage::CFilters, Instant::now());
+                }
+            }
+            NetworkMessage::Addr(list) => {
+                if let Ok(mut lock) = self.timed_messages.lock() {
+                    lock.add_many(TimedMessage::Addr, list.0.len(), Instant::now());
+                }
+                if let Ok(mut lock) = self.addr_gossip.lock() {
+                    for (_, addr) in &list.0 {
+                        let mut key: Vec<u8> =
+                            addr.address.iter().flat_map(|s| s.to_be_bytes()).collect();
+                        key.extend_from_slice(&addr.port.to_be_bytes());
+                        lock.add(key);
+                    }
+                }
+            }
+            NetworkMessage::AddrV2(list) => {
+                if let Ok(mut lock) = self.timed_messages.lock() {
+                    lock.add_many(TimedMessage::Addr, list.0.len(), Instant::now());
+                }
+                if let Ok(mut lock) = self.addr_gossip.lock() {
+                    for addr in &list.0 {
+                        let mut key = consensus::serialize(&addr.addr);
+                        key.extend_from_slice(&addr.port.to_be_bytes());
+                        lock.add(key);
+                    }
+                }
+            }
+            NetworkMessage::Pong(pong) => {
+                // There are bigger problems with this connection if the lock fails, so it is
+                // okay to ignore the nonce.
+                let solicited = match self.outbound_ping_state.lock() {
+                    Ok(mut lock) => match *lock {
+                        OutboundPing::Waiting { nonce, then } if *pong == nonce => {
+                            let now = Instant::now();
+                            *lock = OutboundPing::LastReceived { then: now };
+                            if let Ok(mut rtt) = self.ping_rtt.lock() {
+                                *rtt = Some(now.saturating_duration_since(then));
+                            }
+                            true
+                        }
+                        _ => false,
+                    },
+                    Err(_) => true,
+                };
+                if !solicited {
+                    if let Ok(mut lock) = self.unsolicited_pongs.lock() {
+                        *lock += 1;
+                    }
+                }
+            }
+            NetworkMessage::Inv(payload) => {
+                let payload = &payload.0;
+                let now = Instant::now();
+                if let Ok(mut lock) = self.timed_messages.lock() {
+                    for inv in payload {
+                        if matches!(
+                            inv,
+                            Inventory::WTx(_)
+                                | Inventory::WitnessTransaction(_)
+                                | Inventory::Transaction(_)
+                        ) {
+                            lock.add_single(TimedMessage::TransactionAnnouncement, now);
+                        }
+                        if matches!(
+                            inv,
+                            Inventory::Block(_)
+                                | Inventory::WitnessBlock(_)
+                                | Inventory::CompactBlock(_)
+                        ) {
+                            lock.last_block = now;
+                        }
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Handle messages from a peer by type. Every method does nothing by default, so only the
+/// messages of interest must be implemented. See [`ConnectionReader::dispatch`].
+#[allow(unused_variables)]
+pub trait MessageHandler {
+    /// A block was received.
+    fn on_block(&mut self, block: Block) {}
+
+    /// Block headers were received.
+    fn on_headers(&mut self, headers: Vec<Header>) {}
+
+    /// The peer announced new inventory.
+    fn on_inv(&mut self, inventory: Vec<Inventory>) {}
+
+    /// The peer requested data.
+    fn on_get_data(&mut self, inventory: Vec<Inventory>) {}
+
+    /// The peer could not find the requested data.
+    fn on_not_found(&mut self, inventory: Vec<Inventory>) {}
+
+    /// The peer requested block headers.
+    fn on_get_headers(&mut self, get_headers: GetHeadersMessage) {}
+
+    /// A transaction was received.
+    fn on_tx(&mut self, transaction: Transaction) {}
+
+    /// The peer gossiped addresses.
+    fn on_addr(&mut self, addrs: Vec<(u32, Address)>) {}
+
+    /// The peer gossiped addresses in the `addrv2` format.
+    fn on_addrv2(&mut self, addrs: Vec<AddrV2Message>) {}
+
+    /// A compact block filter was received.
+    fn on_cfilter(&mut self, cfilter: CFilter) {}
+
+    /// Compact block filter headers were received.
+    fn on_cfheaders(&mut self, cfheaders: CFHeaders) {}
+
+    /// A compact block was received.
+    fn on_cmpct_block(&mut self, cmpct_block: CmpctBlock) {}
+
+    /// A compact block was reconstructed or requires transactions from the peer. Only called
+    /// when [`ConnectionConfig::mempool_lookup`] is configured.
+    fn on_compact_block_status(&mut self, status: CompactBlockStatus) {}
+
+    /// The peer sent a ping.
+    fn on_ping(&mut self, nonce: u64) {}
+
+    /// The peer sent a pong.
+    fn on_pong(&mut self, nonce: u64) {}
+
+    /// The peer responded to a `getutxos` request.
+    fn on_utxos(&mut self, utxos: Utxos) {}
+
+    /// Any message without a dedicated method.
+    fn on_other(&mut self, message: NetworkMessage) {}
+}
+
+/// The progress of reconstructing a compact block from the mempool.
+#[derive(Debug, Clone)]
+pub enum CompactBlockStatus {
+    /// Every transaction was found and the block is complete.
+    Complete(Block),
+    /// Transactions at these indexes were missing and have been requested with `getblocktxn`.
+    Requested {
+        /// The hash of the block being reconstructed.
+        block_hash: BlockHash,
+        /// The indexes of the missing transactions within the block.
+        missing: Vec<u64>,
+    },
+    /// The block could not be reconstructed, for instance from a short ID collision, and should
+    /// be requested in full.
+    Failed(BlockHash),
+}
+
+#[derive(Debug, Clone)]
+struct PartialBlock {
+    header: Header,
+    txdata: Vec<Option<Transaction>>,
+}
+
+impl PartialBlock {
+    fn new(compact_block: &HeaderAndShortIds, lookup: &MempoolLookup) -> Self {
+        let len = compact_block.short_ids.len() + compact_block.prefilled_txs.len();
+        let mut txdata = vec![None; len];
+        // Prefilled indexes are encoded as the difference from the previous prefilled index
+        let mut next_index = 0;
+        for prefilled in &compact_block.prefilled_txs {
+            let index = next_index + prefilled.idx as usize;
+            if let Some(slot) = txdata.get_mut(index) {
+                *slot = Some(prefilled.tx.clone());
+            }
+            next_index = index + 1;
+        }
+        let mut short_ids = compact_block.short_ids.iter();
+        for slot in txdata.iter_mut().filter(|slot| slot.is_none()) {
+            if let Some(short_id) = short_ids.next() {
+                *slot = lookup.find(*short_id);
+            }
+        }
+        Self {
+            header: compact_block.header,
+            txdata,
+        }
+    }
+
+    fn missing(&self) -> Vec<u64> {
+        self.txdata
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| tx.is_none())
+            .map(|(index, _)| index as u64)
+            .collect()
+    }
+
+    // Returns `false` if the number of transactions does not match the number missing.
+    fn fill(&mut self, transactions: &[Transaction]) -> bool {
+        let mut transactions = transactions.iter();
+        for slot in self.txdata.iter_mut().filter(|slot| slot.is_none()) {
+            match transactions.next() {
+                Some(tx) => *slot = Some(tx.clone()),
+                None => return false,
+            }
+        }
+        transactions.next().is_none()
+    }
+
+    fn complete(self) -> CompactBlockStatus {
+        let block_hash = self.header.block_hash();
+        let Some(txdata) = self.txdata.into_iter().collect::<Option<Vec<_>>>() else {
+            return CompactBlockStatus::Failed(block_hash);
+        };
+        let block = Block {
+            header: self.header,
+            txdata,
+        };
+        if !block.check_merkle_root() {
+            return CompactBlockStatus::Failed(block_hash);
+        }
+        CompactBlockStatus::Complete(block)
+    }
+}
+
+/// The response to a BIP64 `getutxos` request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Utxos {
+    /// The height of the chain tip of the peer.
+    pub chain_height: u32,
+    /// The hash of the chain tip of the peer.
+    pub chain_tip: BlockHash,
+    /// A bit for each requested outpoint, set if the outpoint is unspent.
+    pub bitmap: Vec<u8>,
+    /// The unspent outputs, in the order they were requested.
+    pub outputs: Vec<Utxo>,
+}
+
+impl Utxos {
+    /// Decode a `utxos` message, returning `None` if the message has a different command.
+    pub fn from_message(message: &NetworkMessage) -> Option<Result<Self, Error>> {
+        match message {
+            NetworkMessage::Unknown { command, payload } if command.to_string() == "utxos" => Some(
+                consensus::deserialize(payload).map_err(|source| Error::Deserialize {
+                    command: Some(command.clone()),
+                    source,
+                }),
+            ),
+            _ => None,
+        }
+    }
+
+    /// If the outpoint at the index of the request is unspent.
+    pub fn is_unspent(&self, index: usize) -> bool {
+        self.bitmap
+            .get(index / 8)
+            .is_some_and(|byte| byte & (1 << (index % 8)) != 0)
+    }
+}
+
+impl Decodable for Utxos {
+    fn consensus_decode<R: bitcoin::io::BufRead + ?Sized>(
+        reader: &mut R,
+    ) -> Result<Self, consensus::encode::Error> {
+        let chain_height = u32::consensus_decode(reader)?;
+        let chain_tip = BlockHash::consensus_decode(reader)?;
+        let bitmap = Vec::<u8>::consensus_decode(reader)?;
+        let VarInt(len) = VarInt::consensus_decode(reader)?;
+        // Each output is at least 17 bytes, so the bitmap bounds a legitimate count
+        let max_outputs = bitmap.len() as u64 * 8;
+        if len > max_outputs {
+            return Err(consensus::encode::Error::ParseFailed(
+                "more outputs than requested outpoints",
+            ));
+        }
+        let mut outputs = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            outputs.push(Utxo {
+                tx_version: u32::consensus_decode(reader)?,
+                height: u32::consensus_decode(reader)?,
+                output: TxOut::consensus_decode(reader)?,
+            });
+        }
+        Ok(Self {
+            chain_height,
+            chain_tip,
+            bitmap,
+            outputs,
+        })
+    }
+}
+
+/// An unspent output reported in a `utxos` message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Utxo {
+    /// The version of the transaction that created the output.
+    pub tx_version: u32,
+    /// The height of the block that created the output, or `0x7FFFFFFF` if in the mempool.
+    pub height: u32,
+    /// The output itself.
+    pub output: TxOut,
+}
+
+/// Cumulative records of peers across many connections. Connections to the same peer are
+/// identified by address, as a [`PeerId`] is unique to a single connection.
+#[derive(Debug, Clone, Default)]
+pub struct PeerHistory {
+    peers: HashMap<SocketAddr, PeerRecord>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct PeerRecord {
+    uptime: Duration,
+    connections: u32,
+    disconnects: u32,
+}
+
+impl PeerHistory {
+    /// Construct an empty history.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a new connection to a peer.
+    pub fn connected(&mut self, peer: SocketAddr) {
+        self.peers.entry(peer).or_default().connections += 1;
+    }
+
+    /// Record a connection to a peer ending after it remained open for `uptime`. See
+    /// [`ConnectionMetrics::connection_time`].
+    pub fn disconnected(&mut self, peer: SocketAddr, uptime: Duration) {
+        let record = self.peers.entry(peer).or_default();
+        record.uptime += uptime;
+        record.disconnects += 1;
+    }
+
+    /// The sum of the time spent connected to this peer over all connections.
+    pub fn total_uptime(&self, peer: &SocketAddr) -> Duration {
+        self.peers
+            .get(peer)
+            .map(|record| record.uptime)
+            .unwrap_or_default()
+    }
+
+    /// The number of times a connection to this peer has ended.
+    pub fn disconnect_count(&self, peer: &SocketAddr) -> u32 {
+        self.peers
+            .get(peer)
+            .map(|record| record.disconnects)
+            .unwrap_or_default()
+    }
+
+    /// The number of times a connection was made to this peer after the first.
+    pub fn reconnect_count(&self, peer: &SocketAddr) -> u32 {
+        self.peers
+            .get(peer)
+            .map(|record| record.connections.saturating_sub(1))
+            .unwrap_or_default()
+    }
+}
+
+/// Counts of the transports used by connections across a node, to learn what fraction of peers
+/// support the encrypted v2 transport before requiring it. Each connection is recorded once, after
+/// its handshake completes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransportStats {
+    v1: u64,
+    v2: u64,
+    v1_fallback: u64,
+}
+
+impl TransportStats {
+    /// Construct statistics with no connections recorded.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the transport of a connection that completed the handshake.
+    pub fn record(&mut self, metrics: &ConnectionMetrics) {
+        match metrics.transport() {
+            TransportKind::V1 => self.v1 += 1,
+            TransportKind::V2 => self.v2 += 1,
+            TransportKind::V1Fallback => self.v1_fallback += 1,
+        }
+    }
+
+    /// The number of connections recorded with the transport.
+    pub fn count(&self, kind: TransportKind) -> u64 {
+        match kind {
+            TransportKind::V1 => self.v1,
+            TransportKind::V2 => self.v2,
+            TransportKind::V1Fallback => self.v1_fallback,
+        }
+    }
+
+    /// The number of connections recorded.
+    pub fn total(&self) -> u64 {
+        self.v1 + self.v2 + self.v1_fallback
+    }
+
+    /// The fraction of recorded connections that used the transport, or `None` if no connections
+    /// were recorded.
+    pub fn ratio(&self, kind: TransportKind) -> Option<f64> {
+        let total = self.total();
+        if total == 0 {
+            return None;
+        }
+        Some(self.count(kind) as f64 / total as f64)
+    }
+
+    /// The fraction of recorded connections that used the encrypted v2 transport, or `None` if no
+    /// connections were recorded.
+    pub fn encrypted_ratio(&self) -> Option<f64> {
+        self.ratio(TransportKind::V2)
+    }
+}
+
+// A full `headers` message implies the peer has more headers to send
+const MAX_HEADERS_PER_MESSAGE: usize = 2_000;
+
+/// Track outstanding `getheaders` requests during header sync, so a peer that never responds, not
+/// even with an empty `headers` message, can be replaced. The timeout is reset each time a full
+/// batch of headers arrives, as more headers are expected to follow.
+#[derive(Debug, Clone, Copy)]
+pub struct HeadersTracker {
+    timeout: Duration,
+    waiting_since: Option<Instant>,
+}
+
+impl HeadersTracker {
+    /// Expect a response within `timeout` of each request.
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            waiting_since: None,
+        }
+    }
+
+    /// Record that a `getheaders` message was sent. An earlier outstanding request keeps its
+    /// original start time.
+    pub fn requested(&mut self, now: Instant) {
+        self.waiting_since.get_or_insert(now);
+    }
+
+    /// Record a `headers` message from the peer. A full batch resets the timeout, while a partial
+    /// or empty batch completes the sync with this peer.
+    pub fn received(&mut self, headers: &[Header], now: Instant) {
+        self.waiting_since = (headers.len() >= MAX_HEADERS_PER_MESSAGE).then_some(now);
+    }
+
+    /// A response to the most recent `getheaders` is still expected.
+    pub fn is_waiting(&self) -> bool {
+        self.waiting_since.is_some()
+    }
+
+    /// Returns [`Error::HeadersTimeout`] if the peer has not responded within the timeout.
+    pub fn check(&self, now: Instant) -> Result<(), Error> {
+        match self.waiting_since {
+            Some(then) if now.saturating_duration_since(then) > self.timeout => {
+                Err(Error::HeadersTimeout)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Schedule `getaddr` requests across peers on a rotation, so addresses stay fresh over long runs
+/// without querying any single peer more than once per interval. Peers are identified by address,
+/// so reconnecting to a peer does not allow it to be queried again early.
+#[derive(Debug, Clone)]
+pub struct AddrRefresher {
+    interval: Duration,
+    last_request: HashMap<SocketAddr, Instant>,
+}
+
+impl AddrRefresher {
+    /// Query each peer at most once per `interval`. See [`GETADDR_INTERVAL`].
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_request: HashMap::new(),
+        }
+    }
+
+    /// Choose the next peer to send `getaddr` to, recording the request. Peers that were never
+    /// queried come first, followed by the peer queried longest ago. Returns `None` if every peer
+    /// was queried within the interval.
+    pub fn next(
+        &mut self,
+        peers: impl IntoIterator<Item = SocketAddr>,
+        now: Instant,
+    ) -> Option<SocketAddr> {
+        let addr = peers
+            .into_iter()
+            .filter(|addr| {
+                self.last_request
+                    .get(addr)
+                    .is_none_or(|then| now.saturating_duration_since(*then) >= self.interval)
+            })
+            .min_by_key(|addr| self.last_request.get(addr).copied())?;
+        self.last_request.insert(addr, now);
+        Some(addr)
+    }
+}
+
+impl Default for AddrRefresher {
+    fn default() -> Self {
+        Self::new(GETADDR_INTERVAL)
+    }
+}
+
+/// Throttle connection attempts to addresses that recently failed. Each consecutive failure
+/// doubles the time before the address may be attempted again, up to a maximum. Failures are
+/// forgotten over time, one for each maximum backoff that passes without another failure.
+#[derive(Debug, Clone)]
+pub struct ConnectAttemptTracker {
+    base_backoff: Duration,
+    max_backoff: Duration,
+    failures: HashMap<SocketAddr, FailedAttempts>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FailedAttempts {
+    count: u32,
+    last: Instant,
+}
+
+impl ConnectAttemptTracker {
+    /// Wait at least `base_backoff` after the first failure, and at most `max_backoff` after any
+    /// number of failures.
+    pub fn new(base_backoff: Duration, max_backoff: Duration) -> Self {
+        Self {
+            base_backoff,
+            max_backoff,
+            failures: HashMap::new(),
+        }
+    }
+
+    /// Record a failed connection attempt.
+    pub fn failed(&mut self, addr: SocketAddr, now: Instant) {
+        let count = self.recent_failures(&addr, now) + 1;
+        self.failures
+            .insert(addr, FailedAttempts { count, last: now });
+    }
+
+    /// Record a successful connection, forgetting previous failures.
+    pub fn succeeded(&mut self, addr: SocketAddr) {
+        self.failures.remove(&addr);
+    }
+
+    /// Has enough time passed since the last failure to attempt the address again.
+    pub fn should_attempt(&self, addr: SocketAddr, now: Instant) -> bool {
+        let Some(attempts) = self.failures.get(&addr) else {
+            return true;
+        };
+        let exponent = self.recent_failures(&addr, now).saturating_sub(1).min(31);
+        let backoff = self
+            .base_backoff
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff);
+        now.saturating_duration_since(attempts.last) >= backoff
+    }
+
+    // The number of failures, less one for each maximum backoff since the last failure
+    fn recent_failures(&self, addr: &SocketAddr, now: Instant) -> u32 {
+        let Some(attempts) = self.failures.get(addr) else {
+            return 0;
+        };
+        let elapsed = now.saturating_duration_since(attempts.last);
+        let decayed = elapsed.as_secs_f64() / self.max_backoff.as_secs_f64().max(f64::EPSILON);
+        attempts.count.saturating_sub(decayed as u32)
+    }
+}
+
+impl Default for ConnectAttemptTracker {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1), Duration::from_secs(10 * 60))
+    }
+}
+
+// The most connections a `PeerManager` opens at once by default.
+const MAX_CONCURRENT_DIALS: usize = 8;
+// The most potential peers a `PeerManager` remembers by default.
+const MAX_CANDIDATES: usize = 1_000;
+// The rate a peer's allowance of gossiped addresses refills, as in Bitcoin Core.
+const ADDR_GOSSIP_PER_SECOND: f64 = 0.1;
+
+// Limit the addresses a single peer may add to the potential peers. The allowance starts at one
+// full `addr` message, refills slowly up to that size, and grows when we request addresses.
+#[derive(Debug, Clone, Copy)]
+struct AddrBudget {
+    tokens: f64,
+    max: f64,
+    updated: Instant,
+}
+
+impl AddrBudget {
+    fn new(max: usize, now: Instant) -> Self {
+        Self {
+            tokens: max as f64,
+            max: max as f64,
+            updated: now,
+        }
+    }
+
+    fn requested(&mut self) {
+        self.tokens += self.max;
+    }
+
+    // The number of the `wanted` addresses that may be accepted.
+    fn take(&mut self, wanted: usize, now: Instant) -> usize {
+        if self.tokens < self.max {
+            let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * ADDR_GOSSIP_PER_SECOND).min(self.max);
+        }
+        self.updated = now;
+        let allowed = (self.tokens.floor() as usize).min(wanted);
+        self.tokens -= allowed as f64;
+        allowed
+    }
+}
+
+/// The reason a connection was opened, which determines how it is maintained.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConnectionKind {
+    /// A peer chosen by the user, which is always reconnected and never evicted.
+    Manual,
+    /// A peer chosen from the known addresses, which may be evicted or replaced.
+    Automatic,
+    /// A short-lived connection to test that an address is reachable.
+    Feeler,
+    /// A connection that only relays blocks, see [`ConnectionConfig::block_relay_only`].
+    BlockRelayOnly,
+}
+
+/// Maintain a target number of outbound connections, replacing peers as connections end.
+/// Addresses to connect to are supplied with [`PeerManager::add_addresses`], and at most one
+/// automatic peer is chosen from each network group. Messages from every peer are collected with
+/// [`PeerManager::try_recv_message`].
+#[derive(Debug)]
+pub struct PeerManager {
+    config: ConnectionConfig,
+    timeout_params: TimeoutParams,
+    target: usize,
+    block_relay_target: usize,
+    max_concurrent_dials: usize,
+    max_candidates: usize,
+    candidates: VecDeque<SocketAddr>,
+    known_candidates: HashSet<SocketAddr>,
+    manual: Vec<SocketAddr>,
+    peers: HashMap<PeerId, ManagedPeer>,
+    history: PeerHistory,
+    transport_stats: TransportStats,
+    addr_refresher: AddrRefresher,
+    attempts: ConnectAttemptTracker,
+    sender: mpsc::Sender<(PeerId, NetworkMessage)>,
+    receiver: mpsc::Receiver<(PeerId, NetworkMessage)>,
+}
+
+#[derive(Debug)]
+struct ManagedPeer {
+    addr: SocketAddr,
+    kind: ConnectionKind,
+    writer: ConnectionWriter,
+    metrics: ConnectionMetrics,
+    // Closed directly, as the writer may have stopped before it could close the stream
+    stream: TcpStream,
+    read_handle: JoinHandle<Error>,
+    addr_budget: AddrBudget,
+}
+
+impl PeerManager {
+    /// Open automatic connections with this configuration until there are `target` peers.
+    pub fn new(config: ConnectionConfig, timeout_params: TimeoutParams, target: usize) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            config,
+            timeout_params,
+            target,
+            block_relay_target: 0,
+            max_concurrent_dials: MAX_CONCURRENT_DIALS,
+            max_candidates: MAX_CANDIDATES,
+            candidates: VecDeque::new(),
+            known_candidates: HashSet::new(),
+            manual: Vec::new(),
+            peers: HashMap::new(),
+            history: PeerHistory::new(),
+            transport_stats: TransportStats::new(),
+            addr_refresher: AddrRefresher::default(),
+            attempts: ConnectAttemptTracker::default(),
+            sender,
+            receiver,
+        }
+    }
+
+    /// Open block-relay-only connections until there are `target` of them, in addition to the
+    /// automatic connections.
+    pub fn block_relay_target(mut self, target: usize) -> Self {
+        self.block_relay_target = target;
+        self
+    }
+
+    /// Open at most this many connections at once while reaching the targets. Defaults to 8.
+    pub fn max_concurrent_dials(mut self, max: usize) -> Self {
+        self.max_concurrent_dials = max.max(1);
+        self
+    }
+
+    /// Remember at most this many potential peers. Addresses added beyond the limit are ignored.
+    /// Defaults to 1000.
+    pub fn max_candidates(mut self, max: usize) -> Self {
+        self.max_candidates = max;
+        self
+    }
+
+    /// Add potential peers to connect to. Addresses that are already known are ignored, as are
+    /// addresses beyond [`PeerManager::max_candidates`].
+    pub fn add_addresses(&mut self, addrs: impl IntoIterator<Item = SocketAddr>) {
+        for addr in addrs {
+            self.push_candidate(addr);
+        }
+    }
+
+    /// Always keep a connection to this peer. Manual peers do not count towards the target.
+    pub fn add_manual_peer(&mut self, addr: SocketAddr) {
+        if !self.manual.contains(&addr) {
+            self.manual.push(addr);
+        }
+    }
+
+    /// Replace the tracker used to throttle connection attempts to addresses that failed.
+    pub fn attempt_tracker(mut self, tracker: ConnectAttemptTracker) -> Self {
+        self.attempts = tracker;
+        self
+    }
+
+    /// Remove peers whose connection has ended, then reconnect to manual peers and open
+    /// connections until the targets are reached or no suitable addresses remain. Connections are
+    /// opened concurrently, see [`PeerManager::max_concurrent_dials`], so an unresponsive address
+    /// delays this call by at most one handshake timeout. Addresses that recently failed are
+    /// skipped, see [`ConnectAttemptTracker`]. Peers that disconnect are tried again later.
+    /// Returns the reasons connections ended or could not be opened. Intended to be called
+    /// periodically.
+    pub fn maintain(&mut self) -> Vec<Error> {
+        let mut errors = Vec::new();
+        let finished: Vec<PeerId> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.read_handle.is_finished())
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        for peer_id in finished {
+            if let Some(error) = self.remove(peer_id) {
+                errors.push(error);
+            }
+        }
+        let connected: HashSet<SocketAddr> = self.peers.values().map(|peer| peer.addr).collect();
+        let now = Instant::now();
+        let manual: Vec<(SocketAddr, ConnectionKind)> = self
+            .manual
+            .iter()
+            .filter(|addr| !connected.contains(addr) && self.attempts.should_attempt(**addr, now))
+            .map(|addr| (*addr, ConnectionKind::Manual))
+            .collect();
+        for wave in manual.chunks(self.max_concurrent_dials) {
+            for (_, result) in self.connect_all(wave.to_vec()) {
+                if let Err(e) = result {
+                    errors.push(e);
+                }
+            }
+        }
+        let mut groups: HashSet<Vec<u8>> = self
+            .peers
+            .values()
+            .filter(|peer| peer.kind != ConnectionKind::Manual)
+            .map(|peer| net_group(peer.addr.ip()))
+            .collect();
+        let mut skipped = Vec::new();
+        for (kind, target) in [
+            (ConnectionKind::Automatic, self.target),
+            (ConnectionKind::BlockRelayOnly, self.block_relay_target),
+        ] {
+            loop {
+                let needed = target
+                    .saturating_sub(self.count(kind))
+                    .min(self.max_concurrent_dials);
+                let mut wave = Vec::new();
+                while wave.len() < needed {
+                    let Some(addr) = self.pop_candidate() else {
+                        break;
+                    };
+                    let group = net_group(addr.ip());
+                    if groups.contains(&group)
+                        || self.manual.contains(&addr)
+                        || !self.attempts.should_attempt(addr, now)
+                    {
+                        skipped.push(addr);
+                        continue;
+                    }
+                    groups.insert(group);
+                    wave.push((addr, kind));
+                }
+                if wave.is_empty() {
+                    break;
+                }
+                for (addr, result) in self.connect_all(wave) {
+                    if let Err(e) = result {
+                        // Another address in the group may be tried instead
+                        groups.remove(&net_group(addr.ip()));
+                        errors.push(e);
+                        skipped.push(addr);
+                    }
+                }
+            }
+        }
+        self.add_addresses(skipped);
+        errors
+    }
+
+    /// Disconnect the automatic or block-relay-only peer that offers the fewest useful services,
+    /// preferring to keep peers that have been connected longer. Manual peers are never evicted.
+    pub fn evict(&mut self) -> Option<PeerId> {
+        let now = Instant::now();
+        let peer_id = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| {
+                matches!(
+                    peer.kind,
+                    ConnectionKind::Automatic | ConnectionKind::BlockRelayOnly
+                )
+            })
+            .min_by_key(|(_, peer)| {
+                (
+                    peer.metrics.feeler_data().service_score(),
+                    peer.metrics.connection_time(now),
+                )
+            })
+            .map(|(peer_id, _)| *peer_id)?;
+        self.remove(peer_id);
+        Some(peer_id)
+    }
+
+    /// Open a feeler connection to test that the address is reachable, closing it once the
+    /// handshake completes. Reachable addresses are added to the potential peers.
+    pub fn feeler(&mut self, addr: SocketAddr) -> Result<FeelerData, Error> {
+        let tcp_stream = TcpStream::connect_timeout(&addr, self.timeout_params.tcp)?;
+        configure_stream(&self.config, &tcp_stream, &self.timeout_params)?;
+        self.history.connected(addr);
+        let feeler = self.config.clone().probe(tcp_stream, self.timeout_params);
+        self.history.disconnected(addr, Duration::ZERO);
+        let feeler = feeler?;
+        self.add_addresses([addr]);
+        Ok(feeler)
+    }
+
+    fn push_candidate(&mut self, addr: SocketAddr) {
+        if self.candidates.len() < self.max_candidates && self.known_candidates.insert(addr) {
+            self.candidates.push_back(addr);
+        }
+    }
+
+    fn pop_candidate(&mut self) -> Option<SocketAddr> {
+        let addr = self.candidates.pop_front()?;
+        self.known_candidates.remove(&addr);
+        Some(addr)
+    }
+
+    fn count(&self, kind: ConnectionKind) -> usize {
+        self.peers.values().filter(|peer| peer.kind == kind).count()
+    }
+
+    fn remove(&mut self, peer_id: PeerId) -> Option<Error> {
+        let peer = self.peers.remove(&peer_id)?;
+        self.history
+            .disconnected(peer.addr, peer.metrics.connection_time(Instant::now()));
+        let _ = peer.writer.shutdown();
+        let _ = peer.stream.close();
+        if peer.kind != ConnectionKind::Manual {
+            self.push_candidate(peer.addr);
+        }
+        peer.read_handle.join().ok()
+    }
+
+    // Open a connection to each address on its own thread, waiting for every handshake to end.
+    fn connect_all(
+        &mut self,
+        addrs: Vec<(SocketAddr, ConnectionKind)>,
+    ) -> Vec<(SocketAddr, Result<(), Error>)> {
+        let results: Vec<_> = std::thread::scope(|scope| {
+            let handles: Vec<_> = addrs
+                .into_iter()
+                .map(|(addr, kind)| {
+                    let config = match kind {
+                        ConnectionKind::BlockRelayOnly => self.config.clone().block_relay_only(),
+                        _ => self.config.clone(),
+                    };
+                    let timeout_params = self.timeout_params;
+                    let handle = scope.spawn(move || config.open_connection(addr, timeout_params));
+                    (addr, kind, handle)
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|(addr, kind, handle)| {
+                    let result = handle.join().unwrap_or_else(|_| {
+                        Err(Error::Io(io::Error::other("connection thread panicked")))
+                    });
+                    (addr, kind, result)
+                })
+                .collect()
+        });
+        results
+            .into_iter()
+            .map(|(addr, kind, result)| (addr, self.add_peer(addr, kind, result)))
+            .collect()
+    }
+
+    fn add_peer(
+        &mut self,
+        addr: SocketAddr,
+        kind: ConnectionKind,
+        connection: Result<Connection, Error>,
+    ) -> Result<(), Error> {
+        let (writer, mut reader, metrics) = match connection {
+            Ok(connection) => connection,
+            Err(e) => {
+                self.attempts.failed(addr, Instant::now());
+                return Err(e);
+            }
+        };
+        let stream = match reader.stream.get_ref().try_clone() {
+            Ok(stream) => stream,
+            Err(e) => {
+                let _ = writer.shutdown();
+                return Err(Error::Io(e));
+            }
+        };
+        self.attempts.succeeded(addr);
+        self.transport_stats.record(&metrics);
+        let peer_id = writer.peer_id();
+        let sender = self.sender.clone();
+        let read_handle = std::thread::spawn(move || loop {
+            match reader.read_message() {
+                Ok(Some(message)) => {
+                    if sender.send((peer_id, message)).is_err() {
+                        break Error::ChannelClosed;
+                    }
+                }
+                Ok(None) => continue,
+                Err(e) => break e,
+            }
+        });
+        self.history.connected(addr);
+        let addr_budget = AddrBudget::new(self.config.max_addr_per_message, Instant::now());
+        self.peers.insert(
+            peer_id,
+            ManagedPeer {
+                addr,
+                kind,
+                writer,
+                metrics,
+                stream,
+                read_handle,
+                addr_budget,
+            },
+        );
+        Ok(())
+    }
+
+    /// The next message received from any peer, if one is available. Addresses gossiped by
+    /// peers are added as potential peers before the message is returned. Messages with more than
+    /// [`ConnectionConfig::max_addr_per_message`] addresses are not used, and each peer may only
+    /// add that many addresses before its allowance slowly refills, or we request addresses from
+    /// it with [`PeerManager::refresh_addresses`].
+    pub fn try_recv_message(&mut self) -> Option<(PeerId, NetworkMessage)> {
+        let (peer_id, message) = self.receiver.try_recv().ok()?;
+        let mut addrs: Vec<SocketAddr> = match &message {
+            NetworkMessage::Addr(addrs) if addrs.0.len() <= self.config.max_addr_per_message => {
+                addrs
+                    .0
+                    .iter()
+                    .filter_map(|(_, addr)| addr.socket_addr().ok())
+                    .collect()
+            }
+            NetworkMessage::AddrV2(addrs) if addrs.0.len() <= self.config.max_addr_per_message => {
+                addrs
+                    .0
+                    .iter()
+                    .filter_map(|addr| addr.socket_addr().ok())
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+        if !addrs.is_empty() {
+            let allowed = self
+                .peers
+                .get_mut(&peer_id)
+                .map_or(0, |peer| peer.addr_budget.take(addrs.len(), Instant::now()));
+            addrs.truncate(allowed);
+            self.add_addresses(addrs);
+        }
+        Some((peer_id, message))
+    }
+
+    /// Send `getaddr` to the next peer on the rotation, if any peer is due. The addresses it
+    /// responds with are added by [`PeerManager::try_recv_message`]. Intended to be called
+    /// periodically, along with [`PeerManager::maintain`].
+    pub fn refresh_addresses(&mut self) -> Option<PeerId> {
+        let addr = self
+            .addr_refresher
+            .next(self.peers.values().map(|peer| peer.addr), Instant::now())?;
+        let (peer_id, peer) = self.peers.iter_mut().find(|(_, peer)| peer.addr == addr)?;
+        peer.writer.send_message(NetworkMessage::GetAddr).ok()?;
+        peer.addr_budget.requested();
+        Some(*peer_id)
+    }
+
+    /// The peers that are currently connected, with their address and connection metrics.
+    pub fn peers(&self) -> impl Iterator<Item = (PeerId, SocketAddr, &ConnectionMetrics)> {
+        self.peers
+            .iter()
+            .map(|(peer_id, peer)| (*peer_id, peer.addr, &peer.metrics))
+    }
+
+    /// The reason the connection to a peer was opened.
+    pub fn connection_kind(&self, peer_id: PeerId) -> Option<ConnectionKind> {
+        self.peers.get(&peer_id).map(|peer| peer.kind)
+    }
+
+    /// The writer for a connected peer.
+    pub fn writer(&self, peer_id: PeerId) -> Option<&ConnectionWriter> {
+        self.peers.get(&peer_id).map(|peer| &peer.writer)
+    }
+
+    /// The records of every peer this manager has connected to.
+    pub fn history(&self) -> &PeerHistory {
+        &self.history
+    }
+
+    /// The transports used by every connection this manager has opened.
+    pub fn transport_stats(&self) -> &TransportStats {
+        &self.transport_stats
+    }
+
+    /// Query the DNS seeds for more potential peers if fewer than `min` are known. See
+    /// [`bootstrap_addresses`].
+    pub fn bootstrap(&mut self, min: usize, resolver: &impl Resolver) {
+        let known: Vec<SocketAddr> = self.candidates.iter().copied().collect();
+        let addrs = bootstrap_addresses(&known, self.config.network(), min, resolver);
+        self.add_addresses(addrs);
+    }
+}
+
+/// Open outbound connections to the addresses until `target` connections are open, with at most
+/// `max_concurrent` handshakes in flight at once. The addresses are attempted in waves, so
+/// connecting to a long list of addresses does not exhaust file descriptors. Connections that
+/// complete beyond the target in the final wave are dropped. Returns the open connections with
+/// their addresses, and the errors of the failed attempts.
+pub fn open_any(
+    config: &ConnectionConfig,
+    addrs: impl IntoIterator<Item = SocketAddr>,
+    timeout_params: TimeoutParams,
+    target: usize,
+    max_concurrent: usize,
+) -> (Vec<(SocketAddr, Connection)>, Vec<Error>) {
+    let mut connections = Vec::new();
+    let mut errors = Vec::new();
+    let mut addrs = addrs.into_iter().peekable();
+    while connections.len() < target && addrs.peek().is_some() {
+        let wave: Vec<SocketAddr> = addrs.by_ref().take(max_concurrent.max(1)).collect();
+        let results: Vec<(SocketAddr, Result<Connection, Error>)> = std::thread::scope(|scope| {
+            let handles: Vec<_> = wave
+                .into_iter()
+                .map(|addr| {
+                    let config = config.clone();
+                    let handle = scope.spawn(move || config.open_connection(addr, timeout_params));
+                    (addr, handle)
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|(addr, handle)| {
+                    let result = handle.join().unwrap_or_else(|_| {
+                        Err(Error::Io(io::Error::other("connection thread panicked")))
+                    });
+                    (addr, result)
+                })
+                .collect()
+        });
+        for (addr, result) in results {
+            match result {
+                Ok(connection) => connections.push((addr, connection)),
+                Err(e) => errors.push(e),
+            }
+        }
+    }
+    connections.truncate(target);
+    (connections, errors)
+}
+
+/// Learn which network a peer is on by requesting the genesis block of every known network and
+/// comparing the block it returns. Unlike the message magic, the peer cannot misrepresent the
+/// genesis block. Returns `None` if no genesis block arrives within the timeout.
+pub fn detect_network_by_genesis<R: Read + StreamControl>(
+    connection: &mut Connection<R>,
+    timeout: Duration,
+) -> Option<Network> {
+    let (writer, reader, _) = connection;
+    let genesis_hashes: Vec<(Network, BlockHash)> = [
+        Network::Bitcoin,
+        Network::Testnet(TestnetVersion::V3),
+        Network::Testnet(TestnetVersion::V4),
+        Network::Signet,
+        Network::Regtest,
+    ]
+    .into_iter()
+    .map(|network| (network, genesis_block(network).block_hash()))
+    .collect();
+    let inventory = genesis_hashes
+        .iter()
+        .map(|(_, hash)| Inventory::Block(*hash))
+        .collect();
+    writer
+        .send_message(NetworkMessage::GetData(InventoryPayload(inventory)))
+        .ok()?;
+    let is_genesis = |message: &NetworkMessage| match message {
+        NetworkMessage::Block(block) => {
+            let hash = block.block_hash();
+            genesis_hashes.iter().any(|(_, genesis)| *genesis == hash)
+        }
+        _ => false,
+    };
+    let NetworkMessage::Block(block) = reader.wait_for(is_genesis, timeout).ok()? else {
+        return None;
+    };
+    let hash = block.block_hash();
+    genesis_hashes
+        .into_iter()
+        .find(|(_, genesis)| *genesis == hash)
+        .map(|(network, _)| network)
+}
+
+/// Addresses to connect to at startup. The known addresses are returned as-is if there are at
+/// least `min` of them. Otherwise, the DNS seeds for the network are queried and their results
+/// are added, so seeds are only a fallback for a sparse address book.
+pub fn bootstrap_addresses(
+    known: &[SocketAddr],
+    network: Network,
+    min: usize,
+    resolver: &impl Resolver,
+) -> Vec<SocketAddr> {
+    let mut addrs = known.to_vec();
+    if addrs.len() >= min {
+        return addrs;
+    }
+    let port = dns::default_port(network);
+    for ip in dns::resolve_seeds(network, resolver) {
+        let addr = SocketAddr::new(ip, port);
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+    addrs
+}
+
+/// Replace the implausible timestamps of received addresses, those in 1973 or before or more
+/// than ten minutes in the future, with a time five days before the given time since the Unix
+/// epoch. Bitcoin Core does the same before storing addresses.
+pub fn adjust_addr_timestamps<A>(received: &mut [(A, u32)], now: Duration) {
+    let now = now.as_secs();
+    let penalized = now.saturating_sub(ADDR_TIME_PENALTY) as u32;
+    for (_, time) in received.iter_mut() {
+        if *time <= MIN_ADDR_TIME || u64::from(*time) > now + ADDR_RELAY_WINDOW {
+            *time = penalized;
+        }
+    }
+}
+
+/// Select the received addresses, with their timestamps in seconds since the Unix epoch, that
+/// should be relayed to other peers at the given time since the Unix epoch. Like Bitcoin Core,
+/// nothing is relayed from messages with more than [`MAX_ADDR_RELAY`] addresses, timestamps are
+/// adjusted by [`adjust_addr_timestamps`], and only addresses seen in the last ten minutes are
+/// relayed. Works for both `addr` and `addrv2` entries.
+pub fn prepare_addr_relay<A: Clone>(received: &[(A, u32)], now: Duration) -> Vec<(A, u32)> {
+    if received.len() > MAX_ADDR_RELAY {
+        return Vec::new();
+    }
+    let mut received = received.to_vec();
+    adjust_addr_timestamps(&mut received, now);
+    let now = now.as_secs();
+    received
+        .into_iter()
+        .filter(|(_, time)| u64::from(*time) + ADDR_RELAY_WINDOW > now)
+        .collect()
+}
+
+// Peers in the same network group are likely operated by the same entity
+fn net_group(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets()[..2].to_vec(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip.octets()[..2].to_vec(),
+            None => ip.octets()[..4].to_vec(),
+        },
+    }
+}
+
+/// Collect the block hashes announced in an inventory, as sent in response to a `GetBlocks`
+/// request or when a peer announces new blocks by `Inv`.
+pub fn inventory_block_hashes(inventory: &[Inventory]) -> Vec<BlockHash> {
+    inventory
+        .iter()
+        .filter_map(|inv| match inv {
+            Inventory::Block(hash)
+            | Inventory::WitnessBlock(hash)
+            | Inventory::CompactBlock(hash) => Some(*hash),
+            _ => None,
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+enum WriteTransport {
+    V1(Magic),
+    V2 { magic: Magic, cipher: PacketWriter },
+}
+
+impl WriteTransport {
+    fn write_message<W: Write>(
+        &mut self,
+        network_message: NetworkMessage,
+        writer: &mut W,
+    ) -> Result<(), io::Error> {
+        let bytes = self.encode_message(network_message);
+        writer.write_all(&bytes)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn encode_message(&mut self, network_message: NetworkMessage) -> Vec<u8> {
+        match self {
+            WriteTransport::V1(magic) => {
+                let raw = RawNetworkMessage::new(*magic, network_message);
+                consensus::serialize(&raw)
+            }
+            WriteTransport::V2 { magic, cipher } => {
+                let contents = encode_v2_contents(*magic, network_message);
+                cipher
+                    .encrypt_packet(&contents, None, PacketType::Genuine)
+                    .expect("packets are allocated to fit their contents")
+            }
+        }
+    }
+}
+
+/// The checksum of a message payload, which is the first four bytes of the double-SHA256 of the
+/// payload.
+pub fn message_checksum(payload: &[u8]) -> [u8; 4] {
+    let hash = sha256d::Hash::hash(payload).to_byte_array();
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+/// Serialize a message as it would appear on the wire, encoded as hex. Useful for sharing a
+/// message when reporting a bug.
+pub fn message_to_hex(magic: Magic, network_message: &NetworkMessage) -> String {
+    let raw = RawNetworkMessage::new(magic, network_message.clone());
+    consensus::serialize(&raw)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Decode a message from hex, as produced by [`message_to_hex`]. The message header and checksum
+/// are validated.
+pub fn message_from_hex(hex: &str) -> Result<NetworkMessage, Error> {
+    let hex = hex.trim();
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return Err(Error::InvalidHex);
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| Error::InvalidHex))
+        .collect::<Result<Vec<u8>, Error>>()?;
+    let message = consensus::deserialize::<RawNetworkMessage>(&bytes)?;
+    Ok(message.into_payload())
+}
+
+/// A pool of message buffers that may be shared by many readers to avoid repeated allocations.
+#[derive(Debug, Clone)]
+pub struct BufferPool {
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>,
+    max_buffer_size: usize,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    /// Keep up to `max_buffers` buffers of at most `max_buffer_size` bytes. Messages larger than
+    /// `max_buffer_size` are allocated separately and are not returned to the pool.
+    pub fn new(max_buffer_size: usize, max_buffers: usize) -> Self {
+        Self {
+            buffers: Arc::new(Mutex::new(Vec::with_capacity(max_buffers))),
+            max_buffer_size,
+            max_buffers,
+        }
+    }
+
+    /// The number of buffers currently available in the pool.
+    pub fn available(&self) -> usize {
+        self.buffers.lock().map(|lock| lock.len()).unwrap_or(0)
+    }
+
+    fn take(&self, len: usize) -> Vec<u8> {
+        if len <= self.max_buffer_size {
+            if let Some(mut buf) = self.buffers.lock().ok().and_then(|mut lock| lock.pop()) {
+                buf.clear();
+                buf.resize(len, 0);
+                return buf;
+            }
+        }
+        vec![0; len]
+    }
+
+    fn give(&self, buf: Vec<u8>) {
+        if buf.capacity() > self.max_buffer_size {
+            return;
+        }
+        if let Ok(mut lock) = self.buffers.lock() {
+            if lock.len() < self.max_buffers {
+                lock.push(buf);
+            }
+        }
+    }
+}
+
+/// A limit on the memory used to read messages, which may be shared by many readers to bound the
+/// memory used by all connections.
+#[derive(Debug, Clone)]
+pub struct ReadMemoryBudget {
+    used: Arc<Mutex<usize>>,
+    limit: usize,
+}
+
+impl ReadMemoryBudget {
+    /// Allow up to `limit` bytes of messages to be read at once.
+    pub fn new(limit: usize) -> Self {
+        Self {
+            used: Arc::new(Mutex::new(0)),
+            limit,
+        }
+    }
+
+    /// The number of bytes currently reserved by readers.
+    pub fn in_use(&self) -> usize {
+        self.used.lock().map(|lock| *lock).unwrap_or(0)
+    }
+
+    fn reserve(&self, len: usize) -> Result<Reservation, Error> {
+        let mut used = self
+            .used
+            .lock()
+            .map_err(|_| Error::ReadMemoryExhausted(len))?;
+        if used.saturating_add(len) > self.limit {
+            return Err(Error::ReadMemoryExhausted(len));
+        }
+        *used += len;
+        Ok(Reservation {
+            used: Arc::clone(&self.used),
+            len,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Reservation {
+    used: Arc<Mutex<usize>>,
+    len: usize,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if let Ok(mut used) = self.used.lock() {
+            *used = used.saturating_sub(self.len);
+        }
+    }
+}
+
+// Read large messages incrementally so progress may be observed.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The bytes received and total length of a message that is currently being read.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MessageProgress(Arc<Mutex<Option<(usize, usize)>>>);
+
+impl MessageProgress {
+    fn update(&self, progress: Option<(usize, usize)>) {
+        if let Ok(mut lock) = self.0.lock() {
+            *lock = progress;
+        }
+    }
+
+    pub(crate) fn get(&self) -> Option<(usize, usize)> {
+        self.0.lock().ok().and_then(|lock| *lock)
+    }
+}
+
+#[derive(Debug)]
+struct FrameHeader {
+    bytes: [u8; 24],
+    command: CommandString,
+    length: usize,
+    // The bytes read off the wire for the message, including any v2 decoy packets before it
+    wire_len: usize,
+    // The memory budget held for a v2 packet that was read along with the header
+    reservation: Option<Reservation>,
+}
+
+// The bytes of a message received so far by non-blocking reads.
+#[derive(Debug, Default)]
+struct PartialFrame {
+    bytes: Vec<u8>,
+    // The decrypted length of the v2 packet being read, which may only be decrypted once
+    packet_len: Option<usize>,
+    reservation: Option<Reservation>,
+    decoy_len: usize,
+}
+
+impl PartialFrame {
+    fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    // Move the available bytes into the frame until it holds `needed` bytes.
+    fn fill<R: BufRead>(&mut self, reader: &mut R, needed: usize) -> Result<(), Error> {
+        while self.bytes.len() < needed {
+            let available = reader.fill_buf()?;
+            if available.is_empty() {
+                return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+            }
+            let len = available.len().min(needed - self.bytes.len());
+            self.bytes.extend_from_slice(&available[..len]);
+            reader.consume(len);
+        }
+        Ok(())
+    }
+
+    fn reserve(&mut self, budget: Option<&ReadMemoryBudget>, len: usize) -> Result<(), Error> {
+        if let (Some(budget), None) = (budget, &self.reservation) {
+            self.reservation = Some(budget.reserve(len)?);
+        }
+        Ok(())
+    }
+
+    fn take(&mut self) -> Vec<u8> {
+        self.packet_len = None;
+        self.reservation = None;
+        std::mem::take(&mut self.bytes)
+    }
+}
+
+#[derive(Debug)]
+enum ReadTransport {
+    V1(Magic),
+    // Packets are decrypted as a whole, so the frame is kept until the body is read
+    V2 {
+        magic: Magic,
+        cipher: PacketReader,
+        pending: Vec<u8>,
+        #[cfg(feature = "debug-v2-session")]
+        session_id: [u8; 32],
+    },
+}
+
+impl ReadTransport {
+    fn read_message<R: BufRead>(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<Option<NetworkMessage>, Error> {
+        let header = self.read_header(reader, None)?;
+        let command = header.command.clone();
+        let frame = self.read_body(reader, header, None, None)?;
+        let message = self
+            .decode_frame(&frame)
+            .map_err(|source| Error::Deserialize {
+                command: Some(command),
+                source,
+            })?;
+        Ok(Some(message))
+    }
+
+    // Read towards the next message without losing bytes if the read is interrupted, so a message
+    // may arrive over many calls.
+    fn poll_frame<R: BufRead>(
+        &mut self,
+        reader: &mut R,
+        partial: &mut PartialFrame,
+        budget: Option<&ReadMemoryBudget>,
+    ) -> Result<Option<(FrameHeader, Vec<u8>)>, Error> {
+        match self {
+            ReadTransport::V1(magic) => {
+                partial.fill(reader, 24)?;
+                let mut bytes = [0; 24];
+                bytes.copy_from_slice(&partial.bytes[..24]);
+                let message_header = consensus::deserialize::<V1MessageHeader>(&bytes)?;
+                if message_header.magic != *magic {
+                    return Err(Error::UnexpectedMagic(message_header.magic));
+                }
+                let length = message_header.length as usize;
+                if length > MAX_MESSAGE_LEN {
+                    return Err(Error::OversizedMessage(length));
+                }
+                partial.reserve(budget, bytes.len() + length)?;
+                partial.fill(reader, bytes.len() + length)?;
+                let header = FrameHeader {
+                    bytes,
+                    command: message_header.command,
+                    length,
+                    wire_len: bytes.len() + length,
+                    reservation: None,
+                };
+                Ok(Some((header, partial.take())))
+            }
+            ReadTransport::V2 { magic, cipher, .. } => loop {
+                partial.fill(reader, 3)?;
+                let packet_len = match partial.packet_len {
+                    Some(packet_len) => packet_len,
+                    None => {
+                        let mut length_bytes = [0; 3];
+                        length_bytes.copy_from_slice(&partial.bytes[..3]);
+                        let packet_len = cipher.decypher_len(length_bytes);
+                        if packet_len > MAX_V2_PACKET_LEN {
+                            return Err(Error::OversizedMessage(packet_len));
+                        }
+                        partial.packet_len = Some(packet_len);
+                        packet_len
+                    }
+                };
+                partial.reserve(budget, 3 + packet_len)?;
+                partial.fill(reader, 3 + packet_len)?;
+                let packet = partial.take();
+                let payload = cipher
+                    .decrypt_payload(&packet[3..], None)
+                    .map_err(Error::V2Protocol)?;
+                if payload.packet_type() == PacketType::Decoy {
+                    partial.decoy_len += packet.len();
+                    continue;
+                }
+                let frame = v2_contents_to_frame(*magic, payload.contents())?;
+                let mut bytes = [0; 24];
+                bytes.copy_from_slice(&frame[..24]);
+                let message_header = consensus::deserialize::<V1MessageHeader>(&bytes)?;
+                let header = FrameHeader {
+                    bytes,
+                    command: message_header.command,
+                    length: message_header.length as usize,
+                    wire_len: std::mem::take(&mut partial.decoy_len) + packet.len(),
+                    reservation: None,
+                };
+                break Ok(Some((header, frame)));
+            },
+        }
+    }
+
+    // Read the header of the next message. The body of a v1 message is reserved against the
+    // budget when it is read, while a v2 packet is reserved here before it is read as a whole.
+    fn read_header<R: BufRead>(
+        &mut self,
+        reader: &mut R,
+        budget: Option<&ReadMemoryBudget>,
+    ) -> Result<FrameHeader, Error> {
+        match self {
+            ReadTransport::V1(magic) => {
+                let mut bytes = [0; 24];
+                reader.read_exact(&mut bytes)?;
+                let message_header = consensus::deserialize::<V1MessageHeader>(&bytes)?;
+                if message_header.magic != *magic {
+                    return Err(Error::UnexpectedMagic(message_header.magic));
+                }
+                // Will panic on machines with under 32 bit precision
+                let length = message_header.length as usize;
+                if length > MAX_MESSAGE_LEN {
+                    return Err(Error::OversizedMessage(length));
+                }
+                Ok(FrameHeader {
+                    bytes,
+                    command: message_header.command,
+                    length,
+                    wire_len: bytes.len() + length,
+                    reservation: None,
+                })
+            }
+            ReadTransport::V2 {
+                magic,
+                cipher,
+                pending,
+                ..
+            } => {
+                let mut wire_len = 0;
+                loop {
+                    let mut length_bytes = [0; 3];
+                    reader.read_exact(&mut length_bytes)?;
+                    let packet_len = cipher.decypher_len(length_bytes);
+                    if packet_len > MAX_V2_PACKET_LEN {
+                        return Err(Error::OversizedMessage(packet_len));
+                    }
+                    let reservation = match budget {
+                        Some(budget) => Some(budget.reserve(length_bytes.len() + packet_len)?),
+                        None => None,
+                    };
+                    let mut packet = vec![0; packet_len];
+                    reader.read_exact(&mut packet)?;
+                    wire_len += length_bytes.len() + packet.len();
+                    let payload = cipher
+                        .decrypt_payload(&packet, None)
+                        .map_err(Error::V2Protocol)?;
+                    if payload.packet_type() == PacketType::Decoy {
+                        continue;
+                    }
+                    let frame = v2_contents_to_frame(*magic, payload.contents())?;
+                    let mut bytes = [0; 24];
+                    bytes.copy_from_slice(&frame[..24]);
+                    let message_header = consensus::deserialize::<V1MessageHeader>(&bytes)?;
+                    *pending = frame;
+                    break Ok(FrameHeader {
+                        bytes,
+                        command: message_header.command,
+                        length: message_header.length as usize,
+                        wire_len,
+                        reservation,
+                    });
+                }
+            }
+        }
+    }
+
+    fn read_body<R: BufRead>(
+        &mut self,
+        reader: &mut R,
+        header: FrameHeader,
+        progress: Option<&MessageProgress>,
+        pool: Option<&BufferPool>,
+    ) -> Result<Vec<u8>, Error> {
+        match self {
+            ReadTransport::V1(_) => {
+                let FrameHeader {
+                    bytes: header_buf,
+                    length,
+                    ..
+                } = header;
+                let frame_len = header_buf.len() + length;
+                let mut message_buf = match pool {
+                    Some(pool) => pool.take(frame_len),
+                    None => vec![0; frame_len],
+                };
+                message_buf[..header_buf.len()].copy_from_slice(&header_buf);
+                let contents_buf = &mut message_buf[header_buf.len()..];
+                let mut bytes_read = 0;
+                while bytes_read < length {
+                    if let Some(progress) = progress {
+                        progress.update(Some((bytes_read, length)));
+                    }
+                    let end = std::cmp::min(bytes_read + READ_CHUNK_SIZE, length);
+                    reader.read_exact(&mut contents_buf[bytes_read..end])?;
+                    bytes_read = end;
+                }
+                if let Some(progress) = progress {
+                    progress.update(None);
+                }
+                Ok(message_buf)
+            }
+            ReadTransport::V2 { pending, .. } => Ok(std::mem::take(pending)),
+        }
+    }
+
+    // Discard the payload of a message without reading it into memory.
+    fn skip_body<R: BufRead>(&mut self, reader: &mut R, header: &FrameHeader) -> Result<(), Error> {
+        if let ReadTransport::V2 { pending, .. } = self {
+            pending.clear();
+            return Ok(());
+        }
+        let mut body = reader.take(header.length as u64);
+        let skipped = io::copy(&mut body, &mut io::sink())?;
+        if skipped < header.length as u64 {
+            return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+        Ok(())
+    }
+
+    fn decode_frame(&self, frame: &[u8]) -> Result<NetworkMessage, DeserializeError> {
+        let message = consensus::deserialize::<RawNetworkMessage>(frame)?;
+        Ok(message.into_payload())
+    }
+}
+
+/// Errors that occur during a connection.
+#[derive(Debug)]
+pub enum Error {
+    /// A message was not deserialized according to protocol specifications.
+    Deserialize {
+        /// The command of the message, if the header was decoded.
+        command: Option<CommandString>,
+        /// The underlying decoding error.
+        source: DeserializeError,
+    },
+    /// An IO related error occurred.
+    Io(io::Error),
+    /// An error occurred during the version handshake.
+    Handshake(handshake::Error),
+    /// The peer sent magic that does not belong to the current network.
+    UnexpectedMagic(Magic),
+    /// The peer did not send a version message.
+    MissingVersion,
+    /// The peer did not send a verack message before the handshake timeout.
+    MissingVerack,
+    /// The channel to the message writing thread was closed.
+    ChannelClosed,
+    /// A block locator exceeded the maximum number of hashes.
+    TooManyLocatorHashes(usize),
+    /// The inbound message filter requested the connection be closed after this message.
+    FilterDisconnect(CommandString),
+    /// The expected message did not arrive in time.
+    WaitTimeout,
+    /// The connection failed while sending the final messages of the handshake.
+    HandshakeIncompleteResponse(io::Error),
+    /// A string could not be decoded as hex.
+    InvalidHex,
+    /// The connection was closed after reaching the configured maximum lifetime.
+    LifetimeExpired,
+    /// A `getutxos` request exceeded the maximum number of outpoints.
+    TooManyOutpoints(usize),
+    /// The stream could not be split into separate halves for reading and writing.
+    StreamClone(io::Error),
+    /// The peer did not send any message before the initial read timeout.
+    NoInitialVersion,
+    /// The peer relayed transactions over a block-relay-only connection.
+    UnexpectedTransactionRelay(CommandString),
+    /// The peer closed their side of the connection.
+    PeerHalfClosed,
+    /// The peer sent more addresses in a single message than allowed.
+    OversizedAddr(usize),
+    /// A `cfheaders` message did not continue from the last filter header.
+    FilterHeaderDiscontinuity(FilterHeader),
+    /// The system clock is set before the Unix epoch.
+    SystemTime,
+    /// The peer did not respond to a `getheaders` request in time.
+    HeadersTimeout,
+    /// The peer sent more unrequested blocks than allowed.
+    UnrequestedBlockFlood(u64),
+    /// A header in a `headers` message does not build on the header before it.
+    DisconnectedHeaders(BlockHash),
+    /// A header in a `headers` message does not meet the proof of work it claims.
+    InsufficientPow(BlockHash),
+    /// Reading a message of this length would exceed the read memory budget. The rest of the
+    /// message is not read, so the stream is closed.
+    ReadMemoryExhausted(usize),
+    /// The peer declared a message longer than the protocol allows.
+    OversizedMessage(usize),
+    /// The v2 transport handshake was interrupted, usually because the peer only supports v1.
+    V2Handshake(io::Error),
+    /// The peer violated the v2 transport protocol.
+    V2Protocol(bip324::Error),
+    /// The peer uses a transport that is not allowed by the configuration.
+    UnsupportedTransport,
+    /// The Socks5 proxy could not open the connection, with the reply code it sent.
+    ProxyReply(u8),
+    /// The Socks5 proxy rejected the credentials, or requires authentication that was not given.
+    ProxyAuthentication,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Deserialize {
+                command: Some(command),
+                source,
+            } => write!(f, "invalid {command} message: {source}"),
+            Error::Deserialize {
+                command: None,
+                source,
+            } => source.fmt(f),
+            Error::Io(e) => e.fmt(f),
+            Error::Handshake(e) => e.fmt(f),
+            Error::UnexpectedMagic(magic) => write!(f, "unexpected network magic: {magic}"),
+            Error::MissingVersion => write!(f, "missing version message."),
+            Error::MissingVerack => write!(f, "missing verack message."),
+            Error::ChannelClosed => write!(f, "channel closed"),
+            Error::TooManyLocatorHashes(len) => write!(f, "too many locator hashes: {len}"),
+            Error::FilterDisconnect(command) => {
+                write!(f, "disconnected by inbound filter on: {command}")
+            }
+            Error::WaitTimeout => write!(f, "timed out waiting for message"),
+            Error::HandshakeIncompleteResponse(e) => {
+                write!(f, "connection failed while completing handshake: {e}")
+            }
+            Error::InvalidHex => write!(f, "invalid hex string"),
+            Error::LifetimeExpired => write!(f, "connection lifetime expired"),
+            Error::TooManyOutpoints(len) => write!(f, "too many outpoints: {len}"),
+            Error::StreamClone(e) => write!(f, "could not clone the stream: {e}"),
+            Error::NoInitialVersion => write!(f, "no version message before the initial timeout"),
+            Error::UnexpectedTransactionRelay(command) => {
+                write!(
+                    f,
+                    "transaction relay on a block-relay-only connection: {command}"
+                )
+            }
+            Error::PeerHalfClosed => write!(f, "peer closed the connection"),
+            Error::OversizedAddr(len) => write!(f, "too many addresses in one message: {len}"),
+            Error::FilterHeaderDiscontinuity(previous) => {
+                write!(f, "filter headers do not connect at: {previous}")
+            }
+            Error::SystemTime => write!(f, "system time is before the unix epoch"),
+            Error::HeadersTimeout => write!(f, "peer did not respond to getheaders"),
+            Error::UnrequestedBlockFlood(count) => {
+                write!(f, "too many unrequested blocks: {count}")
+            }
+            Error::DisconnectedHeaders(hash) => write!(f, "disconnected header: {hash}"),
+            Error::InsufficientPow(hash) => write!(f, "insufficient proof of work: {hash}"),
+            Error::ReadMemoryExhausted(len) => {
+                write!(f, "read memory budget exhausted by a {len} byte message")
+            }
+            Error::OversizedMessage(len) => write!(f, "message too large: {len} bytes"),
+            Error::V2Handshake(e) => write!(f, "v2 handshake interrupted: {e}"),
+            Error::V2Protocol(e) => write!(f, "v2 transport error: {e}"),
+            Error::UnsupportedTransport => write!(f, "peer uses an unsupported transport"),
+            Error::ProxyReply(code) => write!(f, "proxy failed to connect with reply code {code}"),
+            Error::ProxyAuthentication => write!(f, "proxy authentication failed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// Classify how a connection manager should react to this error.
+    ///
+    /// - [`Error::Deserialize`]: ban-worthy, the peer sent a malformed message.
+    /// - [`Error::Io`]: retryable, includes refused connections and timeouts.
+    /// - [`Error::Handshake`]: see below.
+    /// - [`Error::UnexpectedMagic`]: fatal, the peer is on another network.
+    /// - [`Error::MissingVersion`]: retryable, the peer closed the connection or sent nothing.
+    /// - [`Error::MissingVerack`]: retryable, the peer may be slow.
+    /// - [`Error::ChannelClosed`]: fatal, the connection is no longer usable.
+    /// - [`Error::TooManyLocatorHashes`]: fatal, the request is invalid.
+    /// - [`Error::FilterDisconnect`]: fatal, the connection was closed on request.
+    /// - [`Error::WaitTimeout`]: retryable.
+    /// - [`Error::HandshakeIncompleteResponse`]: retryable.
+    /// - [`Error::InvalidHex`]: fatal, the input is invalid.
+    /// - [`Error::LifetimeExpired`]: retryable, the connection was rotated.
+    /// - [`Error::TooManyOutpoints`]: fatal, the request is invalid.
+    /// - [`Error::StreamClone`]: retryable, the system may be short on resources.
+    /// - [`Error::NoInitialVersion`]: retryable, the peer may be slow.
+    /// - [`Error::UnexpectedTransactionRelay`]: ban-worthy, the peer violated the protocol.
+    /// - [`Error::PeerHalfClosed`]: retryable.
+    /// - [`Error::OversizedAddr`]: ban-worthy, the peer is flooding addresses.
+    /// - [`Error::FilterHeaderDiscontinuity`]: ban-worthy, the peer served an inconsistent chain.
+    /// - [`Error::SystemTime`]: fatal, the system clock must be corrected.
+    /// - [`Error::HeadersTimeout`]: retryable, another peer may serve the headers.
+    /// - [`Error::UnrequestedBlockFlood`]: ban-worthy, the peer is pushing blocks.
+    /// - [`Error::DisconnectedHeaders`]: ban-worthy, the peer served an inconsistent chain.
+    /// - [`Error::InsufficientPow`]: ban-worthy, the peer served a fabricated header.
+    /// - [`Error::ReadMemoryExhausted`]: retryable, memory may be released by other connections.
+    /// - [`Error::OversizedMessage`]: ban-worthy, the peer violated the protocol.
+    /// - [`Error::V2Handshake`]: retryable, the peer may accept a v1 connection.
+    /// - [`Error::V2Protocol`]: fatal, the encrypted stream cannot be recovered.
+    /// - [`Error::UnsupportedTransport`]: fatal, the peer does not support the required transport.
+    /// - [`Error::ProxyReply`]: retryable, the target may be unreachable for now.
+    /// - [`Error::ProxyAuthentication`]: fatal, the credentials must be corrected.
+    ///
+    /// Handshake errors are ban-worthy for protocol violations
+    /// ([`handshake::Error::IrrelevantMessage`] and [`handshake::Error::InvalidHeight`]) and fatal
+    /// otherwise, as reconnecting to the same peer will not change the outcome.
+    pub fn severity(&self) -> ErrorSeverity {
+        match self {
+            Error::Deserialize { .. } => ErrorSeverity::BanWorthy,
+            Error::Io(_) => ErrorSeverity::Retryable,
+            Error::Handshake(e) => match e {
+                handshake::Error::IrrelevantMessage(_) | handshake::Error::InvalidHeight(_) => {
+                    ErrorSeverity::BanWorthy
+                }
+                handshake::Error::ConnectionToSelf
+                | handshake::Error::TooLowVersion(_)
+                | handshake::Error::MissingService(_)
+                | handshake::Error::UserAgentTooLong(_)
+                | handshake::Error::InvalidUserAgentComponent(_)
+                | handshake::Error::VersionRejected(_)
+                | handshake::Error::UnsupportedCompactVersion(_)
+                | handshake::Error::UnknownServices(_) => ErrorSeverity::Fatal,
+            },
+            Error::UnexpectedMagic(_) => ErrorSeverity::Fatal,
+            Error::MissingVersion => ErrorSeverity::Retryable,
+            Error::MissingVerack => ErrorSeverity::Retryable,
+            Error::ChannelClosed => ErrorSeverity::Fatal,
+            Error::TooManyLocatorHashes(_) => ErrorSeverity::Fatal,
+            Error::FilterDisconnect(_) => ErrorSeverity::Fatal,
+            Error::WaitTimeout => ErrorSeverity::Retryable,
+            Error::HandshakeIncompleteResponse(_) => ErrorSeverity::Retryable,
+            Error::InvalidHex => ErrorSeverity::Fatal,
+            Error::LifetimeExpired => ErrorSeverity::Retryable,
+            Error::TooManyOutpoints(_) => ErrorSeverity::Fatal,
+            Error::StreamClone(_) => ErrorSeverity::Retryable,
+            Error::NoInitialVersion => ErrorSeverity::Retryable,
+            Error::UnexpectedTransactionRelay(_) => ErrorSeverity::BanWorthy,
+            Error::PeerHalfClosed => ErrorSeverity::Retryable,
+            Error::OversizedAddr(_) => ErrorSeverity::BanWorthy,
+            Error::FilterHeaderDiscontinuity(_) => ErrorSeverity::BanWorthy,
+            Error::SystemTime => ErrorSeverity::Fatal,
+            Error::HeadersTimeout => ErrorSeverity::Retryable,
+            Error::UnrequestedBlockFlood(_) => ErrorSeverity::BanWorthy,
+            Error::DisconnectedHeaders(_) => ErrorSeverity::BanWorthy,
+            Error::InsufficientPow(_) => ErrorSeverity::BanWorthy,
+            Error::ReadMemoryExhausted(_) => ErrorSeverity::Retryable,
+            Error::OversizedMessage(_) => ErrorSeverity::BanWorthy,
+            Error::V2Handshake(_) => ErrorSeverity::Retryable,
+            Error::V2Protocol(_) => ErrorSeverity::Fatal,
+            Error::UnsupportedTransport => ErrorSeverity::Fatal,
+            Error::ProxyReply(_) => ErrorSeverity::Retryable,
+            Error::ProxyAuthentication => ErrorSeverity::Fatal,
+        }
+    }
+
+    /// Classify why the connection ended because of this error. A writer that closed without an
+    /// error from [`ConnectionWriter::take_errors`] is a [`DisconnectCause::CleanClose`].
+    pub fn disconnect_cause(&self) -> DisconnectCause {
+        match self {
+            Error::Io(e) | Error::HandshakeIncompleteResponse(e) | Error::V2Handshake(e) => {
+                match e.kind() {
+                    io::ErrorKind::UnexpectedEof => DisconnectCause::CleanClose,
+                    io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted => {
+                        DisconnectCause::Reset
+                    }
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => DisconnectCause::Timeout,
+                    io::ErrorKind::BrokenPipe => DisconnectCause::BrokenPipe,
+                    _ => DisconnectCause::Other,
+                }
+            }
+            Error::PeerHalfClosed | Error::LifetimeExpired => DisconnectCause::CleanClose,
+            Error::WaitTimeout | Error::NoInitialVersion | Error::HeadersTimeout => {
+                DisconnectCause::Timeout
+            }
+            _ => DisconnectCause::Other,
+        }
+    }
+}
+
+/// Why a connection ended, as classified by [`Error::disconnect_cause`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DisconnectCause {
+    /// The connection was closed by either side without an error.
+    CleanClose,
+    /// The peer reset or aborted the connection.
+    Reset,
+    /// The peer did not respond in time.
+    Timeout,
+    /// The peer closed the connection while we were writing to it.
+    BrokenPipe,
+    /// Any other failure, such as a protocol violation.
+    Other,
+}
+
+/// How a connection manager should react to an [`Error`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorSeverity {
+    /// The connection may succeed if attempted again.
+    Retryable,
+    /// The connection should be dropped and not retried.
+    Fatal,
+    /// The peer violated the protocol and should be banned.
+    BanWorthy,
+}
+
+impl From<DeserializeError> for Error {
+    fn from(value: DeserializeError) -> Self {
+        Self::Deserialize {
+            command: None,
+            source: value,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<handshake::Error> for Error {
+    fn from(value: handshake::Error) -> Self {
+        Self::Handshake(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::{self, BufReader, Write},
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        sync::{atomic::AtomicBool, Arc},
+        time::{Duration, Instant},
+    };
+
+    use bip324::Role;
+    use bitcoin::{
+        absolute::LockTime,
+        bip152::{BlockTransactions, HeaderAndShortIds, ShortId},
+        transaction::Version,
+        Network, TxIn,
+    };
+    use p2p::{
+        message::NetworkMessage,
+        message_compact_blocks::{BlockTxn, CmpctBlock},
+        NetworkExt, ProtocolVersion,
+    };
+
+    use super::{
+        adjust_addr_timestamps, bootstrap_addresses, connect_from, detect_network_by_genesis,
+        encode_v2_contents, jitter_delay, loopback, memory_stream, message_checksum,
+        message_from_hex, message_to_hex, net_group, open_any, prepare_addr_relay,
+        sends_v1_version, socks5_connect, transaction_inventory, v2_contents_to_frame,
+        v2_handshake, violates_version, AddrBudget, AddrRefresher, BufferPool, CompactBlockStatus,
+        ConnectAttemptTracker, Connection, ConnectionExt, ConnectionKind, ConnectionReader,
+        DisconnectCause, Error, ErrorSeverity, HeadersTracker, MemoryReader, PartialFrame,
+        PeerHistory, PeerManager, ProxyTarget, ReadMemoryBudget, ReadTransport, StreamControl,
+        TimeoutParams, TransportKind, TransportStats, TxAnnouncement, Utxos, MAX_ADDR_RELAY,
+    };
+    use crate::handshake::{self, ConnectionConfig, FilterAction, TransportPreference};
+
+    #[test]
+    fn test_loopback() {
+        let ((our_writer, _, our_metrics), (_, mut their_reader, their_metrics)) = loopback(
+            ConnectionConfig::new(),
+            ConnectionConfig::new().our_height(10),
+            TimeoutParams::default(),
+        )
+        .unwrap();
+        assert_eq!(our_metrics.feeler_data().reported_height, 10);
+        assert_eq!(their_metrics.feeler_data().reported_height, 0);
+        our_writer.send_message(NetworkMessage::Ping(7)).unwrap();
+        let ping = their_reader
+            .wait_for(
+                |message| matches!(message, NetworkMessage::Ping(_)),
+                Duration::from_secs(5),
+            )
+            .unwrap();
+        assert!(matches!(ping, NetworkMessage::Ping(7)));
+        let ping = p2p::message::CommandString::try_from_static("ping").unwrap();
+        assert_eq!(their_metrics.bytes_by_type().get(&ping), Some(&32));
+        // The outbound side follows its verack with a `sendcmpct`
+        let send_cmpct = p2p::message::CommandString::try_from_static("sendcmpct").unwrap();
+        assert_eq!(
+            their_metrics.first_post_handshake_message(),
+            Some(send_cmpct)
+        );
+    }
+
+    #[test]
+    fn test_read_message_with_size() {
+        let ((our_writer, _, _), (_, mut their_reader, _)) = loopback(
+            ConnectionConfig::new(),
+            ConnectionConfig::new(),
+            TimeoutParams::default(),
+        )
+        .unwrap();
+        our_writer.send_message(NetworkMessage::Ping(7)).unwrap();
+        let size = loop {
+            if let Some((NetworkMessage::Ping(7), size)) =
+                their_reader.read_message_with_size().unwrap()
+            {
+                break size;
+            }
+        };
+        assert_eq!(size, 32);
+    }
+
+    #[test]
+    fn test_ban_score() {
+        let ((our_writer, _, _), (_, mut their_reader, their_metrics)) = loopback(
+            ConnectionConfig::new(),
+            ConnectionConfig::new().ban_score_points(100, 30),
+            TimeoutParams::default(),
+        )
+        .unwrap();
+        our_writer.send_message(NetworkMessage::MemPool).unwrap();
+        our_writer.send_message(NetworkMessage::Ping(5)).unwrap();
+        their_reader
+            .wait_for(
+                |message| matches!(message, NetworkMessage::Ping(5)),
+                Duration::from_secs(5),
+            )
+            .unwrap();
+        assert_eq!(their_metrics.ban_score(), 30);
+        assert!(their_metrics.should_disconnect(30));
+        assert!(!their_metrics.should_disconnect(31));
+    }
+
+    #[test]
+    fn test_ping_now() {
+        let ((our_writer, mut our_reader, our_metrics), (_their_writer, mut their_reader, _)) =
+            loopback(
+                ConnectionConfig::new(),
+                ConnectionConfig::new(),
+                TimeoutParams::default(),
+            )
+            .unwrap();
+        let responder = std::thread::spawn(move || {
+            let _ = their_reader.wait_for(|_| false, Duration::from_secs(1));
+        });
+        let reader = std::thread::spawn(move || {
+            our_reader
+                .wait_for(
+                    |message| matches!(message, NetworkMessage::Pong(_)),
+                    Duration::from_secs(5),
+                )
+                .unwrap();
+        });
+        let rtt = our_writer.ping_now(Duration::from_secs(5)).unwrap();
+        assert_eq!(our_metrics.ping_rtt(), Some(rtt));
+        reader.join().unwrap();
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn test_read_message_timeout() {
+        let ((our_writer, _, _), (_, mut their_reader, _)) = loopback(
+            ConnectionConfig::new(),
+            ConnectionConfig::new(),
+            TimeoutParams::default(),
+        )
+        .unwrap();
+        // Drain the messages sent at the end of the handshake
+        while their_reader
+            .read_message_timeout(Duration::from_millis(100))
+            .unwrap()
+            .is_some()
+        {}
+        our_writer.send_message(NetworkMessage::Ping(3)).unwrap();
+        let message = their_reader
+            .read_message_timeout(Duration::from_secs(5))
+            .unwrap();
+        assert!(matches!(message, Some(NetworkMessage::Ping(3))));
+        our_writer.shutdown().unwrap();
+        assert!(their_reader
+            .read_message_timeout(Duration::from_secs(5))
+            .is_err());
+    }
+
+    #[test]
+    fn test_wait_for_split_message() {
+        use std::io::Write;
+
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = std::thread::spawn(move || {
+            let (tcp_stream, _) = listener.accept().unwrap();
+            let mut raw = tcp_stream.try_clone().unwrap();
+            let config = ConnectionConfig::new().inbound();
+            let (writer, _reader, _) =
+                ConnectionExt::handshake(config, tcp_stream, TimeoutParams::default()).unwrap();
+            let magic = ConnectionConfig::new().network().default_network_magic();
+            let frame = bitcoin::consensus::serialize(&p2p::message::RawNetworkMessage::new(
+                magic,
+                NetworkMessage::Ping(9),
+            ));
+            std::thread::sleep(Duration::from_millis(200));
+            raw.write_all(&frame[..10]).unwrap();
+            std::thread::sleep(Duration::from_millis(300));
+            raw.write_all(&frame[10..]).unwrap();
+            writer.send_message(NetworkMessage::Ping(10)).unwrap();
+            writer
+        });
+        let (_our_writer, mut our_reader, _) = ConnectionConfig::new()
+            .open_connection(addr, TimeoutParams::default())
+            .unwrap();
+        let is_ping = |message: &NetworkMessage| matches!(message, NetworkMessage::Ping(_));
+        // The ping starts to arrive before the deadline but completes after it
+        let ping = our_reader
+            .wait_for(is_ping, Duration::from_millis(350))
+            .unwrap();
+        assert!(matches!(ping, NetworkMessage::Ping(9)));
+        let ping = our_reader
+            .wait_for(is_ping, Duration::from_secs(5))
+            .unwrap();
+        assert!(matches!(ping, NetworkMessage::Ping(10)));
+        assert!(matches!(
+            our_reader.wait_for(is_ping, Duration::from_millis(50)),
+            Err(Error::WaitTimeout)
+        ));
+        drop(peer.join().unwrap());
+    }
+
+    #[test]
+    fn test_dropped_writer_stops() {
+        let ((our_writer, mut our_reader, _), (their_writer, _their_reader, _)) = loopback(
+            ConnectionConfig::new(),
+            ConnectionConfig::new(),
+            TimeoutParams::default(),
+        )
+        .unwrap();
+        drop(our_writer);
+        // Pings are answered through the writer, which stops once it notices it was dropped
+        let start = std::time::Instant::now();
+        let result = loop {
+            their_writer.send_message(NetworkMessage::Ping(1)).unwrap();
+            match our_reader.wait_for(|_| false, Duration::from_millis(200)) {
+                Err(Error::WaitTimeout) if start.elapsed() < Duration::from_secs(5) => continue,
+                result => break result,
+            }
+        };
+        assert!(matches!(result, Err(Error::ChannelClosed)));
+    }
+
+    #[test]
+    fn test_send_tx_witness() {
+        use bitcoin::Witness;
+        use p2p::message_blockdata::Inventory;
+
+        let ((our_writer, _, _), (_, mut their_reader, _)) = loopback(
+            ConnectionConfig::new(),
+            ConnectionConfig::new(),
+            TimeoutParams::default(),
+        )
+        .unwrap();
+        let mut input = TxIn::EMPTY_COINBASE;
+        input.witness = Witness::from_slice(&[[1u8; 4]]);
+        let transaction = bitcoin::Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![input],
+            output: Vec::new(),
+        };
+        let txid = transaction.compute_txid();
+        let is_tx = |message: &NetworkMessage| matches!(message, NetworkMessage::Tx(_));
+        for (request, witness_len) in [
+            (Inventory::Transaction(txid), 0),
+            (Inventory::WitnessTransaction(txid), 1),
+        ] {
+            our_writer.send_tx(&request, transaction.clone()).unwrap();
+            let NetworkMessage::Tx(received) = their_reader
+                .wait_for(is_tx, Duration::from_secs(5))
+                .unwrap()
+            else {
+                unreachable!("only transactions satisfy the predicate");
+            };
+            assert_eq!(received.input[0].witness.len(), witness_len);
+            assert_eq!(received.compute_txid(), txid);
+        }
+    }
+
+    // A block with a coinbase and two transactions, along with its compact block. Every
+    // transaction but the coinbase is sent as a short ID.
+    fn compact_block_fixture() -> (bitcoin::Block, HeaderAndShortIds) {
+        let transaction = |lock_time: u32| bitcoin::Transaction {
+            version: Version::ONE,
+            lock_time: LockTime::from_consensus(lock_time),
+            input: vec![TxIn::EMPTY_COINBASE],
+            output: Vec::new(),
+        };
+        let mut block = bitcoin::Block {
+            header: bitcoin::constants::genesis_block(Network::Regtest).header,
+            txdata: vec![transaction(0), transaction(1), transaction(2)],
+        };
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        let compact_block = HeaderAndShortIds::from_block(&block, 42, 2, &[]).unwrap();
+        (block, compact_block)
+    }
+
+    // Send the compact block to a peer that finds the transactions at the given indexes of the
+    // block for the short IDs, in order.
+    fn send_compact_block(
+        compact_block: HeaderAndShortIds,
+        block: &bitcoin::Block,
+        found: &[usize],
+        inbound_filter: FilterAction,
+    ) -> (Connection<MemoryReader>, Connection<MemoryReader>) {
+        let mempool: HashMap<ShortId, bitcoin::Transaction> = compact_block
+            .short_ids
+            .iter()
+            .copied()
+            .zip(found.iter().map(|index| block.txdata[*index].clone()))
+            .collect();
+        let theirs = ConnectionConfig::new()
+            .mempool_lookup(move |short_id| mempool.get(&short_id).cloned())
+            .inbound_filter(move |message| match message {
+                NetworkMessage::CmpctBlock(_) => inbound_filter,
+                _ => FilterAction::Pass,
+            });
+        let (ours, theirs) =
+            loopback(ConnectionConfig::new(), theirs, TimeoutParams::default()).unwrap();
+        ours.0
+            .send_message(NetworkMessage::CmpctBlock(CmpctBlock { compact_block }))
+            .unwrap();
+        ours.0.send_message(NetworkMessage::Ping(1)).unwrap();
+        (ours, theirs)
+    }
+
+    fn wait_for_ping(reader: &mut ConnectionReader<MemoryReader>) {
+        reader
+            .wait_for(
+                |message| matches!(message, NetworkMessage::Ping(_)),
+                Duration::from_secs(5),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_compact_block_from_mempool() {
+        let (block, compact_block) = compact_block_fixture();
+        let (_ours, (_, mut their_reader, _)) =
+            send_compact_block(compact_block, &block, &[1, 2], FilterAction::Pass);
+        wait_for_ping(&mut their_reader);
+        let status = their_reader.take_compact_block_status();
+        assert!(
+            matches!(status, Some(CompactBlockStatus::Complete(received)) if received == block)
+        );
+    }
+
+    #[test]
+    fn test_compact_block_getblocktxn() {
+        let (block, compact_block) = compact_block_fixture();
+        let block_hash = block.block_hash();
+        let ((our_writer, mut our_reader, _), (_, mut their_reader, _)) =
+            send_compact_block(compact_block, &block, &[1], FilterAction::Pass);
+        wait_for_ping(&mut their_reader);
+        let status = their_reader.take_compact_block_status();
+        assert!(matches!(
+            status,
+            Some(CompactBlockStatus::Requested { block_hash: hash, missing })
+                if hash == block_hash && missing == vec![2]
+        ));
+        let request = our_reader
+            .wait_for(
+                |message| matches!(message, NetworkMessage::GetBlockTxn(_)),
+                Duration::from_secs(5),
+            )
+            .unwrap();
+        let NetworkMessage::GetBlockTxn(request) = request else {
+            unreachable!("only getblocktxn satisfies the predicate");
+        };
+        assert_eq!(request.txs_request.block_hash, block_hash);
+        assert_eq!(request.txs_request.indexes, vec![2]);
+        let transactions = BlockTransactions {
+            block_hash,
+            transactions: vec![block.txdata[2].clone()],
+        };
+        our_writer
+            .send_message(NetworkMessage::BlockTxn(BlockTxn { transactions }))
+            .unwrap();
+        our_writer.send_message(NetworkMessage::Ping(2)).unwrap();
+        wait_for_ping(&mut their_reader);
+        let status = their_reader.take_compact_block_status();
+        assert!(
+            matches!(status, Some(CompactBlockStatus::Complete(received)) if received == block)
+        );
+    }
+
+    #[test]
+    fn test_compact_block_short_id_collision() {
+        let (block, compact_block) = compact_block_fixture();
+        // Another transaction in the mempool shares the short ID of the first transaction
+        let (_ours, (_, mut their_reader, _)) =
+            send_compact_block(compact_block, &block, &[2, 2], FilterAction::Pass);
+        wait_for_ping(&mut their_reader);
+        let status = their_reader.take_compact_block_status();
+        assert!(
+            matches!(status, Some(CompactBlockStatus::Failed(hash)) if hash == block.block_hash())
+        );
+    }
+
+    #[test]
+    fn test_compact_block_filtered() {
+        let (block, compact_block) = compact_block_fixture();
+        let (_ours, (_, mut their_reader, _)) =
+            send_compact_block(compact_block, &block, &[1, 2], FilterAction::Drop);
+        wait_for_ping(&mut their_reader);
+        assert!(their_reader.take_compact_block_status().is_none());
+    }
+
+    #[test]
+    fn test_verified_compact_filters() {
+        use p2p::message_filter::CFCheckpt;
+
+        let config = ConnectionConfig::new();
+        let genesis = bitcoin::constants::genesis_block(config.network()).block_hash();
+        let ((our_writer, _, _), (_, mut their_reader, their_metrics)) =
+            loopback(config, ConnectionConfig::new(), TimeoutParams::default()).unwrap();
+        let checkpt = |filter_type, stop_hash| {
+            NetworkMessage::CFCheckpt(CFCheckpt {
+                filter_type,
+                stop_hash,
+                filter_headers: Vec::new(),
+            })
+        };
+        let other = bitcoin::constants::genesis_block(Network::Regtest).block_hash();
+        our_writer.send_message(checkpt(0x00, other)).unwrap();
+        our_writer.send_message(checkpt(0x01, genesis)).unwrap();
+        our_writer.send_message(NetworkMessage::Ping(1)).unwrap();
+        wait_for_ping(&mut their_reader);
+        let verified = their_metrics.verified_services().unwrap();
+        assert!(!verified.has(p2p::ServiceFlags::COMPACT_FILTERS));
+        our_writer.send_message(checkpt(0x00, genesis)).unwrap();
+        our_writer.send_message(NetworkMessage::Ping(2)).unwrap();
+        wait_for_ping(&mut their_reader);
+        let verified = their_metrics.verified_services().unwrap();
+        assert!(verified.has(p2p::ServiceFlags::COMPACT_FILTERS));
+    }
+
+    #[test]
+    fn test_tolerate_decode_errors() {
+        let ping = p2p::message::CommandString::try_from_static("ping").unwrap();
+        // A ping whose nonce is cut short
+        let undecodable = || NetworkMessage::Unknown {
+            command: ping.clone(),
+            payload: vec![1, 2, 3],
+        };
+        let theirs = ConnectionConfig::new().tolerate_decode_errors(1);
+        let ((our_writer, _, _), (_, mut their_reader, _)) =
+            loopback(ConnectionConfig::new(), theirs, TimeoutParams::default()).unwrap();
+        our_writer.send_message(undecodable()).unwrap();
+        our_writer.send_message(NetworkMessage::Ping(1)).unwrap();
+        let message = their_reader
+            .wait_for(
+                |message| matches!(message, NetworkMessage::Ping(_)),
+                Duration::from_secs(5),
+            )
+            .unwrap();
+        assert!(matches!(message, NetworkMessage::Ping(1)));
+        our_writer.send_message(undecodable()).unwrap();
+        let error = loop {
+            if let Err(e) = their_reader.read_message() {
+                break e;
+            }
+        };
+        assert!(matches!(
+            error,
+            Error::Deserialize { command: Some(command), .. } if command == ping
+        ));
+    }
+
+    fn first_ping(ours: ConnectionConfig, pings: &[u64]) -> NetworkMessage {
+        let ((our_writer, _, _), (_, mut their_reader, _)) =
+            loopback(ours, ConnectionConfig::new(), TimeoutParams::default()).unwrap();
+        for nonce in pings {
+            our_writer
+                .send_message(NetworkMessage::Ping(*nonce))
+                .unwrap();
+        }
+        their_reader
+            .wait_for(
+                |message| matches!(message, NetworkMessage::Ping(_)),
+                Duration::from_secs(5),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_outbound_filter_rewrite() {
+        let ours = ConnectionConfig::new().outbound_filter(|message| {
+            if let NetworkMessage::Ping(nonce) = message {
+                *nonce += 10;
+            }
+            true
+        });
+        assert!(matches!(first_ping(ours, &[1]), NetworkMessage::Ping(11)));
+    }
+
+    #[test]
+    fn test_outbound_filter_drop() {
+        let ours = ConnectionConfig::new()
+            .outbound_filter(|message| !matches!(message, NetworkMessage::Ping(1)));
+        assert!(matches!(first_ping(ours, &[1, 2]), NetworkMessage::Ping(2)));
+    }
+
+    fn send_cfheaders(previous: &[bitcoin::bip158::FilterHeader]) -> Result<NetworkMessage, Error> {
+        use bitcoin::bip158::{FilterHash, FilterHeader};
+        use p2p::message_filter::CFHeaders;
+
+        let theirs = ConnectionConfig::new().check_filter_headers();
+        let ((our_writer, _, _), (_, mut their_reader, _)) =
+            loopback(ConnectionConfig::new(), theirs, TimeoutParams::default()).unwrap();
+        their_reader.expect_filter_header(FilterHeader::from_byte_array([1; 32]));
+        let mut result = Err(Error::WaitTimeout);
+        for previous_filter_header in previous {
+            let cfheaders = CFHeaders {
+                filter_type: 0x00,
+                stop_hash: bitcoin::BlockHash::from_byte_array([0; 32]),
+                previous_filter_header: *previous_filter_header,
+                filter_hashes: vec![FilterHash::from_byte_array([2; 32])],
+            };
+            our_writer
+                .send_message(NetworkMessage::CFHeaders(cfheaders))
+                .unwrap();
+            result = their_reader.wait_for(
+                |message| matches!(message, NetworkMessage::CFHeaders(_)),
+                Duration::from_secs(5),
+            );
+        }
+        result
+    }
+
+    #[test]
+    fn test_check_filter_headers_connecting() {
+        use bitcoin::bip158::{FilterHash, FilterHeader};
+
+        let first = FilterHeader::from_byte_array([1; 32]);
+        let second = FilterHash::from_byte_array([2; 32]).filter_header(&first);
+        let message = send_cfheaders(&[first, second]).unwrap();
+        assert!(matches!(
+            message,
+            NetworkMessage::CFHeaders(cfheaders) if cfheaders.previous_filter_header == second
+        ));
+    }
+
+    #[test]
+    fn test_check_filter_headers_discontinuous() {
+        use bitcoin::bip158::FilterHeader;
+
+        let first = FilterHeader::from_byte_array([1; 32]);
+        let result = send_cfheaders(&[first, first]);
+        assert!(matches!(
+            result,
+            Err(Error::FilterHeaderDiscontinuity(previous)) if previous == first
+        ));
+    }
+
+    // Send an `addr` with two addresses followed by a ping to a peer that allows one address
+    fn send_addr_flood(theirs: ConnectionConfig) -> (Result<NetworkMessage, Error>, u64) {
+        let theirs = theirs.max_addr_per_message(1);
+        let ((our_writer, _, _), (_, mut their_reader, their_metrics)) =
+            loopback(ConnectionConfig::new(), theirs, TimeoutParams::default()).unwrap();
+        let addrs = (1..=2)
+            .map(|port| {
+                let addr = SocketAddr::from((Ipv4Addr::new(1, 2, 3, 4), port));
+                (0, p2p::Address::new(&addr, p2p::ServiceFlags::NETWORK))
+            })
+            .collect();
+        our_writer
+            .send_message(NetworkMessage::Addr(p2p::message::AddrV1Payload(addrs)))
+            .unwrap();
+        our_writer.send_message(NetworkMessage::Ping(1)).unwrap();
+        let result = their_reader.wait_for(
+            |message| matches!(message, NetworkMessage::Ping(_)),
+            Duration::from_secs(5),
+        );
+        (result, their_metrics.oversized_addr_count())
+    }
+
+    #[test]
+    fn test_addr_flood_counted() {
+        let (result, oversized) = send_addr_flood(ConnectionConfig::new());
+        assert!(matches!(result, Ok(NetworkMessage::Ping(1))));
+        assert_eq!(oversized, 1);
+    }
+
+    #[test]
+    fn test_addr_flood_disconnect() {
+        let theirs = ConnectionConfig::new().disconnect_on_addr_flood();
+        let (result, oversized) = send_addr_flood(theirs);
+        assert!(matches!(result, Err(Error::OversizedAddr(2))));
+        assert_eq!(oversized, 1);
+    }
+
+    #[test]
+    fn test_addr_flood_trusted() {
+        let theirs = ConnectionConfig::new().disconnect_on_addr_flood().trusted();
+        let (result, oversized) = send_addr_flood(theirs);
+        assert!(matches!(result, Ok(NetworkMessage::Ping(1))));
+        assert_eq!(oversized, 1);
+    }
+
+    #[test]
+    fn test_skip_until() {
+        let ((our_writer, mut our_reader, _), (_, mut their_reader, _)) = loopback(
+            ConnectionConfig::new(),
+            ConnectionConfig::new(),
+            TimeoutParams::default(),
+        )
+        .unwrap();
+        // A block that fails to decode is skipped without an error
+        our_writer
+            .send_message(NetworkMessage::Unknown {
+                command: p2p::message::CommandString::try_from_static("block").unwrap(),
+                payload: vec![1, 2, 3],
+            })
+            .unwrap();
+        our_writer.send_message(NetworkMessage::Ping(5)).unwrap();
+        our_writer.send_message(NetworkMessage::MemPool).unwrap();
+        let mempool = p2p::message::CommandString::try_from_static("mempool").unwrap();
+        let message = their_reader.skip_until(mempool).unwrap();
+        assert!(matches!(message, NetworkMessage::MemPool));
+        let pong = our_reader
+            .wait_for(
+                |message| matches!(message, NetworkMessage::Pong(_)),
+                Duration::from_secs(5),
+            )
+            .unwrap();
+        assert!(matches!(pong, NetworkMessage::Pong(5)));
+    }
+
+    #[test]
+    fn test_dispatch() {
+        #[derive(Default)]
+        struct Recorder(Vec<String>);
+
+        impl super::MessageHandler for Recorder {
+            fn on_headers(&mut self, headers: Vec<bitcoin::block::Header>) {
+                self.0.push(format!("headers {}", headers.len()));
+            }
+
+            fn on_ping(&mut self, nonce: u64) {
+                self.0.push(format!("ping {nonce}"));
+            }
+
+            fn on_other(&mut self, message: NetworkMessage) {
+                if let NetworkMessage::MemPool = message {
+                    self.0.push("mempool".to_string());
+                }
+            }
+        }
+
+        let ((our_writer, _, _), (_, mut their_reader, _)) = loopback(
+            ConnectionConfig::new(),
+            ConnectionConfig::new(),
+            TimeoutParams::default(),
+        )
+        .unwrap();
+        let genesis = bitcoin::constants::genesis_block(Network::Regtest).header;
+        our_writer.send_message(NetworkMessage::Ping(1)).unwrap();
+        our_writer
+            .send_message(NetworkMessage::Headers(p2p::message::HeadersMessage(vec![
+                genesis,
+            ])))
+            .unwrap();
+        our_writer.send_message(NetworkMessage::MemPool).unwrap();
+        our_writer.disconnect_flushing().unwrap();
+        let mut recorder = Recorder::default();
+        // Dispatching ends once the peer closes the connection
+        assert!(matches!(
+            their_reader.dispatch(&mut recorder),
+            Err(Error::PeerHalfClosed)
+        ));
+        assert_eq!(recorder.0, ["ping 1", "headers 1", "mempool"]);
+    }
+
+    fn receive_headers(
+        headers: Vec<bitcoin::block::Header>,
+    ) -> Result<Vec<bitcoin::block::Header>, Error> {
+        let ((our_writer, _, _), (_, mut their_reader, _)) = loopback(
+            ConnectionConfig::new(),
+            ConnectionConfig::new(),
+            TimeoutParams::default(),
+        )
+        .unwrap();
+        our_writer.send_message(NetworkMessage::Ping(1)).unwrap();
+        our_writer
+            .send_message(NetworkMessage::Headers(p2p::message::HeadersMessage(
+                headers,
+            )))
+            .unwrap();
+        their_reader.wait_for_headers(Duration::from_secs(5))
+    }
+
+    #[test]
+    fn test_wait_for_headers() {
+        let genesis = bitcoin::constants::genesis_block(Network::Regtest).header;
+        let child = bitcoin::block::Header {
+            prev_blockhash: genesis.block_hash(),
+            ..genesis
+        };
+        let headers = receive_headers(vec![genesis, child]).unwrap();
+        assert_eq!(headers, [genesis, child]);
+    }
+
+    #[test]
+    fn test_wait_for_headers_disconnected() {
+        let genesis = bitcoin::constants::genesis_block(Network::Regtest).header;
+        let result = receive_headers(vec![genesis, genesis]);
+        assert!(matches!(
+            result,
+            Err(Error::DisconnectedHeaders(hash)) if hash == genesis.block_hash()
+        ));
+    }
+
+    #[test]
+    fn test_handshake_jitter() {
+        assert_eq!(jitter_delay(None), Duration::ZERO);
+        assert_eq!(jitter_delay(Some(Duration::ZERO)), Duration::ZERO);
+        let max = Duration::from_millis(50);
+        assert!((0..100).all(|_| jitter_delay(Some(max)) < max));
+        // The delayed messages still complete the handshake
+        let ours = ConnectionConfig::new().handshake_jitter(max);
+        assert!(loopback(ours, ConnectionConfig::new(), TimeoutParams::default()).is_ok());
+    }
+
+    #[test]
+    fn test_undecodable_ban_score() {
+        let theirs = ConnectionConfig::new()
+            .tolerate_decode_errors(1)
+            .ban_score_points(30, 5);
+        let ((our_writer, _, _), (_, mut their_reader, their_metrics)) =
+            loopback(ConnectionConfig::new(), theirs, TimeoutParams::default()).unwrap();
+        our_writer
+            .send_message(NetworkMessage::Unknown {
+                command: p2p::message::CommandString::try_from_static("ping").unwrap(),
+                payload: vec![1, 2, 3],
+            })
+            .unwrap();
+        our_writer.send_message(NetworkMessage::Ping(1)).unwrap();
+        wait_for_ping(&mut their_reader);
+        assert_eq!(their_metrics.ban_score(), 30);
+    }
+
+    #[test]
+    fn test_try_read_message() {
+        let ((our_writer, _, _), (_, mut their_reader, _)) = loopback(
+            ConnectionConfig::new(),
+            ConnectionConfig::new(),
+            TimeoutParams::default(),
+        )
+        .unwrap();
+        while their_reader
+            .read_message_timeout(Duration::from_millis(100))
+            .unwrap()
+            .is_some()
+        {}
+        assert!(their_reader.try_read_message().unwrap().is_none());
+        our_writer.send_message(NetworkMessage::Ping(3)).unwrap();
+        let start = std::time::Instant::now();
+        let message = loop {
+            if let Some(message) = their_reader.try_read_message().unwrap() {
+                break message;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5));
+        };
+        assert!(matches!(message, NetworkMessage::Ping(3)));
+    }
+
+    #[test]
+    fn test_try_read_message_dropped() {
+        let theirs = ConnectionConfig::new().inbound_filter(|message| match message {
+            NetworkMessage::Ping(1) => FilterAction::Drop,
+            _ => FilterAction::Pass,
+        });
+        let ((our_writer, _, _), (_, mut their_reader, _)) =
+            loopback(ConnectionConfig::new(), theirs, TimeoutParams::default()).unwrap();
+        while their_reader
+            .read_message_timeout(Duration::from_millis(100))
+            .unwrap()
+            .is_some()
+        {}
+        our_writer.send_message(NetworkMessage::Ping(1)).unwrap();
+        our_writer.send_message(NetworkMessage::Ping(2)).unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        // The dropped message ends the call instead of waiting for the next one
+        assert!(their_reader.try_read_message().unwrap().is_none());
+        let message = their_reader.try_read_message().unwrap();
+        assert!(matches!(message, Some(NetworkMessage::Ping(2))));
+        assert!(their_reader.try_read_message().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_poll_split_header() {
+        let magic = Network::Regtest.default_network_magic();
+        let raw = p2p::message::RawNetworkMessage::new(magic, NetworkMessage::Ping(5));
+        let frame = bitcoin::consensus::serialize(&raw);
+        let closed = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let ((_, mut writer), (reader, _)) = memory_stream(&closed);
+        reader
+            .apply_read_timeout(Some(Duration::from_millis(1)))
+            .unwrap();
+        let mut reader = std::io::BufReader::new(reader);
+        let mut transport = ReadTransport::V1(magic);
+        let mut partial = PartialFrame::default();
+        std::io::Write::write_all(&mut writer, &frame[..10]).unwrap();
+        let poll = transport.poll_frame(&mut reader, &mut partial, None);
+        assert!(matches!(poll, Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::TimedOut));
+        assert_eq!(partial.bytes, frame[..10]);
+        std::io::Write::write_all(&mut writer, &frame[10..]).unwrap();
+        let (header, bytes) = transport
+            .poll_frame(&mut reader, &mut partial, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(header.command.to_string(), "ping");
+        assert_eq!(bytes, frame);
+        assert!(partial.is_empty());
+    }
+
+    #[test]
+    fn test_v2_contents() {
+        let magic = Network::Regtest.default_network_magic();
+        let contents = encode_v2_contents(magic, NetworkMessage::Ping(42));
+        assert_eq!(contents[0], 18);
+        let frame = v2_contents_to_frame(magic, &contents).unwrap();
+        let message = bitcoin::consensus::deserialize::<p2p::message::RawNetworkMessage>(&frame)
+            .unwrap()
+            .into_payload();
+        assert!(matches!(message, NetworkMessage::Ping(42)));
+        let contents = encode_v2_contents(magic, NetworkMessage::Verack);
+        assert_eq!(contents.len(), 13);
+        let frame = v2_contents_to_frame(magic, &contents).unwrap();
+        let message = bitcoin::consensus::deserialize::<p2p::message::RawNetworkMessage>(&frame)
+            .unwrap()
+            .into_payload();
+        assert!(matches!(message, NetworkMessage::Verack));
+        assert!(v2_contents_to_frame(magic, &[]).is_err());
+        assert!(v2_contents_to_frame(magic, &[0, 1]).is_err());
+    }
+
+    #[test]
+    fn test_v2_loopback() {
+        let v2 = ConnectionConfig::new().transport(TransportPreference::V2Only);
+        let ((our_writer, _, our_metrics), (_, mut their_reader, their_metrics)) =
+            loopback(v2.clone(), v2, TimeoutParams::default()).unwrap();
+        assert!(our_metrics.is_encrypted());
+        assert_eq!(their_metrics.transport(), TransportKind::V2);
+        our_writer.send_message(NetworkMessage::Ping(7)).unwrap();
+        let ping = their_reader
+            .wait_for(
+                |message| matches!(message, NetworkMessage::Ping(_)),
+                Duration::from_secs(5),
+            )
+            .unwrap();
+        assert!(matches!(ping, NetworkMessage::Ping(7)));
+    }
+
+    // Send a single ping once the messages trailing the handshake are read, returning the bytes
+    // written by our side and read by theirs.
+    fn ping_byte_deltas(config: ConnectionConfig) -> (u64, u64) {
+        let ((our_writer, _, our_metrics), (_, mut their_reader, their_metrics)) =
+            loopback(config.clone(), config, TimeoutParams::default()).unwrap();
+        while their_reader
+            .read_message_timeout(Duration::from_millis(100))
+            .unwrap()
+            .is_some()
+        {}
+        let sent = our_metrics.bytes_sent();
+        let received = their_metrics.bytes_received();
+        our_writer.send_message(NetworkMessage::Ping(7)).unwrap();
+        // Wait for the writer to finish so the count of sent bytes is final
+        our_writer.disconnect_flushing().unwrap();
+        their_reader
+            .wait_for(
+                |message| matches!(message, NetworkMessage::Ping(7)),
+                Duration::from_secs(5),
+            )
+            .unwrap();
+        (
+            our_metrics.bytes_sent() - sent,
+            their_metrics.bytes_received() - received,
+        )
+    }
+
+    #[test]
+    fn test_bytes_sent_received() {
+        // A 24 byte header and an 8 byte nonce
+        assert_eq!(ping_byte_deltas(ConnectionConfig::new()), (32, 32));
+        // A 3 byte length, a header byte, a short ID, the nonce and a 16 byte tag, which is smaller
+        // than the v1 frame the message is decoded from
+        let v2 = ConnectionConfig::new().transport(TransportPreference::V2Only);
+        assert_eq!(ping_byte_deltas(v2), (29, 29));
+    }
+
+    #[test]
+    #[cfg(feature = "debug-v2-session")]
+    fn test_v2_session_id() {
+        let v2 = ConnectionConfig::new().transport(TransportPreference::V2Only);
+        let ((_, _, our_metrics), (_, _, their_metrics)) =
+            loopback(v2.clone(), v2, TimeoutParams::default()).unwrap();
+        assert!(our_metrics.v2_session_id().is_some());
+        assert_eq!(our_metrics.v2_session_id(), their_metrics.v2_session_id());
+        let ((_, _, v1_metrics), _) = loopback(
+            ConnectionConfig::new(),
+            ConnectionConfig::new(),
+            TimeoutParams::default(),
+        )
+        .unwrap();
+        assert!(v1_metrics.v2_session_id().is_none());
+    }
+
+    #[test]
+    fn test_no_initial_version() {
+        let closed = Arc::new(AtomicBool::new(false));
+        let ((our_reader, our_writer), _silent) = memory_stream(&closed);
+        let mut timeout_params = TimeoutParams::default();
+        timeout_params.initial_read_timeout(Duration::from_millis(200));
+        let result = ConnectionConfig::new().handshake_over(our_reader, our_writer, timeout_params);
+        assert!(matches!(result, Err(Error::NoInitialVersion)));
+    }
+
+    #[test]
+    fn test_missing_verack() {
+        let config = ConnectionConfig::new();
+        let magic = config.network().default_network_magic();
+        let unix_time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap();
+        let version = config.build_our_version(unix_time, 1, None);
+        let raw = p2p::message::RawNetworkMessage::new(magic, NetworkMessage::Version(version));
+        let closed = Arc::new(AtomicBool::new(false));
+        let ((our_reader, our_writer), (_their_reader, mut their_writer)) = memory_stream(&closed);
+        // The peer sends its version but never acknowledges ours
+        their_writer
+            .write_all(&bitcoin::consensus::serialize(&raw))
+            .unwrap();
+        let mut timeout_params = TimeoutParams::default();
+        timeout_params.handshake_timeout(Duration::from_millis(200));
+        let result = config.handshake_over(our_reader, our_writer, timeout_params);
+        assert!(matches!(result, Err(Error::MissingVerack)));
+    }
+
+    #[test]
+    fn test_their_advertised_address() {
+        let config = ConnectionConfig::new();
+        let magic = config.network().default_network_magic();
+        let unix_time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap();
+        let addr = SocketAddr::from((Ipv4Addr::new(1, 2, 3, 4), 8333));
+        let advertised = p2p::Address::new(&addr, p2p::ServiceFlags::NETWORK);
+        let mut version = config.build_our_version(unix_time, 1, None);
+        version.sender = advertised.clone();
+        let closed = Arc::new(AtomicBool::new(false));
+        let ((our_reader, our_writer), (_their_reader, mut their_writer)) = memory_stream(&closed);
+        for message in [NetworkMessage::Version(version), NetworkMessage::Verack] {
+            let raw = p2p::message::RawNetworkMessage::new(magic, message);
+            their_writer
+                .write_all(&bitcoin::consensus::serialize(&raw))
+                .unwrap();
+        }
+        let (_, _, metrics) = config
+            .handshake_over(our_reader, our_writer, TimeoutParams::default())
+            .unwrap();
+        assert_eq!(metrics.their_advertised_address(), &advertised);
+    }
+
+    #[test]
+    fn test_sends_v1_version() {
+        let magic = Network::Regtest.default_network_magic();
+        let prefix = super::v1_version_prefix(magic);
+        let closed = Arc::new(AtomicBool::new(false));
+        let ((_, mut writer), (reader, _)) = memory_stream(&closed);
+        let mut buf_reader = BufReader::new(reader);
+        // The prefix arriving in parts is only recognized once complete
+        writer.write_all(&prefix[..4]).unwrap();
+        let delayed = prefix[4..].to_vec();
+        let sender = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            writer.write_all(&delayed).unwrap();
+            writer
+        });
+        assert!(sends_v1_version(&mut buf_reader, magic).unwrap());
+        let _writer = sender.join().unwrap();
+        let mut received = vec![0; prefix.len()];
+        buf_reader.read_exact(&mut received).unwrap();
+        assert_eq!(received, prefix);
+        // A key that only starts with the magic is not taken as a version message
+        let ((_, mut key_writer), (key_reader, _)) = memory_stream(&closed);
+        let mut key_reader = BufReader::new(key_reader);
+        key_writer.write_all(&prefix[..4]).unwrap();
+        key_writer.write_all(&[0xff; 60]).unwrap();
+        assert!(!sends_v1_version(&mut key_reader, magic).unwrap());
+    }
+
+    #[test]
+    fn test_v2_handshake_bounds() {
+        let closed = Arc::new(AtomicBool::new(false));
+        // A peer that stops after sending its key fails the handshake at the deadline
+        let ((reader, mut writer), (_, mut their_writer)) = memory_stream(&closed);
+        their_writer.write_all(&[7; 64]).unwrap();
+        let mut buf_reader = BufReader::new(reader);
+        let deadline = Instant::now() + Duration::from_millis(200);
+        let silent = v2_handshake(
+            Network::Regtest,
+            Role::Initiator,
+            &mut buf_reader,
+            &mut writer,
+            deadline,
+        );
+        assert!(
+            matches!(silent, Err(Error::V2Handshake(e)) if e.kind() == io::ErrorKind::TimedOut)
+        );
+        assert!(Instant::now() < deadline + Duration::from_secs(1));
+        // Garbage past the limit without a terminator is rejected
+        let ((reader, mut writer), (_, mut their_writer)) = memory_stream(&closed);
+        their_writer.write_all(&[7; 64]).unwrap();
+        their_writer.write_all(&[9; 5_000]).unwrap();
+        let mut buf_reader = BufReader::new(reader);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let garbage = v2_handshake(
+            Network::Regtest,
+            Role::Initiator,
+            &mut buf_reader,
+            &mut writer,
+            deadline,
+        );
+        assert!(matches!(
+            garbage,
+            Err(Error::V2Handshake(_) | Error::V2Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn test_v2_downgrade() {
+        let auto = ConnectionConfig::new().transport(TransportPreference::Auto);
+        let ((_, _, our_metrics), (_, _, their_metrics)) = loopback(
+            ConnectionConfig::new(),
+            auto.clone(),
+            TimeoutParams::default(),
+        )
+        .unwrap();
+        assert!(!our_metrics.is_encrypted());
+        assert!(!their_metrics.is_encrypted());
+        let v1_peer = loopback(auto, ConnectionConfig::new(), TimeoutParams::default());
+        assert!(matches!(v1_peer, Err(Error::V2Handshake(_))));
+        let v2_only = ConnectionConfig::new().transport(TransportPreference::V2Only);
+        assert!(loopback(ConnectionConfig::new(), v2_only, TimeoutParams::default()).is_err());
+    }
+
+    #[test]
+    fn test_transport_stats() {
+        let mut stats = TransportStats::new();
+        assert_eq!(stats.ratio(TransportKind::V2), None);
+        let v2 = ConnectionConfig::new().transport(TransportPreference::V2Only);
+        let ((_, _, v2_metrics), _) = loopback(v2.clone(), v2, TimeoutParams::default()).unwrap();
+        let ((_, _, v1_metrics), _) = loopback(
+            ConnectionConfig::new(),
+            ConnectionConfig::new(),
+            TimeoutParams::default(),
+        )
+        .unwrap();
+        stats.record(&v2_metrics);
+        stats.record(&v1_metrics);
+        stats.record(&v1_metrics);
+        stats.record(&v2_metrics);
+        assert_eq!(stats.total(), 4);
+        assert_eq!(stats.count(TransportKind::V1), 2);
+        assert_eq!(stats.count(TransportKind::V1Fallback), 0);
+        assert_eq!(stats.encrypted_ratio(), Some(0.5));
+        assert_eq!(stats.ratio(TransportKind::V1Fallback), Some(0.0));
+    }
+
+    #[test]
+    fn test_v2_fallback() {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let v1_peer = std::thread::spawn(move || {
+            let mut results = Vec::new();
+            for _ in 0..2 {
+                let (tcp_stream, _) = listener.accept().unwrap();
+                let config = ConnectionConfig::new().inbound();
+                results.push(
+                    ConnectionExt::handshake(config, tcp_stream, TimeoutParams::default())
+                        .map(|(_, _, metrics)| metrics.transport()),
+                );
+            }
+            results
+        });
+        let auto = ConnectionConfig::new().transport(TransportPreference::Auto);
+        let (_, _, metrics) = auto
+            .open_connection(addr, TimeoutParams::default())
+            .unwrap();
+        assert_eq!(metrics.transport(), TransportKind::V1Fallback);
+        assert!(!metrics.is_encrypted());
+        let results = v1_peer.join().unwrap();
+        assert!(results[0].is_err());
+        assert_eq!(*results[1].as_ref().unwrap(), TransportKind::V1);
+    }
+
+    #[test]
+    fn test_disconnect_flushing() {
+        let ((our_writer, _our_reader, our_metrics), (_their_writer, mut their_reader, _)) =
+            loopback(
+                ConnectionConfig::new().buffered_writes(true),
+                ConnectionConfig::new(),
+                TimeoutParams::default(),
+            )
+            .unwrap();
+        our_writer.send_message(NetworkMessage::Ping(1)).unwrap();
+        our_writer.send_message(NetworkMessage::Ping(2)).unwrap();
+        our_writer.disconnect_flushing().unwrap();
+        let ping = p2p::message::CommandString::try_from_static("ping").unwrap();
+        assert_eq!(our_metrics.bytes_by_type().get(&ping), Some(&64));
+        let mut pings = Vec::new();
+        while pings.len() < 2 {
+            match their_reader.read_message_timeout(Duration::from_secs(5)) {
+                Ok(Some(NetworkMessage::Ping(nonce))) => pings.push(nonce),
+                Ok(Some(_)) => continue,
+                other => panic!("expected the queued pings, got {other:?}"),
+            }
+        }
+        assert_eq!(pings, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_detect_network_by_genesis() {
+        let (mut ours, (their_writer, mut their_reader, _)) = loopback(
+            ConnectionConfig::new(),
+            ConnectionConfig::new(),
+            TimeoutParams::default(),
+        )
+        .unwrap();
+        let responder = std::thread::spawn(move || {
+            their_reader
+                .wait_for(
+                    |message| matches!(message, NetworkMessage::GetData(_)),
+                    Duration::from_secs(5),
+                )
+                .unwrap();
+            let genesis = bitcoin::constants::genesis_block(Network::Regtest);
+            their_writer
+                .send_message(NetworkMessage::Block(genesis))
+                .unwrap();
+            (their_writer, their_reader)
+        });
+        let network = detect_network_by_genesis(&mut ours, Duration::from_secs(5));
+        assert_eq!(network, Some(Network::Regtest));
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn test_unrequested_blocks() {
+        let ((our_writer, mut our_reader, our_metrics), (their_writer, mut their_reader, _)) =
+            loopback(
+                ConnectionConfig::new().max_unrequested_blocks(1),
+                ConnectionConfig::new(),
+                TimeoutParams::default(),
+            )
+            .unwrap();
+        let genesis = bitcoin::constants::genesis_block(Network::Regtest);
+        let inventory = vec![p2p::message_blockdata::Inventory::Block(
+            genesis.block_hash(),
+        )];
+        our_writer
+            .send_message(NetworkMessage::GetData(p2p::message::InventoryPayload(
+                inventory,
+            )))
+            .unwrap();
+        their_reader
+            .wait_for(
+                |message| matches!(message, NetworkMessage::GetData(_)),
+                Duration::from_secs(5),
+            )
+            .unwrap();
+        for _ in 0..3 {
+            their_writer
+                .send_message(NetworkMessage::Block(genesis.clone()))
+                .unwrap();
+        }
+        let is_block = |message: &NetworkMessage| matches!(message, NetworkMessage::Block(_));
+        our_reader
+            .wait_for(is_block, Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(our_metrics.unrequested_block_count(), 0);
+        our_reader
+            .wait_for(is_block, Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(our_metrics.unrequested_block_count(), 1);
+        assert!(matches!(
+            our_reader.wait_for(is_block, Duration::from_secs(5)),
+            Err(Error::UnrequestedBlockFlood(2))
+        ));
+    }
+
+    #[test]
+    fn test_auto_getheaders_on_block_inv() {
+        use p2p::{message::InventoryPayload, message_blockdata::Inventory};
+
+        let genesis = bitcoin::constants::genesis_block(Network::Regtest).block_hash();
+        let ((_our_writer, mut our_reader, _), (their_writer, mut their_reader, _)) = loopback(
+            ConnectionConfig::new().auto_getheaders_on_block_inv(move || vec![genesis]),
+            ConnectionConfig::new(),
+            TimeoutParams::default(),
+        )
+        .unwrap();
+        let inv = InventoryPayload(vec![Inventory::Block(genesis)]);
+        their_writer.send_message(NetworkMessage::Inv(inv)).unwrap();
+        our_reader
+            .wait_for(
+                |message| matches!(message, NetworkMessage::Inv(_)),
+                Duration::from_secs(5),
+            )
+            .unwrap();
+        let get_headers = their_reader
+            .wait_for(
+                |message| matches!(message, NetworkMessage::GetHeaders(_)),
+                Duration::from_secs(5),
+            )
+            .unwrap();
+        let NetworkMessage::GetHeaders(get_headers) = get_headers else {
+            unreachable!()
+        };
+        assert_eq!(get_headers.locator_hashes, vec![genesis]);
+    }
+
+    #[test]
+    fn test_utxos_decode() {
+        let mut payload = Vec::new();
+        payload.extend(840_000u32.to_le_bytes());
+        payload.extend([0; 32]);
+        // Bitmap of one byte, where the second outpoint is unspent
+        payload.extend([0x01, 0b10]);
+        payload.push(0x01);
+        payload.extend(2u32.to_le_bytes());
+        payload.extend(839_999u32.to_le_bytes());
+        payload.extend(5_000u64.to_le_bytes());
+        payload.push(0x00);
+        let message = NetworkMessage::Unknown {
+            command: p2p::message::CommandString::try_from_static("utxos").unwrap(),
+            payload,
+        };
+        let utxos = Utxos::from_message(&message).unwrap().unwrap();
+        assert_eq!(utxos.chain_height, 840_000);
+        assert!(!utxos.is_unspent(0));
+        assert!(utxos.is_unspent(1));
+        assert_eq!(utxos.outputs.len(), 1);
+        assert_eq!(utxos.outputs[0].height, 839_999);
+        assert!(Utxos::from_message(&NetworkMessage::Verack).is_none());
+    }
+
+    #[test]
+    fn test_violates_version() {
+        let wtxid = NetworkMessage::WtxidRelay;
+        assert!(violates_version(
+            &wtxid,
+            ProtocolVersion::SENDHEADERS_VERSION
+        ));
+        assert!(!violates_version(
+            &wtxid,
+            ProtocolVersion::WTXID_RELAY_VERSION
+        ));
+        let addrv2 = NetworkMessage::SendAddrV2;
+        assert!(violates_version(
+            &addrv2,
+            ProtocolVersion::WTXID_RELAY_VERSION
+        ));
+        assert!(!violates_version(
+            &addrv2,
+            ProtocolVersion::from_nonstandard(70016)
+        ));
+        let ping = NetworkMessage::Ping(1);
+        assert!(!violates_version(
+            &ping,
+            ProtocolVersion::MIN_PEER_PROTO_VERSION
+        ));
+    }
+
+    #[test]
+    fn test_error_severity() {
+        let refused = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+        assert_eq!(Error::Io(refused).severity(), ErrorSeverity::Retryable);
+        let magic = Network::Regtest.default_network_magic();
+        assert_eq!(
+            Error::UnexpectedMagic(magic).severity(),
+            ErrorSeverity::Fatal
+        );
+        assert_eq!(
+            Error::Handshake(handshake::Error::InvalidHeight(-1)).severity(),
+            ErrorSeverity::BanWorthy
+        );
+    }
+
+    #[test]
+    fn test_disconnect_cause() {
+        let reset = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert_eq!(Error::Io(reset).disconnect_cause(), DisconnectCause::Reset);
+        let pipe = std::io::Error::from(std::io::ErrorKind::BrokenPipe);
+        assert_eq!(
+            Error::Io(pipe).disconnect_cause(),
+            DisconnectCause::BrokenPipe
+        );
+        assert_eq!(
+            Error::PeerHalfClosed.disconnect_cause(),
+            DisconnectCause::CleanClose
+        );
+        assert_eq!(
+            Error::WaitTimeout.disconnect_cause(),
+            DisconnectCause::Timeout
+        );
+        assert_eq!(
+            Error::ChannelClosed.disconnect_cause(),
+            DisconnectCause::Other
+        );
+    }
+
+    #[test]
+    fn test_message_checksum() {
+        assert_eq!(message_checksum(&[]), [0x5d, 0xf6, 0xe0, 0xe2]);
+        let magic = Network::Regtest.default_network_magic();
+        let ping = NetworkMessage::Ping(42);
+        let bytes =
+            bitcoin::consensus::serialize(&p2p::message::RawNetworkMessage::new(magic, ping));
+        assert_eq!(message_checksum(&bytes[24..]), bytes[20..24]);
+    }
+
+    #[test]
+    fn test_read_memory_budget() {
+        let budget = ReadMemoryBudget::new(100);
+        let first = budget.reserve(60).unwrap();
+        assert_eq!(budget.in_use(), 60);
+        assert!(matches!(
+            budget.reserve(41),
+            Err(Error::ReadMemoryExhausted(41))
+        ));
+        let second = budget.clone().reserve(40).unwrap();
+        assert_eq!(budget.in_use(), 100);
+        drop(first);
+        drop(second);
+        assert_eq!(budget.in_use(), 0);
+    }
+
+    #[test]
+    fn test_read_memory_budget_loopback() {
+        let budget = ReadMemoryBudget::new(1_000);
+        let ((our_writer, _, _), (_, mut their_reader, _)) = loopback(
+            ConnectionConfig::new(),
+            ConnectionConfig::new().read_memory_budget(budget.clone()),
+            TimeoutParams::default(),
+        )
+        .unwrap();
+        our_writer.send_message(NetworkMessage::Ping(1)).unwrap();
+        let large = NetworkMessage::Unknown {
+            command: p2p::message::CommandString::try_from_static("large").unwrap(),
+            payload: vec![0; 2_000],
+        };
+        our_writer.send_message(large).unwrap();
+        let result = loop {
+            if let Err(e) = their_reader.read_message() {
+                break e;
+            }
+        };
+        assert!(matches!(result, Error::ReadMemoryExhausted(2_024)));
+        assert_eq!(budget.in_use(), 0);
+        // The body was not read, so the connection can not continue
+        assert!(matches!(
+            their_reader.read_message(),
+            Err(Error::PeerHalfClosed)
+        ));
+    }
+
+    #[test]
+    fn test_read_memory_budget_v2() {
+        let budget = ReadMemoryBudget::new(1_000);
+        let v2 = ConnectionConfig::new().transport(TransportPreference::V2Only);
+        let ((our_writer, _, _), (_, mut their_reader, _)) = loopback(
+            v2.clone(),
+            v2.read_memory_budget(budget.clone()),
+            TimeoutParams::default(),
+        )
+        .unwrap();
+        our_writer.send_message(NetworkMessage::Ping(1)).unwrap();
+        let large = NetworkMessage::Unknown {
+            command: p2p::message::CommandString::try_from_static("large").unwrap(),
+            payload: vec![0; 2_000],
+        };
+        our_writer.send_message(large).unwrap();
+        let result = loop {
+            if let Err(e) = their_reader.read_message() {
+                break e;
+            }
+        };
+        assert!(matches!(result, Error::ReadMemoryExhausted(len) if len > 2_000));
+        assert_eq!(budget.in_use(), 0);
+        assert!(matches!(
+            their_reader.read_message(),
+            Err(Error::PeerHalfClosed)
+        ));
+    }
+
+    #[test]
+    fn test_oversized_message() {
+        let magic = Network::Regtest.default_network_magic();
+        let raw = p2p::message::RawNetworkMessage::new(magic, NetworkMessage::Ping(5));
+        let mut frame = bitcoin::consensus::serialize(&raw);
+        frame[16..20].copy_from_slice(&4_000_001_u32.to_le_bytes());
+        // The length is checked before it is reserved against the budget
+        let budget = ReadMemoryBudget::new(1_000);
+        let result = ReadTransport::V1(magic).read_header(&mut frame.as_slice(), Some(&budget));
+        assert!(matches!(result, Err(Error::OversizedMessage(4_000_001))));
+        let mut partial = PartialFrame::default();
+        let result =
+            ReadTransport::V1(magic).poll_frame(&mut frame.as_slice(), &mut partial, Some(&budget));
+        assert!(matches!(result, Err(Error::OversizedMessage(4_000_001))));
+        assert_eq!(budget.in_use(), 0);
+    }
+
+    #[test]
+    fn test_buffer_pool() {
+        let pool = BufferPool::new(1_024, 1);
+        let buf = pool.take(100);
+        assert_eq!(buf.len(), 100);
+        pool.give(buf);
+        assert_eq!(pool.available(), 1);
+        let buf = pool.take(24);
+        assert_eq!(buf, vec![0; 24]);
+        assert_eq!(pool.available(), 0);
+        pool.give(buf);
+        pool.give(vec![0; 10]);
+        assert_eq!(pool.available(), 1);
+        let large = pool.take(2_048);
+        pool.take(10);
+        pool.give(large);
+        assert_eq!(pool.available(), 0);
+    }
+
+    #[test]
+    fn test_message_hex_round_trip() {
+        let magic = Network::Regtest.default_network_magic();
+        let ping = NetworkMessage::Ping(42);
+        let hex = message_to_hex(magic, &ping);
+        let decoded = message_from_hex(&hex).unwrap();
+        assert_eq!(decoded, ping);
+        assert!(message_from_hex(&hex[1..]).is_err());
+        assert!(message_from_hex("zz").is_err());
+    }
+
+    #[test]
+    fn test_peer_history() {
+        let peer = SocketAddr::from((Ipv4Addr::LOCALHOST, 8333));
+        let other = SocketAddr::from((Ipv4Addr::LOCALHOST, 8334));
+        let mut history = PeerHistory::new();
+        history.connected(peer);
+        history.disconnected(peer, Duration::from_secs(10));
+        history.connected(peer);
+        history.disconnected(peer, Duration::from_secs(5));
+        assert_eq!(history.total_uptime(&peer), Duration::from_secs(15));
+        assert_eq!(history.disconnect_count(&peer), 2);
+        assert_eq!(history.reconnect_count(&peer), 1);
+        assert_eq!(history.total_uptime(&other), Duration::ZERO);
+        assert_eq!(history.disconnect_count(&other), 0);
+    }
+
+    #[test]
+    fn test_open_any_refused() {
+        let addrs: Vec<SocketAddr> = (0..3)
+            .map(|_| {
+                let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+                listener.local_addr().unwrap()
+            })
+            .collect();
+        let (connections, errors) = open_any(
+            &ConnectionConfig::new(),
+            addrs,
+            TimeoutParams::default(),
+            2,
+            2,
+        );
+        assert!(connections.is_empty());
+        assert_eq!(errors.len(), 3);
+    }
+
+    #[test]
+    fn test_peer_manager_failing_candidate() {
+        let refused = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let reachable = listener.local_addr().unwrap();
+        let peer = std::thread::spawn(move || {
+            let (tcp_stream, _) = listener.accept().unwrap();
+            let config = ConnectionConfig::new().inbound();
+            ConnectionExt::handshake(config, tcp_stream, TimeoutParams::default()).unwrap()
+        });
+        let mut manager = PeerManager::new(ConnectionConfig::new(), TimeoutParams::default(), 1);
+        manager.add_addresses([refused, reachable]);
+        let errors = manager.maintain();
+        assert_eq!(errors.len(), 1);
+        let peers: Vec<SocketAddr> = manager.peers().map(|(_, addr, _)| addr).collect();
+        assert_eq!(peers, vec![reachable]);
+        let _connection = peer.join().unwrap();
+    }
+
+    #[test]
+    fn test_peer_manager_candidates() {
+        let addr = |port| SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        let mut manager = PeerManager::new(ConnectionConfig::new(), TimeoutParams::default(), 1)
+            .max_candidates(2);
+        manager.add_addresses([addr(1), addr(1), addr(2), addr(3)]);
+        assert_eq!(manager.candidates, [addr(1), addr(2)]);
+        assert_eq!(manager.pop_candidate(), Some(addr(1)));
+        manager.add_addresses([addr(2), addr(1)]);
+        assert_eq!(manager.candidates, [addr(2), addr(1)]);
+    }
+
+    fn accept_inbound(listener: &std::net::TcpListener) -> Connection {
+        let (tcp_stream, _) = listener.accept().unwrap();
+        let config = ConnectionConfig::new().inbound();
+        ConnectionExt::handshake(config, tcp_stream, TimeoutParams::default()).unwrap()
+    }
+
+    #[test]
+    fn test_peer_manager_manual_peer() {
+        let manual = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let manual_addr = manual.local_addr().unwrap();
+        let automatic = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let automatic_addr = automatic.local_addr().unwrap();
+        let (closed_tx, closed_rx) = std::sync::mpsc::channel();
+        let manual_peer = std::thread::spawn(move || {
+            // The first connection is closed by the peer once we are told to
+            let (writer, _reader, _) = accept_inbound(&manual);
+            closed_rx.recv().unwrap();
+            writer.shutdown().unwrap();
+            accept_inbound(&manual)
+        });
+        let automatic_peer = std::thread::spawn(move || accept_inbound(&automatic));
+        let mut manager = PeerManager::new(ConnectionConfig::new(), TimeoutParams::default(), 1);
+        manager.add_manual_peer(manual_addr);
+        manager.add_addresses([automatic_addr]);
+        assert!(manager.maintain().is_empty());
+        let _automatic_connection = automatic_peer.join().unwrap();
+        let kind_of = |manager: &PeerManager, addr| {
+            let (peer_id, _, _) = manager.peers().find(|(_, peer, _)| *peer == addr)?;
+            manager.connection_kind(peer_id).map(|kind| (peer_id, kind))
+        };
+        let (first_id, kind) = kind_of(&manager, manual_addr).unwrap();
+        assert_eq!(kind, ConnectionKind::Manual);
+        let (automatic_id, kind) = kind_of(&manager, automatic_addr).unwrap();
+        assert_eq!(kind, ConnectionKind::Automatic);
+        assert_eq!(manager.evict(), Some(automatic_id));
+        assert_eq!(manager.evict(), None);
+        assert!(kind_of(&manager, manual_addr).is_some());
+        closed_tx.send(()).unwrap();
+        let start = Instant::now();
+        let second_id = loop {
+            manager.maintain();
+            if let Some((peer_id, _)) = kind_of(&manager, manual_addr) {
+                if peer_id != first_id {
+                    break peer_id;
+                }
+            }
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(
+            manager.connection_kind(second_id),
+            Some(ConnectionKind::Manual)
+        );
+        let _manual_connection = manual_peer.join().unwrap();
+    }
+
+    #[test]
+    fn test_peer_manager_block_relay_target() {
+        use p2p::message_blockdata::Inventory;
+
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = std::thread::spawn(move || accept_inbound(&listener));
+        let mut manager = PeerManager::new(ConnectionConfig::new(), TimeoutParams::default(), 0)
+            .block_relay_target(1);
+        manager.add_addresses([addr]);
+        assert!(manager.maintain().is_empty());
+        let (peer_id, _, _) = manager.peers().next().unwrap();
+        assert_eq!(
+            manager.connection_kind(peer_id),
+            Some(ConnectionKind::BlockRelayOnly)
+        );
+        // A block-relay-only connection does not accept transaction announcements
+        let (writer, _reader, _) = peer.join().unwrap();
+        let txid = bitcoin::Txid::from_byte_array([1; 32]);
+        writer
+            .send_message(NetworkMessage::Inv(p2p::message::InventoryPayload(vec![
+                Inventory::Transaction(txid),
+            ])))
+            .unwrap();
+        let start = Instant::now();
+        let errors = loop {
+            let errors = manager.maintain();
+            if !errors.is_empty() {
+                break errors;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert!(errors
+            .iter()
+            .any(|e| matches!(e, Error::UnexpectedTransactionRelay(_))));
+    }
+
+    #[test]
+    fn test_addr_budget() {
+        let now = std::time::Instant::now();
+        let mut budget = AddrBudget::new(10, now);
+        assert_eq!(budget.take(4, now), 4);
+        assert_eq!(budget.take(10, now), 6);
+        assert_eq!(budget.take(1, now), 0);
+        assert_eq!(budget.take(5, now + Duration::from_secs(20)), 2);
+        budget.requested();
+        assert_eq!(budget.take(20, now + Duration::from_secs(20)), 10);
+        assert_eq!(budget.take(1, now + Duration::from_secs(1_000)), 1);
+    }
+
+    #[test]
+    fn test_headers_tracker() {
+        let start = std::time::Instant::now();
+        let timeout = Duration::from_secs(10);
+        let mut tracker = HeadersTracker::new(timeout);
+        assert!(tracker.check(start + timeout * 2).is_ok());
+        tracker.requested(start);
+        assert!(tracker.check(start + timeout).is_ok());
+        assert!(matches!(
+            tracker.check(start + timeout * 2),
+            Err(Error::HeadersTimeout)
+        ));
+        let genesis = bitcoin::constants::genesis_block(Network::Regtest).header;
+        tracker.received(&vec![genesis; 2_000], start + timeout);
+        assert!(tracker.check(start + timeout * 2).is_ok());
+        tracker.received(&[], start + timeout);
+        assert!(!tracker.is_waiting());
+    }
+
+    #[test]
+    fn test_addr_refresher() {
+        let start = std::time::Instant::now();
+        let first = SocketAddr::from((Ipv4Addr::new(1, 2, 3, 4), 8333));
+        let second = SocketAddr::from((Ipv4Addr::new(5, 6, 7, 8), 8333));
+        let mut refresher = AddrRefresher::new(Duration::from_secs(60));
+        assert_eq!(refresher.next([first], start), Some(first));
+        assert_eq!(refresher.next([first, second], start), Some(second));
+        assert_eq!(refresher.next([first, second], start), None);
+        let later = start + Duration::from_secs(60);
+        assert_eq!(refresher.next([second, first], later), Some(first));
+    }
+
+    #[test]
+    fn test_connect_from() {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let local = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let stream = connect_from(
+            local,
+            listener.local_addr().unwrap(),
+            Duration::from_secs(2),
+        )
+        .unwrap();
+        assert_eq!(stream.local_addr().unwrap(), local);
+        let (_, peer) = listener.accept().unwrap();
+        assert_eq!(peer, local);
+    }
+
+    #[test]
+    fn test_socks5_connect() {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let mut replies = Vec::new();
+            for reply in [0x00, 0x05] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut greeting = [0; 4];
+                stream.read_exact(&mut greeting).unwrap();
+                assert_eq!(greeting, [5, 2, 0, 2]);
+                stream.write_all(&[5, 2]).unwrap();
+                let mut auth = [0; 9];
+                stream.read_exact(&mut auth).unwrap();
+                assert_eq!(&auth, b"\x01\x03bob\x03pwd");
+                stream.write_all(&[1, 0]).unwrap();
+                let mut request = vec![0; 5 + "example.onion".len() + 2];
+                stream.read_exact(&mut request).unwrap();
+                replies.push(request);
+                stream
+                    .write_all(&[5, reply, 0, 1, 127, 0, 0, 1, 0x20, 0x8d])
+                    .unwrap();
+            }
+            replies
+        });
+        let credentials = ("bob".to_string(), "pwd".to_string());
+        let target = ProxyTarget::from(("example.onion", 8333));
+        let mut stream = std::net::TcpStream::connect(proxy).unwrap();
+        socks5_connect(&mut stream, &target, Some(&credentials)).unwrap();
+        let mut stream = std::net::TcpStream::connect(proxy).unwrap();
+        let refused = socks5_connect(&mut stream, &target, Some(&credentials));
+        assert!(matches!(refused, Err(Error::ProxyReply(5))));
+        let requests = server.join().unwrap();
+        assert_eq!(&requests[0][..5], &[5, 1, 0, 3, 13]);
+        assert_eq!(&requests[0][5..18], b"example.onion");
+        assert_eq!(&requests[0][18..], &8333u16.to_be_bytes());
+    }
+
+    #[test]
+    fn test_transaction_inventory() {
+        use bitcoin::{FeeRate, Txid, Wtxid};
+        use p2p::message_blockdata::Inventory;
+
+        let announcement = |byte: u8, fee_rate: FeeRate| TxAnnouncement {
+            txid: Txid::from_byte_array([byte; 32]),
+            wtxid: Wtxid::from_byte_array([byte + 1; 32]),
+            fee_rate,
+        };
+        let transactions = vec![
+            announcement(1, FeeRate::ZERO),
+            announcement(3, FeeRate::DUST),
+        ];
+        let min = Some(FeeRate::BROADCAST_MIN);
+        let inventory = transaction_inventory(transactions.clone(), min, true);
+        assert_eq!(inventory, vec![Inventory::WTx(transactions[1].wtxid)]);
+        let inventory = transaction_inventory(transactions.clone(), None, false);
+        assert_eq!(
+            inventory,
+            vec![
+                Inventory::Transaction(transactions[0].txid),
+                Inventory::Transaction(transactions[1].txid)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_connect_attempt_tracker() {
+        let start = std::time::Instant::now();
+        let secs = Duration::from_secs;
+        let addr = SocketAddr::from((Ipv4Addr::new(1, 2, 3, 4), 8333));
+        let mut tracker = ConnectAttemptTracker::new(secs(1), secs(8));
+        assert!(tracker.should_attempt(addr, start));
+        tracker.failed(addr, start);
+        assert!(!tracker.should_attempt(addr, start));
+        assert!(tracker.should_attempt(addr, start + secs(1)));
+        tracker.failed(addr, start + secs(1));
+        tracker.failed(addr, start + secs(3));
+        assert!(!tracker.should_attempt(addr, start + secs(6)));
+        assert!(tracker.should_attempt(addr, start + secs(7)));
+        tracker.succeeded(addr);
+        assert!(tracker.should_attempt(addr, start + secs(3)));
+    }
+
+    #[test]
+    fn test_bootstrap_addresses() {
+        let known = [SocketAddr::from((Ipv4Addr::new(1, 2, 3, 4), 8333))];
+        let seed = IpAddr::V4(Ipv4Addr::new(5, 6, 7, 8));
+        let resolver = |_: &str| Ok::<_, std::io::Error>(vec![seed]);
+        let addrs = bootstrap_addresses(&known, Network::Bitcoin, 1, &resolver);
+        assert_eq!(addrs, known);
+        let addrs = bootstrap_addresses(&known, Network::Bitcoin, 2, &resolver);
+        assert_eq!(addrs, vec![known[0], SocketAddr::new(seed, 8333)]);
+    }
+
+    #[test]
+    fn test_prepare_addr_relay() {
+        let now = 1_700_000_000;
+        let received = [
+            (1, now),
+            (2, now - 5 * 60),
+            (3, now - 60 * 60),
+            (4, now + 60 * 60),
+            (5, 0),
+        ];
+        let relay = prepare_addr_relay(&received, Duration::from_secs(now.into()));
+        assert_eq!(relay, vec![(1, now), (2, now - 5 * 60)]);
+        let batch: Vec<(u8, u32)> = (0..10).map(|i| (i, now)).collect();
+        let relay = prepare_addr_relay(&batch, Duration::from_secs(now.into()));
+        assert_eq!(relay.len(), MAX_ADDR_RELAY);
+        let getaddr_response: Vec<(u8, u32)> = (0..11).map(|i| (i, now)).collect();
+        let relay = prepare_addr_relay(&getaddr_response, Duration::from_secs(now.into()));
+        assert!(relay.is_empty());
+    }
+
+    #[test]
+    fn test_adjust_addr_timestamps() {
+        let now = 1_700_000_000;
+        let five_days_ago = now - 5 * 24 * 60 * 60;
+        let mut received = [
+            (1, now),
+            (2, now + 5 * 60),
+            (3, now + 60 * 60),
+            (4, 100_000_000),
+            (5, 100_000_001),
+        ];
+        adjust_addr_timestamps(&mut received, Duration::from_secs(now.into()));
+        assert_eq!(
+            received,
+            [
+                (1, now),
+                (2, now + 5 * 60),
+                (3, five_days_ago),
+                (4, five_days_ago),
+                (5, 100_000_001),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_net_group() {
+        let first = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+        let same = IpAddr::V4(Ipv4Addr::new(1, 2, 200, 1));
+        let different = IpAddr::V4(Ipv4Addr::new(1, 3, 3, 4));
+        assert_eq!(net_group(first), net_group(same));
+        assert_ne!(net_group(first), net_group(different));
+        let mapped = IpAddr::V6(Ipv4Addr::new(1, 2, 3, 4).to_ipv6_mapped());
+        assert_eq!(net_group(first), net_group(mapped));
     }
 }
@@ -0,0 +1,622 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io::{self, BufReader, Read, Write},
+    net::SocketAddr,
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bitcoin::key::rand::random;
+use p2p::{message::NetworkMessage, NetworkExt};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpSocket, TcpStream,
+    },
+    sync::{mpsc as async_mpsc, Notify},
+    task::JoinHandle,
+};
+
+use super::{
+    jitter_delay, verack_responses, version_responses, ConnectionReader, Error, OpenWriter,
+    OpenedConnection, PartialFrame, ReadTransport, StreamControl, TimeoutParams, WriteRequest,
+    WriteTransport, WRITER_IDLE_INTERVAL,
+};
+use crate::{
+    handshake::{CompletedHandshake, ConnectionConfig, TransportPreference},
+    ConnectionMetrics, PeerId,
+};
+
+// The most bytes read from the socket at once.
+const READ_CHUNK_LEN: usize = 8 * 1024;
+
+/// The writer, reader and metrics of an open connection driven by Tokio.
+pub type AsyncConnection = (
+    AsyncConnectionWriter,
+    AsyncConnectionReader,
+    ConnectionMetrics,
+);
+
+/// Open or begin a connection to a peer on a Tokio runtime. Messages are processed exactly as
+/// for a [`ConnectionExt`](super::ConnectionExt) connection, but only the v1 transport is
+/// supported, so a configuration that requires v2 fails with [`Error::UnsupportedTransport`].
+pub trait AsyncConnectionExt: Send + Sync {
+    /// Facilitate a version handshake on an open connection.
+    fn handshake(
+        self,
+        tcp_stream: TcpStream,
+        timeout_params: TimeoutParams,
+    ) -> impl Future<Output = Result<AsyncConnection, Error>> + Send;
+
+    /// Open an outbound connection to the specified socket address.
+    fn open_connection(
+        self,
+        to: impl Into<SocketAddr>,
+        timeout_params: TimeoutParams,
+    ) -> impl Future<Output = Result<AsyncConnection, Error>> + Send;
+}
+
+impl AsyncConnectionExt for ConnectionConfig {
+    fn open_connection(
+        self,
+        to: impl Into<SocketAddr>,
+        timeout_params: TimeoutParams,
+    ) -> impl Future<Output = Result<AsyncConnection, Error>> + Send {
+        let to = to.into();
+        async move {
+            if self.transport == TransportPreference::V2Only {
+                return Err(Error::UnsupportedTransport);
+            }
+            let socket = if to.is_ipv4() {
+                TcpSocket::new_v4()?
+            } else {
+                TcpSocket::new_v6()?
+            };
+            if let Some(local) = self.bind_local {
+                socket.bind(local)?;
+            }
+            if let Some((recv, send)) = self.socket_buffers {
+                socket.set_recv_buffer_size(recv.min(u32::MAX as usize) as u32)?;
+                socket.set_send_buffer_size(send.min(u32::MAX as usize) as u32)?;
+            }
+            let connect = async { socket.connect(to).await.map_err(Error::Io) };
+            let tcp_stream = within(Some(timeout_params.tcp), connect).await?;
+            AsyncConnectionExt::handshake(self, tcp_stream, timeout_params).await
+        }
+    }
+
+    async fn handshake(
+        self,
+        tcp_stream: TcpStream,
+        timeout_params: TimeoutParams,
+    ) -> Result<AsyncConnection, Error> {
+        if self.transport == TransportPreference::V2Only {
+            return Err(Error::UnsupportedTransport);
+        }
+        let (read_timeout, write_timeout) = if self.trusted {
+            (None, None)
+        } else {
+            (timeout_params.read, timeout_params.write)
+        };
+        let remote_addr = tcp_stream.peer_addr().ok();
+        let (read_stream, write_stream) = tcp_stream.into_split();
+        let magic = self.network().default_network_magic();
+        let feed = FeedReader::default();
+        let mut stream = HandshakeStream {
+            read_stream,
+            write_stream,
+            buf_reader: BufReader::new(feed.clone()),
+            feed,
+            read_half: ReadTransport::V1(magic),
+            write_half: WriteTransport::V1(magic),
+            partial_frame: PartialFrame::default(),
+            read_timeout,
+            write_timeout,
+        };
+        let completed_handshake =
+            negotiate_version(self.clone(), &mut stream, remote_addr, &timeout_params).await?;
+        let HandshakeStream {
+            read_stream,
+            write_stream,
+            buf_reader,
+            feed,
+            read_half,
+            write_half,
+            ..
+        } = stream;
+        let (outgoing, receiver) = async_mpsc::unbounded_channel();
+        let OpenedConnection {
+            open_writer,
+            sender,
+            writer_alive,
+            peer_id,
+            reader,
+            metrics,
+            ..
+        } = OpenedConnection::new(
+            &self,
+            buf_reader,
+            BridgeWriter(outgoing),
+            (write_half, read_half),
+            completed_handshake,
+            &timeout_params,
+        );
+        // Pings and queued requests are handled by one task, while the bytes are written to the
+        // socket by another.
+        let wake = Arc::new(Notify::new());
+        let written = tokio::spawn(write_socket(write_stream, receiver, write_timeout));
+        let maintain = maintain_connection(open_writer, Arc::clone(&wake));
+        let task_handle = tokio::spawn(async move {
+            let maintained = maintain.await;
+            let written = written.await.unwrap_or(Err(Error::ChannelClosed));
+            maintained.and(written)
+        });
+        let writer = AsyncConnectionWriter {
+            sender,
+            _alive: writer_alive,
+            task_handle,
+            peer_id,
+            wake: Arc::clone(&wake),
+        };
+        let reader = AsyncConnectionReader {
+            inner: reader,
+            stream: read_stream,
+            feed,
+            read_timeout,
+            wake,
+        };
+        Ok((writer, reader, metrics))
+    }
+}
+
+// Handle pings and queued requests as the blocking writer does, waiting to be woken instead of
+// blocking on the queue.
+async fn maintain_connection(
+    mut open_writer: OpenWriter<BridgeWriter>,
+    wake: Arc<Notify>,
+) -> Result<(), Error> {
+    loop {
+        if let Some(result) = open_writer.stopped() {
+            return result;
+        }
+        match open_writer.receiver.try_recv() {
+            Ok(request) => {
+                if open_writer.handle(request)? {
+                    return Ok(());
+                }
+            }
+            Err(mpsc::TryRecvError::Empty) => {
+                open_writer.stream.flush()?;
+                let _ = tokio::time::timeout(WRITER_IDLE_INTERVAL, wake.notified()).await;
+            }
+            Err(mpsc::TryRecvError::Disconnected) => {
+                open_writer.stream.flush()?;
+                return Ok(());
+            }
+        }
+        open_writer.ping_if_due()?;
+    }
+}
+
+// Exchange version messages and complete the handshake, as in the blocking `negotiate_version`.
+async fn negotiate_version(
+    config: ConnectionConfig,
+    stream: &mut HandshakeStream,
+    remote_addr: Option<SocketAddr>,
+    timeout_params: &TimeoutParams,
+) -> Result<CompletedHandshake, Error> {
+    let unix_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| Error::SystemTime)?;
+    let nonce = random();
+    let handshake_jitter = config.handshake_jitter;
+    let version = config.build_our_version(unix_time, nonce, remote_addr);
+    stream.send(NetworkMessage::Version(version)).await?;
+    let first_message = match stream.receive(Some(timeout_params.initial_read)).await {
+        Err(Error::Io(e)) if e.kind() == io::ErrorKind::TimedOut => {
+            return Err(Error::NoInitialVersion)
+        }
+        result => result?,
+    };
+    let inbound = config.inbound;
+    let (mut handshake, messages) = config.start_handshake(unix_time, first_message, nonce)?;
+    for message in version_responses(inbound, messages) {
+        stream.send(message).await?;
+    }
+    let verack_deadline = Instant::now() + timeout_params.handshake;
+    loop {
+        let remaining = verack_deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(Error::MissingVerack);
+        }
+        let timeout = stream
+            .read_timeout
+            .map_or(remaining, |read| read.min(remaining));
+        let message = match stream.receive(Some(timeout)).await {
+            Err(Error::Io(e))
+                if e.kind() == io::ErrorKind::TimedOut && Instant::now() >= verack_deadline =>
+            {
+                return Err(Error::MissingVerack)
+            }
+            result => result?,
+        };
+        if let Some((completed_handshake, responses)) = handshake.negotiate(message)? {
+            if handshake_jitter.is_some() {
+                tokio::time::sleep(jitter_delay(handshake_jitter)).await;
+            }
+            for response in verack_responses(inbound, responses) {
+                stream
+                    .send(response)
+                    .await
+                    .map_err(Error::HandshakeIncompleteResponse)?;
+            }
+            return Ok(completed_handshake);
+        }
+    }
+}
+
+// The socket and framing state while the handshake is in progress.
+struct HandshakeStream {
+    read_stream: OwnedReadHalf,
+    write_stream: OwnedWriteHalf,
+    buf_reader: BufReader<FeedReader>,
+    feed: FeedReader,
+    read_half: ReadTransport,
+    write_half: WriteTransport,
+    partial_frame: PartialFrame,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl HandshakeStream {
+    async fn send(&mut self, message: NetworkMessage) -> Result<(), io::Error> {
+        let bytes = self.write_half.encode_message(message);
+        let write = self.write_stream.write_all(&bytes);
+        match self.write_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, write)
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?,
+            None => write.await,
+        }
+    }
+
+    // Read from the socket until a whole message has arrived.
+    async fn receive(&mut self, timeout: Option<Duration>) -> Result<NetworkMessage, Error> {
+        loop {
+            match self
+                .read_half
+                .poll_frame(&mut self.buf_reader, &mut self.partial_frame, None)
+            {
+                Ok(Some((header, frame))) => {
+                    return self.read_half.decode_frame(&frame).map_err(|source| {
+                        Error::Deserialize {
+                            command: Some(header.command),
+                            source,
+                        }
+                    })
+                }
+                Ok(None) => (),
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e),
+            }
+            within(timeout, self.feed.fill(&mut self.read_stream)).await?;
+        }
+    }
+}
+
+/// Send messages to an open connection driven by Tokio. Requests are queued without blocking, so
+/// messages may be sent from within a task.
+#[derive(Debug)]
+pub struct AsyncConnectionWriter {
+    sender: mpsc::Sender<WriteRequest>,
+    _alive: Arc<()>,
+    task_handle: JoinHandle<Result<(), Error>>,
+    peer_id: PeerId,
+    wake: Arc<Notify>,
+}
+
+#[allow(clippy::result_large_err)]
+impl AsyncConnectionWriter {
+    /// The identifier for this connection.
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// Send a network message to this peer. Errors indicate that the connection is terminated and
+    /// no further messages will succeed.
+    pub fn send_message(&self, network_message: NetworkMessage) -> Result<(), Error> {
+        self.sender
+            .send(WriteRequest::SendMessage(network_message))
+            .map_err(|_| Error::ChannelClosed)?;
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    /// Stop writing to the connection, erroring if the stream is already closed.
+    pub fn shutdown(&self) -> Result<(), Error> {
+        self.sender
+            .send(WriteRequest::Shutdown)
+            .map_err(|_| Error::ChannelClosed)?;
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    /// Close the connection after every message already queued has been written to the socket,
+    /// returning any error the writer encountered.
+    pub async fn disconnect_flushing(self) -> Result<(), Error> {
+        // If the writer has already stopped, its error is reported below
+        let _ = self.sender.send(WriteRequest::Disconnect);
+        drop(self.sender);
+        self.wake.notify_one();
+        self.task_handle.await.unwrap_or(Err(Error::ChannelClosed))
+    }
+
+    /// In the event of a failed message, investigate failures if the connection was not closed
+    /// gracefully.
+    pub async fn take_errors(self) -> Option<Error> {
+        self.task_handle.await.ok()?.err()
+    }
+}
+
+/// Read messages from an open connection driven by Tokio.
+#[derive(Debug)]
+pub struct AsyncConnectionReader {
+    inner: ConnectionReader<FeedReader>,
+    stream: OwnedReadHalf,
+    feed: FeedReader,
+    read_timeout: Option<Duration>,
+    // Wakes the writer for requests queued while reading, such as `getblocktxn`
+    wake: Arc<Notify>,
+}
+
+impl AsyncConnectionReader {
+    /// The identifier for this connection.
+    pub fn peer_id(&self) -> PeerId {
+        self.inner.peer_id()
+    }
+
+    /// Read the next message from the peer, waiting at most the read timeout for each read from
+    /// the socket. Messages are handled as by [`ConnectionReader::read_message`].
+    pub async fn read_message(&mut self) -> Result<Option<NetworkMessage>, Error> {
+        loop {
+            let message = self.inner.try_read_message();
+            self.wake.notify_one();
+            if let Some(message) = message? {
+                return Ok(Some(message));
+            }
+            within(self.read_timeout, self.feed.fill(&mut self.stream)).await?;
+        }
+    }
+}
+
+// Bytes read from the socket that were not yet consumed by the reader.
+#[derive(Debug, Default)]
+struct Feed {
+    bytes: VecDeque<u8>,
+    closed: bool,
+}
+
+// Serves the bytes read from the socket to the blocking reader, which is told to try again later
+// instead of blocking the runtime when none are available.
+#[derive(Debug, Clone, Default)]
+struct FeedReader(Arc<Mutex<Feed>>);
+
+impl FeedReader {
+    // Read from the socket once, marking the feed closed at the end of the stream.
+    async fn fill(&self, stream: &mut OwnedReadHalf) -> Result<(), Error> {
+        let mut buf = vec![0; READ_CHUNK_LEN];
+        let len = stream.read(&mut buf).await?;
+        let mut feed = self.0.lock().map_err(|_| Error::ChannelClosed)?;
+        if len == 0 {
+            feed.closed = true;
+        } else {
+            feed.bytes.extend(&buf[..len]);
+        }
+        Ok(())
+    }
+}
+
+impl Read for FeedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut feed = self
+            .0
+            .lock()
+            .map_err(|_| io::Error::from(io::ErrorKind::Other))?;
+        if feed.bytes.is_empty() {
+            if feed.closed {
+                return Ok(0);
+            }
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let len = buf.len().min(feed.bytes.len());
+        for (slot, byte) in buf.iter_mut().zip(feed.bytes.drain(..len)) {
+            *slot = byte;
+        }
+        Ok(len)
+    }
+}
+
+impl StreamControl for FeedReader {
+    fn close(&self) -> Result<(), io::Error> {
+        if let Ok(mut feed) = self.0.lock() {
+            feed.closed = true;
+        }
+        Ok(())
+    }
+
+    // Reads never wait for the socket, so the feed is always nonblocking
+    fn set_nonblocking(&self, _nonblocking: bool) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum Outgoing {
+    Bytes(Vec<u8>),
+    Close,
+}
+
+// Hands the bytes encoded by the writer to the task that owns the socket.
+#[derive(Debug)]
+struct BridgeWriter(async_mpsc::UnboundedSender<Outgoing>);
+
+impl Write for BridgeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .send(Outgoing::Bytes(buf.to_vec()))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl StreamControl for BridgeWriter {
+    fn close(&self) -> Result<(), io::Error> {
+        // The socket task may have already stopped
+        let _ = self.0.send(Outgoing::Close);
+        Ok(())
+    }
+}
+
+// Write everything handed over by the writer until it closes the stream or stops.
+async fn write_socket(
+    mut stream: OwnedWriteHalf,
+    mut receiver: async_mpsc::UnboundedReceiver<Outgoing>,
+    write_timeout: Option<Duration>,
+) -> Result<(), Error> {
+    while let Some(outgoing) = receiver.recv().await {
+        match outgoing {
+            Outgoing::Bytes(bytes) => {
+                let write = async { stream.write_all(&bytes).await.map_err(Error::Io) };
+                within(write_timeout, write).await?;
+            }
+            Outgoing::Close => break,
+        }
+    }
+    let _ = stream.shutdown().await;
+    Ok(())
+}
+
+// Await the future, failing with a timed out error if it takes longer than the timeout.
+async fn within<T>(
+    timeout: Option<Duration>,
+    future: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| Error::Io(io::ErrorKind::TimedOut.into()))?,
+        None => future.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use p2p::message::NetworkMessage;
+
+    use super::AsyncConnectionExt;
+    use crate::{
+        handshake::{ConnectionConfig, TransportPreference},
+        net::{ConnectionExt, Error, TimeoutParams},
+    };
+
+    #[test]
+    fn test_async_connection() {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = std::thread::spawn(move || {
+            let (tcp_stream, _) = listener.accept().unwrap();
+            let config = ConnectionConfig::new().inbound();
+            let (writer, mut reader, _) =
+                ConnectionExt::handshake(config, tcp_stream, TimeoutParams::new()).unwrap();
+            let ping = reader
+                .wait_for(
+                    |message| matches!(message, NetworkMessage::Ping(_)),
+                    Duration::from_secs(5),
+                )
+                .unwrap();
+            writer.send_message(NetworkMessage::Ping(9)).unwrap();
+            let _ = reader.wait_for(|_| false, Duration::from_secs(1));
+            ping
+        });
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let (writer, mut reader, metrics) = AsyncConnectionExt::open_connection(
+                ConnectionConfig::new(),
+                addr,
+                TimeoutParams::new(),
+            )
+            .await
+            .unwrap();
+            assert!(!metrics.is_encrypted());
+            writer.send_message(NetworkMessage::Ping(7)).unwrap();
+            while !matches!(
+                reader.read_message().await.unwrap(),
+                Some(NetworkMessage::Ping(9))
+            ) {}
+            let v2_only = ConnectionConfig::new().transport(TransportPreference::V2Only);
+            let refused = AsyncConnectionExt::open_connection(v2_only, addr, TimeoutParams::new());
+            assert!(matches!(refused.await, Err(Error::UnsupportedTransport)));
+        });
+        assert!(matches!(peer.join().unwrap(), NetworkMessage::Ping(7)));
+    }
+
+    #[test]
+    fn test_async_pings() {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = std::thread::spawn(move || {
+            let (tcp_stream, _) = listener.accept().unwrap();
+            let config = ConnectionConfig::new().inbound();
+            let (writer, mut reader, _) =
+                ConnectionExt::handshake(config, tcp_stream, TimeoutParams::new()).unwrap();
+            writer.send_message(NetworkMessage::Ping(5)).unwrap();
+            let pong = reader
+                .wait_for(
+                    |message| matches!(message, NetworkMessage::Pong(_)),
+                    Duration::from_secs(5),
+                )
+                .unwrap();
+            let ping = reader.wait_for(
+                |message| matches!(message, NetworkMessage::Ping(_)),
+                Duration::from_secs(5),
+            );
+            (pong, ping.is_ok())
+        });
+        // The writer runs on the same thread as the reader, so it must not block the runtime
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut timeout_params = TimeoutParams::new();
+            timeout_params.ping_interval(Duration::from_millis(100));
+            let (writer, mut reader, _) =
+                AsyncConnectionExt::open_connection(ConnectionConfig::new(), addr, timeout_params)
+                    .await
+                    .unwrap();
+            let _ = tokio::time::timeout(Duration::from_secs(3), async {
+                while let Ok(Some(message)) = reader.read_message().await {
+                    if let NetworkMessage::Ping(nonce) = message {
+                        writer.send_message(NetworkMessage::Pong(nonce)).unwrap();
+                    }
+                }
+            })
+            .await;
+            let _ = writer.disconnect_flushing().await;
+        });
+        let (pong, pinged) = peer.join().unwrap();
+        assert!(matches!(pong, NetworkMessage::Pong(5)));
+        assert!(pinged);
+    }
+}
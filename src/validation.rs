@@ -1,7 +1,8 @@
+use bitcoin::{block::Header, pow::Target, Block, BlockHash};
 use p2p::message::NetworkMessage;
 
 const MAX_INV_SIZE: usize = 50_000;
-const MAX_LOCATOR_HASHES: usize = 101;
+pub(crate) const MAX_LOCATOR_HASHES: usize = 101;
 
 /// Validate messages from peers.
 pub trait ValidationExt {
@@ -55,12 +56,36 @@ impl ValidationExt for NetworkMessage {
     }
 }
 
+/// Check that a block received from a peer is one of the blocks that was requested.
+pub fn block_matches_request(block: &Block, requested: &[BlockHash]) -> bool {
+    requested.contains(&block.block_hash())
+}
+
+/// The index of the first header that does not build on the header before it, if any. Only the
+/// links within the batch are checked, not that the first header connects to a known chain.
+pub fn first_disconnected_header(headers: &[Header]) -> Option<usize> {
+    headers
+        .windows(2)
+        .position(|pair| pair[1].prev_blockhash != pair[0].block_hash())
+        .map(|index| index + 1)
+}
+
+/// Check that the hash of a header meets the target claimed by its `bits` field, and that this
+/// target is no easier than `max_target`, the easiest target allowed on the network. Whether the
+/// `bits` field follows the difficulty adjustment rules is not checked.
+pub fn check_header_pow(header: &Header, max_target: Target) -> bool {
+    let target = header.target();
+    target <= max_target && header.validate_pow(target).is_ok()
+}
+
 #[cfg(test)]
 mod tests {
     use bitcoin::BlockHash;
     use p2p::{message::NetworkMessage, message_network::Alert, ProtocolVersion};
 
-    use crate::validation::ValidationExt;
+    use crate::validation::{
+        block_matches_request, check_header_pow, first_disconnected_header, ValidationExt,
+    };
 
     const MALFORMED_BLOCKHASHES: [BlockHash; 102] = [BlockHash::from_byte_array([0; 32]); 102];
 
@@ -81,4 +106,33 @@ mod tests {
         });
         assert!(getdata.is_discouraged());
     }
+
+    #[test]
+    fn test_first_disconnected_header() {
+        let genesis = bitcoin::constants::genesis_block(bitcoin::Network::Regtest).header;
+        let mut next = genesis;
+        next.prev_blockhash = genesis.block_hash();
+        assert_eq!(first_disconnected_header(&[]), None);
+        assert_eq!(first_disconnected_header(&[genesis, next]), None);
+        assert_eq!(first_disconnected_header(&[genesis, next, next]), Some(2));
+    }
+
+    #[test]
+    fn test_check_header_pow() {
+        let max_target = bitcoin::pow::Target::MAX_ATTAINABLE_MAINNET;
+        let mut genesis = bitcoin::constants::genesis_block(bitcoin::Network::Bitcoin).header;
+        assert!(check_header_pow(&genesis, max_target));
+        genesis.nonce += 1;
+        assert!(!check_header_pow(&genesis, max_target));
+        let regtest = bitcoin::constants::genesis_block(bitcoin::Network::Regtest).header;
+        assert!(!check_header_pow(&regtest, max_target));
+    }
+
+    #[test]
+    fn test_block_matches_request() {
+        let genesis = bitcoin::constants::genesis_block(bitcoin::Network::Bitcoin);
+        assert!(block_matches_request(&genesis, &[genesis.block_hash()]));
+        assert!(!block_matches_request(&genesis, &MALFORMED_BLOCKHASHES));
+        assert!(!block_matches_request(&genesis, &[]));
+    }
 }